@@ -0,0 +1,535 @@
+use std::fmt;
+
+use chaos_framework::{vec3, Quat, Vec3};
+use rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{joint::{JointDesc, JointKind, JointMotor}, phys::{PhysMeshHandle, PhysicalWorld, PhysicsError, World}, physics_util::BodyShape, scene::SceneBody, sink::SceneSink};
+
+// water, so a ragdoll weighs about what a person does
+const ARTICULATED_DENSITY: f32 = 1000.0;
+// what `pose_group` drives a joint with when it had no motor of its own
+const POSE_MOTOR: JointMotor = JointMotor { target_position: 0.0, target_velocity: 0.0, stiffness: 2000.0, damping: 200.0, max_force: 5000.0 };
+// the arm has to hold itself up at full reach
+const ARM_MOTOR: JointMotor = JointMotor { target_position: 0.0, target_velocity: 0.0, stiffness: 20000.0, damping: 2000.0, max_force: 10000.0 };
+
+#[derive(Eq, Hash, PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct GroupHandle(pub u32);
+
+impl fmt::Display for GroupHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "g{}", self.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GroupKind {
+    Chain,
+    Ragdoll,
+    RobotArm,
+}
+
+impl GroupKind {
+    // one of each, in the order the editor lists them
+    pub const ALL: [GroupKind; 3] = [GroupKind::Chain, GroupKind::Ragdoll, GroupKind::RobotArm];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GroupKind::Chain => "Chain",
+            GroupKind::Ragdoll => "Ragdoll",
+            GroupKind::RobotArm => "Robot arm",
+        }
+    }
+}
+
+/* bodies held together by multibody joints and handled as one, the root comes first and every other body hangs off one before it */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BodyGroup {
+    pub kind: GroupKind,
+    pub bodies: Vec<PhysMeshHandle>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChainDesc {
+    pub links: u32,
+    // each link is a capsule this long end to end
+    pub link_length: f32,
+    pub radius: f32,
+    // hangs off a fixed hook at the start instead of falling with everything else
+    pub anchored: bool,
+}
+
+impl Default for ChainDesc {
+    fn default() -> Self {
+        Self { links: 12, link_length: 0.5, radius: 0.1, anchored: true }
+    }
+}
+
+// one body of a group, hung off an earlier part at `anchor` around `axis` (world space)
+struct Part {
+    body: SceneBody,
+    parent: Option<(usize, JointDesc, Vec3, Vec3)>,
+}
+
+impl Part {
+    fn root(body: SceneBody) -> Self {
+        Self { body, parent: None }
+    }
+
+    fn hung(body: SceneBody, parent: usize, joint: JointDesc, anchor: Vec3, axis: Vec3) -> Self {
+        Self { body, parent: Some((parent, joint, anchor, axis)) }
+    }
+}
+
+fn capsule(length: f32, radius: f32) -> BodyShape {
+    BodyShape::Capsule { half_height: (length / 2.0 - radius).max(0.0), radius }
+}
+
+fn limb(shape: BodyShape, position: Vec3, density: f32) -> SceneBody {
+    SceneBody { density, ..SceneBody::new(shape, RigidBodyType::Dynamic, position) }
+}
+
+fn hinge(limits: [f32; 2]) -> JointDesc {
+    JointDesc { limits: Some(limits), ..JointDesc::new(JointKind::Revolute) }
+}
+
+// `limits` bends it forwards and back, `swing` leans it sideways
+fn ball(limits: [f32; 2], swing: f32) -> JointDesc {
+    JointDesc { limits: Some(limits), swing: Some(swing), ..JointDesc::new(JointKind::Spherical) }
+}
+
+fn chain_parts(desc: &ChainDesc, start: Vec3, direction: Vec3) -> Vec<Part> {
+    let direction = direction.try_normalize().unwrap_or(Vec3::NEG_Y);
+    // capsules stand along y, the hook turns with them so every joint starts out straight
+    let rotation = Quat::from_rotation_arc(Vec3::Y, direction);
+    let link_length = desc.link_length.max(desc.radius * 2.0);
+    let joint = JointDesc::new(JointKind::Spherical);
+
+    let mut parts = Vec::with_capacity(desc.links as usize + 1);
+    if desc.anchored {
+        let hook = SceneBody { rotation, ..SceneBody::new(BodyShape::Sphere { radius: desc.radius * 1.5 }, RigidBodyType::Fixed, start) };
+        parts.push(Part::root(hook));
+    }
+    for i in 0..desc.links.max(1) {
+        let centre = start + direction * (i as f32 + 0.5) * link_length;
+        let link = SceneBody { rotation, ..SceneBody::new(capsule(link_length, desc.radius), RigidBodyType::Dynamic, centre) };
+        let parent = parts.len().checked_sub(1);
+
+        parts.push(match parent {
+            Some(parent) => Part::hung(link, parent, joint, start + direction * i as f32 * link_length, Vec3::X),
+            None => Part::root(link),
+        });
+    }
+
+    parts
+}
+
+// about 1.75m tall, facing +z with its feet at `position`, limits in radians around x where positive bends forwards
+// pelvis, torso, head, upper arms, forearms, thighs, shins
+fn ragdoll_parts(position: Vec3) -> Vec<Part> {
+    let at = |x: f32, y: f32| position + vec3(x, y + 0.02, 0.0);
+    let part = |shape, x, y| limb(shape, at(x, y), ARTICULATED_DENSITY);
+
+    let mut parts = vec![
+        Part::root(part(BodyShape::Cuboid { half_extents: vec3(0.16, 0.08, 0.1) }, 0.0, 0.98)),
+        Part::hung(part(BodyShape::Cuboid { half_extents: vec3(0.17, 0.2, 0.1) }, 0.0, 1.28), 0, ball([-0.4, 0.9], 0.35), at(0.0, 1.06), Vec3::X),
+        Part::hung(part(BodyShape::Sphere { radius: 0.11 }, 0.0, 1.62), 1, ball([-0.6, 0.8], 0.6), at(0.0, 1.5), Vec3::X),
+    ];
+    for side in [-1.0, 1.0] {
+        let upper = parts.len();
+        // raised forwards past the head, barely backwards
+        parts.push(Part::hung(part(capsule(0.3, 0.055), side * 0.25, 1.29), 1, ball([-2.6, 0.9], 1.4), at(side * 0.25, 1.44), Vec3::X));
+        // elbows only fold forwards
+        parts.push(Part::hung(part(capsule(0.3, 0.05), side * 0.25, 0.99), upper, hinge([-2.5, 0.0]), at(side * 0.25, 1.14), Vec3::X));
+    }
+    for side in [-1.0, 1.0] {
+        let thigh = parts.len();
+        parts.push(Part::hung(part(capsule(0.45, 0.07), side * 0.1, 0.675), 0, ball([-1.7, 0.4], 0.5), at(side * 0.1, 0.9), Vec3::X));
+        // knees only fold backwards
+        parts.push(Part::hung(part(capsule(0.45, 0.06), side * 0.1, 0.225), thigh, hinge([0.0, 2.4]), at(side * 0.1, 0.45), Vec3::X));
+    }
+
+    parts
+}
+
+// a fixed base with a turret, shoulder, elbow and wrist, every joint held by a motor
+fn robot_arm_parts(position: Vec3) -> Vec<Part> {
+    let at = |y: f32| position + vec3(0.0, y, 0.0);
+    let part = |shape, y| limb(shape, at(y), ARTICULATED_DENSITY / 2.0);
+    let motor = |limits| JointDesc { limits, motor: Some(ARM_MOTOR), ..JointDesc::new(JointKind::Revolute) };
+
+    vec![
+        Part::root(SceneBody::new(BodyShape::Cylinder { half_height: 0.1, radius: 0.35 }, RigidBodyType::Fixed, at(0.1))),
+        Part::hung(part(BodyShape::Cylinder { half_height: 0.1, radius: 0.22 }, 0.3), 0, motor(None), at(0.2), Vec3::Y),
+        Part::hung(part(capsule(1.0, 0.1), 0.9), 1, motor(Some([-2.0, 2.0])), at(0.4), Vec3::X),
+        Part::hung(part(capsule(0.8, 0.08), 1.8), 2, motor(Some([-2.5, 2.5])), at(1.4), Vec3::X),
+        Part::hung(part(BodyShape::Cuboid { half_extents: vec3(0.12, 0.05, 0.12) }, 2.25), 3, motor(Some([-2.0, 2.0])), at(2.2), Vec3::X),
+    ]
+}
+
+impl PhysicalWorld {
+    // `body2` hangs off `body1`, None if it already hangs off something or the two are already articulated together
+    pub fn add_multibody_joint(&mut self, body1: RigidBodyHandle, body2: RigidBodyHandle, desc: JointDesc) -> Option<MultibodyJointHandle> {
+        let handle = self.multibody_joint_set.insert(body1, body2, desc.build(), true)?;
+        self.multibody_joints.insert(handle, desc);
+        self.multibody_links.insert(body2, handle);
+
+        Some(handle)
+    }
+
+    // the joint `body` hangs off, None for a root or a body that isn't articulated
+    pub fn multibody_joint_of(&self, body: RigidBodyHandle) -> Option<MultibodyJointHandle> {
+        self.multibody_links.get(&body).copied()
+    }
+
+    // rebuilds `multibody_links` from the joints themselves, for when both sets were swapped out at once
+    pub fn link_multibody_joints(&mut self) {
+        let multibody_set = &self.multibody_joint_set;
+        self.multibody_links = self.multibody_joints.keys()
+            .filter_map(|handle| {
+                let (multibody, link) = multibody_set.get(*handle)?;
+                Some((multibody.link(link)?.rigid_body_handle(), *handle))
+            })
+            .collect();
+    }
+
+    // swaps what the joint does but not where it sits, so `desc` must keep the kind and offsets it had
+    pub fn set_multibody_joint(&mut self, handle: MultibodyJointHandle, desc: JointDesc) -> Option<JointDesc> {
+        let (multibody, link) = self.multibody_joint_set.get_mut(handle)?;
+        multibody.link_mut(link)?.joint.data = desc.build();
+
+        self.multibody_joints.insert(handle, desc)
+    }
+
+    // rapier 0.22 leaves a body cut off on its own still pointing at the multibody it was cut from, which panics the
+    // solver on the next step, so a body only ever leaves by taking its whole multibody apart and putting the rest back
+    // what's left keeps its pose but not its speed
+    pub fn cut_from_multibody(&mut self, body: RigidBodyHandle) {
+        let Some(link) = self.multibody_joint_set.rigid_body_link(body).copied() else {
+            return;
+        };
+        let Some(multibody) = self.multibody_joint_set.get_multibody(link.multibody) else {
+            return;
+        };
+        let links: Vec<(RigidBodyHandle, RigidBodyHandle, GenericJoint)> = multibody.links()
+            .filter_map(|link| {
+                let parent = multibody.link(link.parent_id()?)?;
+                Some((parent.rigid_body_handle(), link.rigid_body_handle(), link.joint.data))
+            })
+            .collect();
+        // every joint of the old multibody is gone after this, the ones put back get new handles
+        let mut joints = Vec::with_capacity(links.len());
+        for (parent, child, data) in links {
+            let desc = self.multibody_links.remove(&child).and_then(|handle| self.multibody_joints.remove(&handle));
+            if parent != body && child != body {
+                joints.push((parent, child, data, desc));
+            }
+        }
+
+        self.multibody_joint_set.remove_multibody_articulations(body, true);
+        for (parent, child, data, desc) in &joints {
+            let Some(handle) = self.multibody_joint_set.insert(*parent, *child, *data, true) else {
+                continue;
+            };
+            self.multibody_links.insert(*child, handle);
+            if let Some(desc) = desc {
+                self.multibody_joints.insert(handle, *desc);
+            }
+        }
+
+        let children: Vec<RigidBodyHandle> = joints.iter().map(|(_, child, ..)| *child).collect();
+        self.bend_to_bodies(&children);
+    }
+
+    // a multibody joint starts out straight whatever its bodies look like, this turns the joints `children` hang off
+    // to match where their bodies are instead of snapping the bodies straight on the next step
+    pub fn bend_to_bodies(&mut self, children: &[RigidBodyHandle]) {
+        let mut multibodies = Vec::new();
+        for child in children {
+            let Some(index) = self.multibody_joint_set.rigid_body_link(*child).map(|link| link.multibody) else {
+                continue;
+            };
+            let Some((multibody, id)) = self.multibody_links.get(child).and_then(|joint| self.multibody_joint_set.get_mut(*joint)) else {
+                continue;
+            };
+            let Some(parent) = multibody.link(id).and_then(|link| link.parent_id()).and_then(|parent| multibody.link(parent)) else {
+                continue;
+            };
+            let (Some(parent), Some(child)) = (self.rigid_body_set.get(parent.rigid_body_handle()), self.rigid_body_set.get(*child)) else {
+                continue;
+            };
+            let Some(link) = multibody.link_mut(id) else {
+                continue;
+            };
+
+            let data = &link.joint.data;
+            let relative = (parent.position() * data.local_frame1).inverse() * (child.position() * data.local_frame2);
+            let (translation, rotation) = (relative.translation.vector, relative.rotation.scaled_axis());
+            // free linear axes first, then the angular ones, the order rapier counts them in
+            let free = !data.locked_axes.bits();
+            let mut displacement: Vec<Real> = (0..3).filter(|i| free & (1 << i) != 0).map(|i| translation[i]).collect();
+            displacement.extend((0..3).filter(|i| free & (1 << (i + 3)) != 0).map(|i| rotation[i]));
+            link.joint.apply_displacement(&displacement);
+
+            if !multibodies.contains(&index) {
+                multibodies.push(index);
+            }
+        }
+
+        for index in multibodies {
+            if let Some(multibody) = self.multibody_joint_set.get_multibody_mut(index) {
+                multibody.forward_kinematics(&self.rigid_body_set, true);
+                multibody.update_rigid_bodies(&mut self.rigid_body_set, false);
+            }
+        }
+    }
+}
+
+impl World {
+    // hangs `child` off `parent` where they are right now, at `anchor` around `axis`
+    pub async fn articulate(&mut self, parent: PhysMeshHandle, child: PhysMeshHandle, desc: JointDesc, anchor: Vec3, axis: Vec3) -> Result<MultibodyJointHandle, PhysicsError> {
+        if parent == child {
+            return Err(PhysicsError::SelfJoint(parent));
+        }
+        let (body1, body2) = (self.phys_mesh(parent)?.body, self.phys_mesh(child)?.body);
+
+        let mut phys_world = self.phys_world.lock().await;
+        let body = |handle| phys_world.rigid_body_set.get(handle).ok_or(PhysicsError::StaleHandle(handle));
+        let desc = desc.attached(body(body1)?, body(body2)?, anchor, axis);
+
+        phys_world.add_multibody_joint(body1, body2, desc).ok_or(PhysicsError::Articulated(child))
+    }
+
+    pub async fn spawn_chain(&mut self, sink: &mut dyn SceneSink, desc: &ChainDesc, start: Vec3, direction: Vec3) -> Result<GroupHandle, PhysicsError> {
+        self.spawn_group(sink, GroupKind::Chain, chain_parts(desc, start, direction)).await
+    }
+
+    pub async fn spawn_ragdoll(&mut self, sink: &mut dyn SceneSink, position: Vec3) -> Result<GroupHandle, PhysicsError> {
+        self.spawn_group(sink, GroupKind::Ragdoll, ragdoll_parts(position)).await
+    }
+
+    pub async fn spawn_robot_arm(&mut self, sink: &mut dyn SceneSink, position: Vec3) -> Result<GroupHandle, PhysicsError> {
+        self.spawn_group(sink, GroupKind::RobotArm, robot_arm_parts(position)).await
+    }
+
+    // chains hang straight down from `position`, everything else stands on it
+    pub async fn spawn_articulated(&mut self, sink: &mut dyn SceneSink, kind: GroupKind, chain: &ChainDesc, position: Vec3) -> Result<GroupHandle, PhysicsError> {
+        match kind {
+            GroupKind::Chain => self.spawn_chain(sink, chain, position, Vec3::NEG_Y).await,
+            GroupKind::Ragdoll => self.spawn_ragdoll(sink, position).await,
+            GroupKind::RobotArm => self.spawn_robot_arm(sink, position).await,
+        }
+    }
+
+    // all or nothing, the bodies spawned so far go again if a joint can't be made
+    async fn spawn_group(&mut self, sink: &mut dyn SceneSink, kind: GroupKind, parts: Vec<Part>) -> Result<GroupHandle, PhysicsError> {
+        let mut bodies = Vec::with_capacity(parts.len());
+        let mut result = Ok(());
+        for part in &parts {
+            match self.spawn(sink, &part.body).await {
+                Ok(body) => bodies.push(body),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        for (i, part) in parts.iter().enumerate() {
+            let Some((parent, joint, anchor, axis)) = part.parent.filter(|_| result.is_ok()) else {
+                continue;
+            };
+            result = self.articulate(bodies[parent], bodies[i], joint, anchor, axis).await.map(|_| ());
+        }
+
+        if let Err(err) = result {
+            for body in bodies {
+                let _ = self.destroy(sink, body).await;
+            }
+            return Err(err);
+        }
+
+        Ok(self.insert_group(BodyGroup { kind, bodies }))
+    }
+
+    pub fn insert_group(&mut self, group: BodyGroup) -> GroupHandle {
+        let handle = GroupHandle(self.next_group);
+        self.next_group += 1;
+        self.groups.insert(handle, group);
+
+        handle
+    }
+
+    pub fn group(&self, handle: GroupHandle) -> Result<&BodyGroup, PhysicsError> {
+        self.groups.get(&handle).ok_or(PhysicsError::StaleGroup(handle))
+    }
+
+    fn group_bodies(&self, handle: GroupHandle) -> Result<Vec<RigidBodyHandle>, PhysicsError> {
+        self.group(handle)?.bodies.iter().map(|mesh| Ok(self.phys_mesh(*mesh)?.body)).collect()
+    }
+
+    // every joint in the group in body order, the root has none
+    pub async fn group_joints(&self, handle: GroupHandle) -> Result<Vec<JointDesc>, PhysicsError> {
+        let bodies = self.group_bodies(handle)?;
+        let phys_world = self.phys_world.lock().await;
+
+        Ok(bodies.iter()
+            .filter_map(|body| phys_world.multibody_joints.get(&phys_world.multibody_joint_of(*body)?).copied())
+            .collect())
+    }
+
+    // puts the root at `position` without turning it, the rest follows at the angles it's at
+    pub async fn move_group(&mut self, handle: GroupHandle, position: Vec3) -> Result<(), PhysicsError> {
+        let bodies = self.group_bodies(handle)?;
+        let mut phys_world = self.phys_world.lock().await;
+        let phys_world = &mut *phys_world;
+
+        let root = phys_world.body_mut(bodies[0])?;
+        let mut pose = *root.position();
+        pose.translation = vector![position.x, position.y, position.z].into();
+        root.set_position(pose, true);
+
+        // the links are placed from the joint angles, moving their bodies alone would be undone on the next step
+        if let Some(link) = phys_world.multibody_joint_set.rigid_body_link(bodies[0]).copied() {
+            if let Some(multibody) = phys_world.multibody_joint_set.get_multibody_mut(link.multibody) {
+                multibody.forward_kinematics(&phys_world.rigid_body_set, true);
+                multibody.update_rigid_bodies(&mut phys_world.rigid_body_set, false);
+            }
+        }
+        for body in bodies {
+            phys_world.body_mut(body)?.wake_up(true);
+        }
+
+        Ok(())
+    }
+
+    // drives each joint towards its angle in `targets` (metres for prismatic), in the order `group_joints` lists them
+    // joints that had no motor get a stiff one, extra targets are ignored
+    pub async fn pose_group(&mut self, handle: GroupHandle, targets: &[f32]) -> Result<(), PhysicsError> {
+        self.set_group_motors(handle, |desc, i| {
+            let target = targets.get(i)?;
+            Some(JointMotor { target_position: *target, target_velocity: 0.0, ..desc.motor.unwrap_or(POSE_MOTOR) })
+        }).await
+    }
+
+    // takes every motor away, a posed ragdoll goes limp again and an arm slumps
+    pub async fn relax_group(&mut self, handle: GroupHandle) -> Result<(), PhysicsError> {
+        self.set_group_motors(handle, |_, _| None).await
+    }
+
+    async fn set_group_motors(&mut self, handle: GroupHandle, motor: impl Fn(&JointDesc, usize) -> Option<JointMotor>) -> Result<(), PhysicsError> {
+        let bodies = self.group_bodies(handle)?;
+        let mut phys_world = self.phys_world.lock().await;
+
+        // counted the way `group_joints` lists them, bodies cut loose from the rest have no joint to count
+        let joints: Vec<_> = bodies.iter()
+            .filter_map(|body| phys_world.multibody_joint_of(*body))
+            .filter_map(|joint| Some((joint, *phys_world.multibody_joints.get(&joint)?)))
+            .collect();
+        for (i, (joint, desc)) in joints.into_iter().enumerate() {
+            phys_world.set_multibody_joint(joint, JointDesc { motor: motor(&desc, i), ..desc });
+        }
+        // a motor switched on under a sleeping body would do nothing until something woke it
+        for body in bodies {
+            phys_world.body_mut(body)?.wake_up(true);
+        }
+
+        Ok(())
+    }
+
+    // every body that is still around goes, the group with them
+    pub async fn destroy_group(&mut self, sink: &mut dyn SceneSink, handle: GroupHandle) -> Result<BodyGroup, PhysicsError> {
+        let group = self.groups.remove(&handle).ok_or(PhysicsError::StaleGroup(handle))?;
+        for body in &group.bodies {
+            let _ = self.destroy(sink, *body).await;
+        }
+
+        Ok(group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{import::{ImportCollider, MeshAsset}, sink::SceneRecorder};
+
+    fn sphere(y: f32) -> SceneBody {
+        SceneBody::new(BodyShape::Sphere { radius: 0.2 }, RigidBodyType::Dynamic, vec3(0.0, y, 0.0))
+    }
+
+    #[tokio::test]
+    async fn cutting_a_middle_link_leaves_two_working_chains() {
+        let mut world = World::new().await;
+        let mut sink = SceneRecorder::new();
+        let chain = ChainDesc { links: 4, ..ChainDesc::default() };
+        let group = world.spawn_chain(&mut sink, &chain, vec3(0.0, 5.0, 0.0), Vec3::NEG_Y).await.unwrap();
+        // hook, then four links each hanging off the one before
+        let bodies: Vec<RigidBodyHandle> = world.group(group).unwrap().bodies.iter().map(|mesh| world.phys_mesh(*mesh).unwrap().body).collect();
+        for _ in 0..10 {
+            world.step().await;
+        }
+
+        let mut phys_world = world.phys_world.lock().await;
+        phys_world.cut_from_multibody(bodies[2]);
+
+        // the joints on either side of it are gone, the two that don't touch it are back
+        assert_eq!(phys_world.multibody_joints.len(), 2);
+        assert_eq!(phys_world.multibody_links.len(), 2);
+        for (parent, child) in [(bodies[0], bodies[1]), (bodies[3], bodies[4])] {
+            let handle = phys_world.multibody_joint_of(child).unwrap();
+            assert!(phys_world.multibody_joints.contains_key(&handle));
+            let (multibody, link) = phys_world.multibody_joint_set.get(handle).unwrap();
+            let link = multibody.link(link).unwrap();
+            assert_eq!(link.rigid_body_handle(), child);
+            assert_eq!(multibody.link(link.parent_id().unwrap()).unwrap().rigid_body_handle(), parent);
+        }
+        for body in [bodies[2], bodies[3]] {
+            assert!(phys_world.multibody_joint_of(body).is_none());
+        }
+        assert!(phys_world.multibody_joint_set.rigid_body_link(bodies[2]).is_none());
+        let multibody = |body| phys_world.multibody_joint_set.rigid_body_link(body).unwrap().multibody;
+        assert_ne!(multibody(bodies[0]), multibody(bodies[3]));
+        drop(phys_world);
+
+        // rapier would panic here if the cut body still pointed at its old multibody
+        for _ in 0..30 {
+            world.step().await;
+        }
+        let phys_world = world.phys_world.lock().await;
+        assert!(phys_world.rigid_body_set[bodies[4]].translation().y.is_finite());
+    }
+
+    #[tokio::test]
+    async fn a_group_that_cant_be_joined_leaves_nothing_behind() {
+        let mut world = World::new().await;
+        let mut sink = SceneRecorder::new();
+
+        // the last part hangs off itself
+        let joint = JointDesc::new(JointKind::Spherical);
+        let parts = vec![Part::root(sphere(3.0)), Part::hung(sphere(2.0), 0, joint, vec3(0.0, 2.5, 0.0), Vec3::X), Part::hung(sphere(1.0), 2, joint, vec3(0.0, 1.5, 0.0), Vec3::X)];
+        let result = world.spawn_group(&mut sink, GroupKind::Chain, parts).await;
+        assert!(matches!(result, Err(PhysicsError::SelfJoint(_))));
+
+        assert!(world.phys_meshes.is_empty() && world.groups.is_empty());
+        assert!(sink.bodies.is_empty());
+        let phys_world = world.phys_world.lock().await;
+        assert_eq!(phys_world.rigid_body_set.len(), 0);
+        assert!(phys_world.multibody_joints.is_empty() && phys_world.multibody_links.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_group_with_a_body_that_cant_spawn_leaves_nothing_behind() {
+        let mut world = World::new().await;
+        let mut sink = SceneRecorder::new();
+
+        // a mesh this world never loaded
+        let missing = SceneBody::new(BodyShape::Imported { asset: MeshAsset(1), collider: ImportCollider::ConvexHull, scale: 1.0 }, RigidBodyType::Dynamic, Vec3::ZERO);
+        let joint = JointDesc::new(JointKind::Spherical);
+        let parts = vec![Part::root(sphere(3.0)), Part::hung(sphere(2.0), 0, joint, vec3(0.0, 2.5, 0.0), Vec3::X), Part::hung(missing, 1, joint, vec3(0.0, 1.5, 0.0), Vec3::X)];
+        assert!(world.spawn_group(&mut sink, GroupKind::Chain, parts).await.is_err());
+
+        assert!(world.phys_meshes.is_empty() && world.groups.is_empty());
+        assert!(sink.bodies.is_empty());
+        assert_eq!(world.phys_world.lock().await.rigid_body_set.len(), 0);
+    }
+}
@@ -1,28 +1,385 @@
-use std::io;
+use std::{io, time::{Duration, Instant}};
 
-use tokio::net::UdpSocket;
+use chaos_framework::{vec3, Vec3};
+use rapier3d::prelude::RigidBodyType;
+use tokio::time;
+
+use crate::{
+    connection::{random_id, Connection, ConnectionState, CONNECT_RETRY, DEFAULT_TIMEOUT},
+    headless::{flag_value, ArgError},
+    import::AssetStore,
+    netsim::{NetConditions, NetSocket},
+    physics_util::BodyShape,
+    protocol::{
+        decode, encode, ClientMessage, ClientPacket, DisconnectReason, NetError, ServerMessage, ServerPacket,
+        CHANNEL_WORLD, MAX_PACKET_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, RELIABLE_CHANNELS,
+    },
+    phys::{FixedTimestep, PhysMeshHandle},
+    prediction::Prediction,
+    reliable::{Endpoint, Packet},
+    replication::{Replica, DEFAULT_INTERP_DELAY},
+    scene::SceneBody,
+    sink::{SceneRecorder, SceneSink},
+    terrain::TerrainDesc,
+};
 
 pub struct Client {
-    socket: UdpSocket,
+    pub socket: NetSocket,
+    buf: Vec<u8>,
+    pub state: ConnectionState,
+    pub endpoint: Endpoint<ClientMessage, ServerMessage>,
+    // the body the server spawned for us, if it does that
+    pub player: Option<PhysMeshHandle>,
+    pub replica: Replica,
+    pub prediction: Prediction,
+    // files loaded on this side, imported bodies it doesn't have are drawn as a box
+    pub assets: AssetStore,
+    // a heightmap still waiting on some of its samples
+    terrain_transfer: Option<TerrainDesc>,
 }
 
 impl Client {
-    pub async fn new() -> io::Result<Self>  {
-        let socket = UdpSocket::bind("127.0.0.1:0").await?;
-        println!("Client bound to random port");
+    // sends the first connect request, `update` keeps retrying until the server answers
+    pub async fn connect(server_addr: &str, conditions: Option<NetConditions>) -> Result<Self, NetError> {
+        let socket = NetSocket::bind("0.0.0.0:0", conditions).await?;
+        socket.connect(server_addr).await?;
+        println!("Client bound to {:?}, talking to {}", socket.local_addr()?, server_addr);
+
+        let now = Instant::now();
+        let nonce = random_id();
+        let client = Self {
+            socket,
+            buf: vec![0u8; MAX_PACKET_SIZE],
+            state: ConnectionState::Connecting { nonce, started: now, last_attempt: now },
+            // session 0 until the welcome, the server may already be sending reliable messages by then
+            endpoint: Endpoint::new(0, RELIABLE_CHANNELS),
+            player: None,
+            replica: Replica::new(DEFAULT_INTERP_DELAY),
+            prediction: Prediction::new(60.0),
+            assets: AssetStore::new(),
+            terrain_transfer: None,
+        };
+        client.socket.send(&encode(&Packet::unconnected(0, Self::connect_message(nonce)))?).await?;
 
-        Ok(Self { socket })
+        Ok(client)
     }
 
-    pub async fn send_message(&mut self, server_addr: &str, message: &str) -> io::Result<()> {
-        self.socket.send_to(message.as_bytes(), server_addr).await?;
-        println!("Sent message: {}", message);
+    fn connect_message(nonce: u64) -> ClientMessage {
+        ClientMessage::Connect { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, nonce }
+    }
 
-        let mut buf = vec![0u8; 1024];
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, ConnectionState::Connected(_))
+    }
+
+    // unreliable and fire and forget, for the frame loop where we can't await
+    // anything sent before the handshake is done is dropped, the server wouldn't know who we are
+    pub fn try_send(&mut self, message: &ClientMessage) {
+        let ConnectionState::Connected(connection) = &mut self.state else {
+            return;
+        };
+        connection.sent();
+
+        let packet = self.endpoint.packet(Some(message.clone()));
+        self.send_packet(&packet);
+    }
 
-        let (len, addr) = self.socket.recv_from(&mut buf).await?;
-        println!("Received response from {}: {}", addr, String::from_utf8_lossy(&buf[..len]));
+    // goes out on the next `update` and keeps going out until the server has it
+    pub fn send_reliable(&mut self, message: ClientMessage) -> Result<(), NetError> {
+        if !self.is_connected() {
+            return Err(NetError::NotConnected);
+        }
+        self.endpoint.send(CHANNEL_WORLD, message);
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn send_packet(&self, packet: &ClientPacket) {
+        let sent = encode(packet).and_then(|bytes| Ok(self.socket.try_send(&bytes)?));
+        if let Err(err) = sent {
+            eprintln!("could not send {:?}: {err}", packet.message);
+        }
+    }
+
+    // says goodbye if we got as far as hello, either way we're done after this
+    pub fn disconnect(&mut self) {
+        self.try_send(&ClientMessage::Disconnect);
+        self.state = ConnectionState::Disconnected(DisconnectReason::Left);
+    }
+
+    // handshake retries, heartbeats, reliable resends and noticing the server went away
+    fn maintain(&mut self) {
+        let packet = match &mut self.state {
+            ConnectionState::Connecting { started, .. } if started.elapsed() >= DEFAULT_TIMEOUT => {
+                eprintln!("could not connect: no answer from the server");
+                self.state = ConnectionState::Disconnected(DisconnectReason::TimedOut);
+                return;
+            }
+            ConnectionState::Connecting { nonce, last_attempt, .. } => {
+                if last_attempt.elapsed() < CONNECT_RETRY {
+                    return;
+                }
+                *last_attempt = Instant::now();
+                Packet::unconnected(0, Self::connect_message(*nonce))
+            }
+            ConnectionState::Connected(connection) if connection.timed_out() => {
+                eprintln!("disconnected: server timed out");
+                self.state = ConnectionState::Disconnected(DisconnectReason::TimedOut);
+                return;
+            }
+            ConnectionState::Connected(connection) => {
+                let mut packets = self.endpoint.flush();
+                if packets.is_empty() && connection.needs_heartbeat() {
+                    packets.push(self.endpoint.packet(Some(ClientMessage::Heartbeat)));
+                }
+                if !packets.is_empty() {
+                    connection.sent();
+                }
+                for packet in &packets {
+                    self.send_packet(packet);
+                }
+                return;
+            }
+            ConnectionState::Disconnected(_) => return,
+        };
+
+        self.send_packet(&packet);
+    }
+
+    // never blocks, None once the socket has nothing left for us
+    pub fn try_recv(&mut self) -> Option<Result<ServerPacket, NetError>> {
+        match self.socket.try_recv(&mut self.buf) {
+            Ok(len) => Some(decode(&self.buf[..len])),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+
+    // called once a frame: drains the socket into the replica and renders it
+    pub fn update(&mut self, sink: &mut dyn SceneSink, dt: f32) {
+        while let Some(received) = self.try_recv() {
+            let packet = match received {
+                Ok(packet) => packet,
+                Err(err) => {
+                    eprintln!("bad message from server: {err}");
+                    // a broken socket would spin here forever
+                    if matches!(err, NetError::Io(_)) {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            // leftovers from a session we already gave up on
+            if packet.session != 0 && self.endpoint.session != 0 && packet.session != self.endpoint.session {
+                continue;
+            }
+            if let ConnectionState::Connected(connection) = &mut self.state {
+                connection.heard();
+            }
+
+            for message in self.endpoint.receive(packet) {
+                match message {
+                    ServerMessage::Rejected { reason } => eprintln!("server rejected a request: {reason}"),
+                    ServerMessage::Disconnected { reason } => {
+                        if !matches!(self.state, ConnectionState::Disconnected(_)) {
+                            eprintln!("disconnected: {reason}");
+                            self.state = ConnectionState::Disconnected(reason);
+                        }
+                    }
+                    message => self.handle(sink, message),
+                }
+            }
+        }
+
+        self.maintain();
+
+        self.prediction.sync_bodies(&self.replica.bodies, &mut self.assets);
+        self.replica.update(sink, dt);
+        self.prediction.update(sink, dt, &self.replica);
+    }
+
+    fn set_terrain(&mut self, desc: Option<TerrainDesc>) {
+        if let Err(err) = self.prediction.set_terrain(desc) {
+            eprintln!("no ground to predict against: {err}");
+        }
+    }
+
+    fn handle(&mut self, sink: &mut dyn SceneSink, message: ServerMessage) {
+        let input_ack = match &message {
+            ServerMessage::Welcome { session, version, timeout, tick_rate, player, .. } => {
+                // a repeated welcome (we retried before the first one arrived) changes nothing
+                if !matches!(self.state, ConnectionState::Connecting { .. }) {
+                    return;
+                }
+                self.state = ConnectionState::Connected(Connection::new(*session, *version, Duration::from_secs_f32(*timeout)));
+                self.endpoint.session = *session;
+                self.player = *player;
+                println!("connected, session {session:016x}, protocol {version}");
+
+                self.prediction.timestep = FixedTimestep::new(*tick_rate);
+                None
+            }
+            ServerMessage::Terrain { terrain } => {
+                self.terrain_transfer = None;
+                match terrain {
+                    Some(desc) if !desc.is_complete() => self.terrain_transfer = Some(desc.clone()),
+                    _ => self.set_terrain(terrain.clone()),
+                }
+                None
+            }
+            ServerMessage::TerrainSamples { heights } => {
+                if let Some(desc) = &mut self.terrain_transfer {
+                    desc.receive_samples(heights);
+                    if desc.is_complete() {
+                        let desc = self.terrain_transfer.take();
+                        self.set_terrain(desc);
+                    }
+                }
+                None
+            }
+            ServerMessage::Snapshot { input_ack, .. } => Some(*input_ack),
+            _ => None,
+        };
+
+        let Some(tick) = self.replica.apply(sink, message, &self.assets) else {
+            return;
+        };
+        self.try_send(&ClientMessage::Ack { tick });
+
+        // only the newest state is worth rewinding to, a late one would undo newer corrections
+        if self.replica.newest_tick() != Some(tick) {
+            return;
+        }
+        if let (Some(input_ack), Some(snapshot)) = (input_ack, self.replica.snapshot(tick)) {
+            self.prediction.sync_bodies(&self.replica.bodies, &mut self.assets);
+            self.prediction.reconcile(tick, &snapshot.bodies, input_ack);
+        }
+    }
+
+    // shows up locally right away, the server gets the final word
+    pub fn impulse(&mut self, handle: PhysMeshHandle, impulse: Vec3) {
+        let input = self.prediction.input(handle, impulse).unwrap_or(0);
+        self.try_send(&ClientMessage::Impulse { handle, impulse, input });
+    }
+}
+
+pub struct ClientOptions {
+    pub addr: String,
+    pub bodies: u32,
+    pub seconds: f32,
+    pub delay: f32,
+    // kick the oldest body up once a second to exercise prediction
+    pub impulse: bool,
+    pub conditions: Option<NetConditions>,
+}
+
+impl ClientOptions {
+    // returns None unless `--connect <addr>` was passed
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Option<Self>, ArgError> {
+        let args: Vec<String> = args.collect();
+        let Some(addr) = flag_value(&args, "--connect")? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            addr,
+            bodies: flag_value(&args, "--bodies")?.unwrap_or(8),
+            seconds: flag_value(&args, "--seconds")?.unwrap_or(5.0),
+            delay: flag_value(&args, "--delay")?.unwrap_or(DEFAULT_INTERP_DELAY),
+            impulse: args.iter().any(|arg| arg == "--impulse"),
+            conditions: NetConditions::from_args(&args)?,
+        }))
+    }
+}
+
+// joins a server, drops a few cubes and reports what came back, handy to poke at the protocol
+pub async fn probe(opts: ClientOptions) {
+    let mut client = match Client::connect(&opts.addr, opts.conditions).await {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("could not connect to {}: {err}", opts.addr);
+            return;
+        }
+    };
+    client.replica.delay = opts.delay;
+
+    let mut recorder = SceneRecorder::new();
+    let mut frame = time::interval(Duration::from_secs_f32(1.0 / 60.0));
+    let start = Instant::now();
+    let mut last = start;
+    let mut next_kick = 1.0;
+    let mut spawned = false;
+
+    while start.elapsed().as_secs_f32() < opts.seconds {
+        frame.tick().await;
+        let now = Instant::now();
+        client.update(&mut recorder, (now - last).as_secs_f32());
+        last = now;
+
+        if let ConnectionState::Disconnected(reason) = client.state {
+            eprintln!("giving up: {reason}");
+            return;
+        }
+        if client.is_connected() && !spawned {
+            spawned = true;
+            for i in 0..opts.bodies {
+                let message = ClientMessage::Spawn {
+                    body: SceneBody::new(
+                        BodyShape::Cuboid { half_extents: Vec3::splat(0.5) },
+                        RigidBodyType::Dynamic,
+                        vec3(0.0, 2.0 + i as f32 * 1.5, 0.0),
+                    ),
+                };
+                if let Err(err) = client.send_reliable(message) {
+                    eprintln!("could not spawn: {err}");
+                    return;
+                }
+            }
+        }
+
+        if opts.impulse && start.elapsed().as_secs_f32() > next_kick {
+            next_kick += 1.0;
+            if let Some(handle) = client.replica.bodies.keys().min_by_key(|handle| handle.id).copied() {
+                client.impulse(handle, vec3(0.0, 5.0, 0.0));
+            }
+        }
+    }
+
+    client.disconnect();
+
+    let stats = client.replica.stats;
+    println!(
+        "snapshots:  {} received, {} deltas, {} duplicates, {} reordered, {} stale, {} undecodable",
+        stats.received, stats.deltas, stats.duplicates, stats.reordered, stats.stale, stats.undecodable,
+    );
+    if let Some(average) = stats.bytes.checked_div(stats.received) {
+        println!("bandwidth:  {} bytes total, {} per snapshot", stats.bytes, average);
+    }
+    match (client.replica.newest_tick(), client.replica.render_tick) {
+        (Some(newest), Some(render_tick)) => println!("clock:      newest tick {newest}, rendering tick {render_tick:.1}"),
+        _ => println!("clock:      no snapshots"),
+    }
+    println!("bodies:     {} replicated, {} mirrored", client.replica.bodies.len(), recorder.bodies.len());
+    let reliable = client.endpoint.stats;
+    println!(
+        "reliable:   {} sent, {} resent, {} acked, {} delivered, {} duplicates, srtt {:.1}ms",
+        reliable.messages_sent, reliable.resent, reliable.acked, reliable.delivered, reliable.duplicates,
+        client.endpoint.srtt.unwrap_or_default().as_secs_f32() * 1000.0,
+    );
+    if let Some(sim) = client.socket.stats() {
+        println!(
+            "network:    {} sent, {} received, {} dropped, {} duplicated, {} reordered, {} over the cap",
+            sim.sent, sim.received, sim.dropped, sim.duplicated, sim.reordered, sim.overflowed,
+        );
+    }
+    if let Some(player) = client.player {
+        println!("player:     body {player}");
+    }
+    if opts.impulse {
+        let stats = client.prediction.stats;
+        println!(
+            "prediction: {} corrections, max error {:.3}, {} replayed steps, rtt {:.1}ms, {} inputs pending",
+            stats.corrections, stats.max_error, stats.replayed_steps, client.prediction.rtt * 1000.0, client.prediction.inputs.len(),
+        );
+    }
+}
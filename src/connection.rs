@@ -0,0 +1,75 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{phys::PhysMeshHandle, protocol::DisconnectReason};
+
+// send something at least this often, even when there's nothing to say
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+// nothing heard for this long and the other end is gone
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// the connect request is just a datagram, keep repeating it until it's answered
+pub const CONNECT_RETRY: Duration = Duration::from_millis(250);
+pub const DEFAULT_MAX_CLIENTS: usize = 16;
+
+// session ids and nonces, unguessable enough that a stray datagram can't pass for a client
+pub fn random_id() -> u64 {
+    loop {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+        let id = hasher.finish();
+        // 0 means "no session yet" on the wire
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/* one end of an established connection, both sides keep one */
+pub struct Connection {
+    pub session: u64,
+    pub version: u32,
+    pub timeout: Duration,
+    pub last_heard: Instant,
+    pub last_sent: Instant,
+}
+
+impl Connection {
+    pub fn new(session: u64, version: u32, timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self { session, version, timeout, last_heard: now, last_sent: now }
+    }
+
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    pub fn sent(&mut self) {
+        self.last_sent = Instant::now();
+    }
+
+    pub fn needs_heartbeat(&self) -> bool {
+        self.last_sent.elapsed() >= HEARTBEAT_INTERVAL
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.last_heard.elapsed() >= self.timeout
+    }
+}
+
+pub enum ConnectionState {
+    Connecting { nonce: u64, started: Instant, last_attempt: Instant },
+    Connected(Connection),
+    Disconnected(DisconnectReason),
+}
+
+/* what the server tells the world about, in the order it happened */
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    Connected { session: u64, addr: SocketAddr },
+    // `player` is the body the client owned, if the server gave it one
+    Disconnected { session: u64, addr: SocketAddr, reason: DisconnectReason, player: Option<PhysMeshHandle> },
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use chaos_framework::{vec3, Vec3};
+use rapier3d::{crossbeam::channel::{self, Receiver}, prelude::*};
+use tokio::sync::broadcast;
+
+use crate::phys::PhysMeshHandle;
+
+// events a subscriber can fall behind by before it starts missing the oldest ones
+pub const CONTACT_EVENT_CAPACITY: usize = 4096;
+
+// the two meshes involved, `None` for a collider without a body of its own like the terrain
+pub type ContactBodies = [Option<PhysMeshHandle>; 2];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ContactEvent {
+    Started { tick: u64, bodies: ContactBodies },
+    // `removed` when it ended because one of the two was destroyed
+    Stopped { tick: u64, bodies: ContactBodies, removed: bool },
+    // once a step while the pair pushes harder than the lower `contact_force_threshold` of the two, bodies without one never send it
+    Force { tick: u64, bodies: ContactBodies, total_force: Vec3, magnitude: f32, max_direction: Vec3, max_magnitude: f32 },
+}
+
+impl ContactEvent {
+    pub fn tick(&self) -> u64 {
+        match *self {
+            ContactEvent::Started { tick, .. } | ContactEvent::Stopped { tick, .. } | ContactEvent::Force { tick, .. } => tick,
+        }
+    }
+
+    pub fn bodies(&self) -> ContactBodies {
+        match *self {
+            ContactEvent::Started { bodies, .. } | ContactEvent::Stopped { bodies, .. } | ContactEvent::Force { bodies, .. } => bodies,
+        }
+    }
+
+    pub fn involves(&self, handle: PhysMeshHandle) -> bool {
+        self.bodies().contains(&Some(handle))
+    }
+}
+
+/* what rapier's `ChannelEventCollector` gathered during a step, turned into events about meshes and handed to every subscriber */
+pub struct ContactStream {
+    collisions: Receiver<CollisionEvent>,
+    forces: Receiver<ContactForceEvent>,
+    // rapier only reports the contacts of a removed collider ending on the step after it's gone, by then nothing leads back to its mesh
+    removed: HashMap<ColliderHandle, PhysMeshHandle>,
+    sender: broadcast::Sender<ContactEvent>,
+}
+
+impl ContactStream {
+    pub fn new() -> (Self, ChannelEventCollector) {
+        let (collision_sender, collisions) = channel::unbounded();
+        let (force_sender, forces) = channel::unbounded();
+        let (sender, _) = broadcast::channel(CONTACT_EVENT_CAPACITY);
+
+        (Self { collisions, forces, removed: HashMap::new(), sender }, ChannelEventCollector::new(collision_sender, force_sender))
+    }
+
+    pub fn sender(&self) -> broadcast::Sender<ContactEvent> {
+        self.sender.clone()
+    }
+
+    // must be called before the collider goes, it still has to be reported
+    pub fn collider_removed(&mut self, collider: ColliderHandle, handle: PhysMeshHandle) {
+        self.removed.insert(collider, handle);
+    }
+
+    // drops whatever is pending, none of it belongs to the world that replaced the old one
+    pub fn reset(&mut self) {
+        while self.collisions.try_recv().is_ok() {}
+        while self.forces.try_recv().is_ok() {}
+        self.removed.clear();
+    }
+
+    pub fn publish(&mut self, tick: u64, bodies: &RigidBodySet, colliders: &ColliderSet) {
+        let removed = &self.removed;
+        let mesh = |collider: ColliderHandle| colliders.get(collider)
+            .map(|collider| collider.parent().and_then(|body| bodies.get(body)).and_then(|body| PhysMeshHandle::from_user_data(body.user_data)))
+            .unwrap_or_else(|| removed.get(&collider).copied());
+
+        // nobody listening is fine, the events are dropped
+        while let Ok(event) = self.collisions.try_recv() {
+            let event = match event {
+                CollisionEvent::Started(a, b, _) => ContactEvent::Started { tick, bodies: [mesh(a), mesh(b)] },
+                CollisionEvent::Stopped(a, b, flags) => ContactEvent::Stopped { tick, bodies: [mesh(a), mesh(b)], removed: flags.contains(CollisionEventFlags::REMOVED) },
+            };
+            let _ = self.sender.send(event);
+        }
+        while let Ok(event) = self.forces.try_recv() {
+            let (total, max) = (event.total_force, event.max_force_direction);
+            let _ = self.sender.send(ContactEvent::Force {
+                tick,
+                bodies: [mesh(event.collider1), mesh(event.collider2)],
+                total_force: vec3(total.x, total.y, total.z),
+                magnitude: event.total_force_magnitude,
+                max_direction: vec3(max.x, max.y, max.z),
+                max_magnitude: event.max_force_magnitude,
+            });
+        }
+
+        self.removed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::{phys::World, physics_util::BodyShape, scene::SceneBody, sink::SceneRecorder};
+
+    // a ball dropped onto a fixed one, stepped until they touch
+    async fn dropped_pair(world: &mut World, contacts: &mut broadcast::Receiver<ContactEvent>) -> (PhysMeshHandle, PhysMeshHandle) {
+        let mut sink = SceneRecorder::new();
+        let ball = |body_type, y| SceneBody::new(BodyShape::Sphere { radius: 0.5 }, body_type, vec3(0.0, y, 0.0));
+        let below = world.spawn(&mut sink, &ball(RigidBodyType::Fixed, 0.0)).await.unwrap();
+        let above = world.spawn(&mut sink, &ball(RigidBodyType::Dynamic, 2.0)).await.unwrap();
+
+        for _ in 0..60 {
+            world.step().await;
+            if let Some(event) = drain(contacts).first() {
+                assert!(matches!(event, ContactEvent::Started { .. }), "{event:?}");
+                assert!(event.involves(below) && event.involves(above));
+                return (below, above);
+            }
+        }
+        panic!("they never touched");
+    }
+
+    fn drain(contacts: &mut broadcast::Receiver<ContactEvent>) -> Vec<ContactEvent> {
+        let mut events = Vec::new();
+        loop {
+            match contacts.try_recv() {
+                Ok(event) => events.push(event),
+                Err(TryRecvError::Empty) => return events,
+                Err(err) => panic!("{err}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn touching_and_parting_are_reported() {
+        let mut world = World::new().await;
+        let mut contacts = world.subscribe_contacts();
+        let (below, above) = dropped_pair(&mut world, &mut contacts).await;
+
+        let body = world.phys_mesh(above).unwrap().body;
+        world.phys_world.lock().await.rigid_body_set[body].apply_impulse(vector![0.0, 20.0, 0.0], true);
+        let mut stopped = None;
+        for _ in 0..30 {
+            world.step().await;
+            stopped = drain(&mut contacts).into_iter().find(|event| matches!(event, ContactEvent::Stopped { .. }));
+            if stopped.is_some() {
+                break;
+            }
+        }
+
+        let Some(ContactEvent::Stopped { tick, bodies, removed }) = stopped else {
+            panic!("never let go");
+        };
+        assert!(bodies.contains(&Some(below)) && bodies.contains(&Some(above)));
+        assert!(!removed);
+        assert_eq!(tick, world.phys_world.lock().await.tick);
+    }
+
+    #[tokio::test]
+    async fn destroyed_bodies_are_named_in_their_last_stop() {
+        let mut world = World::new().await;
+        let mut contacts = world.subscribe_contacts();
+        let (below, above) = dropped_pair(&mut world, &mut contacts).await;
+        world.step().await;
+        drain(&mut contacts);
+
+        world.destroy(&mut SceneRecorder::new(), above).await.unwrap();
+        world.step().await;
+
+        let events = drain(&mut contacts);
+        assert_eq!(events.len(), 1, "{events:?}");
+        let ContactEvent::Stopped { bodies, removed, .. } = events[0] else {
+            panic!("{:?}", events[0]);
+        };
+        // the collider is gone by now, only the removed map still knows whose it was
+        assert!(bodies.contains(&Some(above)) && bodies.contains(&Some(below)), "{bodies:?}");
+        assert!(removed);
+    }
+}
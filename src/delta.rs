@@ -0,0 +1,321 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::{
+    phys::PhysMeshHandle,
+    protocol::NetError,
+    quantize::{BitReader, BitWriter, QuantizedPose, POSITION_BITS},
+};
+
+// a snapshot never grows past this, so it always fits in one unfragmented datagram
+pub const MTU: usize = 1200;
+// what bincode wraps around the payload: packet header, message tag, tick, baseline and length
+pub const SNAPSHOT_HEADER_BYTES: usize = 64;
+// snapshots remembered on both ends to delta against, ~3s at 20hz
+pub const DELTA_HISTORY: usize = 64;
+
+const COUNT_BITS: u32 = 16;
+const SMALL_ID_BITS: u32 = 12;
+const SMALL_DELTA_BITS: u32 = 9;
+
+const POSITION_UNCHANGED: u32 = 0;
+const POSITION_SMALL: u32 = 1;
+const POSITION_FULL: u32 = 2;
+
+// what one end believes the other end knows, keyed by network id
+pub type PoseTable = HashMap<PhysMeshHandle, QuantizedPose>;
+
+pub struct BodyState {
+    pub handle: PhysMeshHandle,
+    pub pose: QuantizedPose,
+    // asleep according to the island manager
+    pub resting: bool,
+    pub speed: f32,
+}
+
+pub struct EncodedSnapshot {
+    pub baseline: Option<u64>,
+    pub data: Vec<u8>,
+}
+
+/* server side, one per client */
+pub struct DeltaEncoder {
+    sent: VecDeque<(u64, PoseTable)>,
+    pub acked: Option<u64>,
+    pub priority: HashMap<PhysMeshHandle, f32>,
+    pub budget: usize,
+}
+
+impl Default for DeltaEncoder {
+    fn default() -> Self {
+        Self {
+            sent: VecDeque::with_capacity(DELTA_HISTORY),
+            acked: None,
+            priority: HashMap::new(),
+            budget: MTU,
+        }
+    }
+}
+
+impl DeltaEncoder {
+    pub fn ack(&mut self, tick: u64) {
+        let newer = self.acked.is_none_or(|acked| tick > acked);
+        if newer && self.sent.iter().any(|(sent, _)| *sent == tick) {
+            self.acked = Some(tick);
+        }
+    }
+
+    // `bodies` is the whole world right now, only what the client doesn't already know goes out
+    // None when `tick` was already encoded, a second snapshot of it could be decoded against a different baseline
+    // than the one we'd remember for it, and both ends would delta from different states from then on
+    pub fn encode(&mut self, tick: u64, bodies: &[BodyState]) -> Option<EncodedSnapshot> {
+        if self.sent.back().is_some_and(|(sent, _)| *sent >= tick) {
+            return None;
+        }
+
+        let empty = PoseTable::new();
+        let (baseline_tick, baseline) = match self.acked.and_then(|acked| self.sent.iter().find(|(sent, _)| *sent == acked)) {
+            Some((sent, poses)) => (Some(*sent), poses),
+            None => (None, &empty),
+        };
+
+        let present: HashSet<PhysMeshHandle> = bodies.iter().map(|body| body.handle).collect();
+        self.priority.retain(|handle, _| present.contains(handle));
+
+        let mut candidates = Vec::new();
+        for body in bodies {
+            let known = baseline.get(&body.handle);
+            let changed = match known {
+                None => true,
+                // a body can fall asleep on the same tick it moved, so resting alone says nothing
+                Some(pose) => *pose != body.pose,
+            };
+            if !changed {
+                self.priority.remove(&body.handle);
+                continue;
+            }
+
+            // bodies the client has never seen matter most, then the fast ones
+            let weight = if known.is_some() { 1.0 + body.speed } else { 10.0 };
+            let priority = self.priority.entry(body.handle).or_insert(0.0);
+            *priority += weight;
+            candidates.push((*priority, body, known));
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.handle.id.cmp(&b.1.handle.id)));
+
+        let budget = (self.budget.saturating_sub(SNAPSHOT_HEADER_BYTES) * 8).saturating_sub(COUNT_BITS as usize);
+        let mut state: PoseTable = bodies.iter()
+            .filter_map(|body| Some((body.handle, *baseline.get(&body.handle)?)))
+            .collect();
+        let mut entries = BitWriter::new();
+        let mut written = 0;
+
+        for (_, body, known) in &candidates {
+            let mut entry = BitWriter::new();
+            write_entry(&mut entry, body.handle, &body.pose, *known);
+
+            // keep going, a smaller entry further down might still fit
+            if entries.bits() + entry.bits() > budget {
+                continue;
+            }
+
+            entries.append(&entry);
+            written += 1;
+            state.insert(body.handle, body.pose);
+            self.priority.remove(&body.handle);
+        }
+
+        let mut writer = BitWriter::new();
+        writer.write(written as u32, COUNT_BITS);
+        writer.append(&entries);
+
+        if self.sent.len() == DELTA_HISTORY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((tick, state));
+
+        Some(EncodedSnapshot {
+            baseline: baseline_tick,
+            data: writer.into_bytes(),
+        })
+    }
+}
+
+/* client side */
+#[derive(Default)]
+pub struct DeltaDecoder {
+    received: VecDeque<(u64, PoseTable)>,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns the full state at `tick`, not just what changed
+    pub fn decode(&mut self, tick: u64, baseline: Option<u64>, data: &[u8]) -> Result<PoseTable, NetError> {
+        if let Some((_, poses)) = self.received.iter().find(|(received, _)| *received == tick) {
+            return Ok(poses.clone());
+        }
+
+        let mut state = match baseline {
+            Some(baseline) => self.received.iter()
+                .find(|(received, _)| *received == baseline)
+                .map(|(_, poses)| poses.clone())
+                .ok_or(NetError::MissingBaseline(baseline))?,
+            None => PoseTable::new(),
+        };
+
+        let mut reader = BitReader::new(data);
+        let count = reader.read(COUNT_BITS).ok_or(NetError::Malformed)?;
+        for _ in 0..count {
+            let (handle, pose) = read_entry(&mut reader, &state).ok_or(NetError::Malformed)?;
+            state.insert(handle, pose);
+        }
+
+        let i = self.received.partition_point(|(received, _)| *received < tick);
+        self.received.insert(i, (tick, state.clone()));
+        if self.received.len() > DELTA_HISTORY {
+            self.received.pop_front();
+        }
+
+        Ok(state)
+    }
+}
+
+fn write_entry(writer: &mut BitWriter, handle: PhysMeshHandle, pose: &QuantizedPose, known: Option<&QuantizedPose>) {
+    let small_id = handle.id < 1 << SMALL_ID_BITS;
+    writer.write_bool(small_id);
+    writer.write(handle.id, if small_id { SMALL_ID_BITS } else { 32 });
+    // slots only get a generation once they're reused, so this is nearly always a single bit
+    writer.write_bool(handle.generation != 0);
+    if handle.generation != 0 {
+        writer.write(handle.generation, 32);
+    }
+
+    let half = 1i64 << (SMALL_DELTA_BITS - 1);
+    match known {
+        Some(known) if known.position == pose.position => writer.write(POSITION_UNCHANGED, 2),
+        Some(known) => {
+            let deltas = [0, 1, 2].map(|i| pose.position[i] as i64 - known.position[i] as i64);
+            if deltas.iter().all(|d| (-half..half).contains(d)) {
+                writer.write(POSITION_SMALL, 2);
+                for d in deltas {
+                    writer.write((d + half) as u32, SMALL_DELTA_BITS);
+                }
+            } else {
+                writer.write(POSITION_FULL, 2);
+                for p in pose.position {
+                    writer.write(p, POSITION_BITS);
+                }
+            }
+        }
+        None => {
+            writer.write(POSITION_FULL, 2);
+            for p in pose.position {
+                writer.write(p, POSITION_BITS);
+            }
+        }
+    }
+
+    let rotated = known.is_none_or(|known| known.rotation != pose.rotation);
+    writer.write_bool(rotated);
+    if rotated {
+        writer.write(pose.rotation, 32);
+    }
+}
+
+fn read_entry(reader: &mut BitReader, state: &PoseTable) -> Option<(PhysMeshHandle, QuantizedPose)> {
+    let small_id = reader.read_bool()?;
+    let id = reader.read(if small_id { SMALL_ID_BITS } else { 32 })?;
+    let generation = if reader.read_bool()? { reader.read(32)? } else { 0 };
+    let handle = PhysMeshHandle { id, generation };
+    let known = state.get(&handle);
+
+    let half = 1i64 << (SMALL_DELTA_BITS - 1);
+    let position = match reader.read(2)? {
+        POSITION_UNCHANGED => known?.position,
+        POSITION_SMALL => {
+            let known = known?.position;
+            let mut position = [0; 3];
+            for i in 0..3 {
+                let delta = reader.read(SMALL_DELTA_BITS)? as i64 - half;
+                position[i] = (known[i] as i64 + delta) as u32;
+            }
+            position
+        }
+        POSITION_FULL => [reader.read(POSITION_BITS)?, reader.read(POSITION_BITS)?, reader.read(POSITION_BITS)?],
+        _ => return None,
+    };
+
+    let rotation = if reader.read_bool()? { reader.read(32)? } else { known?.rotation };
+
+    Some((handle, QuantizedPose { position, rotation }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_framework::{vec3, Quat};
+
+    use super::*;
+
+    fn bodies(x: f32) -> Vec<BodyState> {
+        (0..4)
+            .map(|id| BodyState {
+                handle: PhysMeshHandle { id, generation: 0 },
+                pose: QuantizedPose::new(vec3(x + id as f32, 1.0, 0.0), Quat::IDENTITY),
+                resting: false,
+                speed: 1.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_tick_is_only_encoded_once() {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoder = DeltaDecoder::new();
+
+        let first = encoder.encode(1, &bodies(0.0)).unwrap();
+        decoder.decode(1, first.baseline, &first.data).unwrap();
+        encoder.ack(1);
+
+        // sending faster than the world steps, tick 2 goes out once whatever the bodies did in between
+        let second = encoder.encode(2, &bodies(1.0)).unwrap();
+        assert!(encoder.encode(2, &bodies(2.0)).is_none());
+        assert!(encoder.encode(1, &bodies(2.0)).is_none());
+        let decoded = decoder.decode(2, second.baseline, &second.data).unwrap();
+        encoder.ack(2);
+
+        let third = encoder.encode(3, &bodies(3.0)).unwrap();
+        assert_eq!(third.baseline, Some(2));
+        let state = decoder.decode(3, third.baseline, &third.data).unwrap();
+
+        for body in bodies(3.0) {
+            assert_eq!(state[&body.handle], body.pose);
+        }
+        assert_eq!(decoded.len(), 4);
+    }
+
+    #[test]
+    fn bodies_that_moved_and_fell_asleep_are_still_sent() {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoder = DeltaDecoder::new();
+
+        let first = encoder.encode(1, &bodies(0.0)).unwrap();
+        decoder.decode(1, first.baseline, &first.data).unwrap();
+        encoder.ack(1);
+
+        // the last step both moved them and put them to sleep
+        let mut settled = bodies(0.5);
+        for body in &mut settled {
+            body.resting = true;
+            body.speed = 0.0;
+        }
+        let second = encoder.encode(2, &settled).unwrap();
+        assert_eq!(second.baseline, Some(1));
+        let state = decoder.decode(2, second.baseline, &second.data).unwrap();
+
+        for body in settled {
+            assert_eq!(state[&body.handle], body.pose);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::{str::FromStr, time::Instant};
+
+use chaos_framework::{vec3, Vec3};
+use rapier3d::prelude::*;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use crate::{articulation::{ChainDesc, GroupKind}, contact::ContactEvent, import::ImportCollider, phys::World, physics_util::BodyShape, scene::SceneBody, sink::{SceneEvent, SceneRecorder, SceneSink}, terrain::TerrainDesc};
+
+pub struct HeadlessOptions {
+    pub ticks: Option<u32>,
+    pub hz: f32,
+    pub bodies: u32,
+    pub until_sleep: bool,
+    pub report_every: u32,
+    pub scene: Option<String>,
+    pub save: Option<String>,
+    // model files dropped in above everything else, `--import a.obj --import b.glb`
+    pub imports: Vec<String>,
+    pub import_collider: ImportCollider,
+    // articulated groups in a row behind the grid, `--chains 2 --ragdolls 3 --arms 1`
+    pub chains: u32,
+    pub ragdolls: u32,
+    pub arms: u32,
+    // grid bodies report contact forces above this, `--force-events 500`
+    pub contact_force_threshold: Option<f32>,
+    // ignored when a scene brings its own
+    pub terrain: TerrainDesc,
+}
+
+impl HeadlessOptions {
+    // returns None unless `--headless` was passed
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Option<Self>, ArgError> {
+        let args: Vec<String> = args.collect();
+        if !args.iter().any(|arg| arg == "--headless") {
+            return Ok(None);
+        }
+
+        let until_sleep = args.iter().any(|arg| arg == "--until-sleep");
+        let ticks = flag_value(&args, "--ticks")?;
+
+        Ok(Some(Self {
+            ticks: if ticks.is_none() && !until_sleep { Some(600) } else { ticks },
+            hz: positive_flag_value(&args, "--hz")?.unwrap_or(60.0),
+            bodies: flag_value(&args, "--bodies")?.unwrap_or(64),
+            until_sleep,
+            report_every: flag_value(&args, "--report-every")?.unwrap_or(60),
+            scene: flag_value(&args, "--scene")?,
+            save: flag_value(&args, "--save")?,
+            imports: flag_values(&args, "--import")?,
+            import_collider: flag_value(&args, "--collider")?.unwrap_or(ImportCollider::ConvexHull),
+            chains: flag_value(&args, "--chains")?.unwrap_or(0),
+            ragdolls: flag_value(&args, "--ragdolls")?.unwrap_or(0),
+            arms: flag_value(&args, "--arms")?.unwrap_or(0),
+            contact_force_threshold: flag_value(&args, "--force-events")?,
+            terrain: TerrainDesc::from_args(&args)?,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgError {
+    pub flag: String,
+    pub value: String,
+}
+
+impl std::fmt::Display for ArgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid value for {}: {}", self.flag, self.value)
+    }
+}
+
+impl std::error::Error for ArgError {}
+
+fn parse_flag<T: FromStr>(name: &str, value: &str) -> Result<T, ArgError> {
+    value.parse().map_err(|_| ArgError { flag: name.to_string(), value: value.to_string() })
+}
+
+// `Ok(None)` when the flag wasn't passed at all
+pub fn flag_value<T: FromStr>(args: &[String], name: &str) -> Result<Option<T>, ArgError> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|value| parse_flag(name, value))
+        .transpose()
+}
+
+// rates and durations, anything that ends up divided by or in a `Duration` has to be finite and above zero
+pub fn positive_flag_value(args: &[String], name: &str) -> Result<Option<f32>, ArgError> {
+    match flag_value::<f32>(args, name)? {
+        Some(value) if !value.is_finite() || value <= 0.0 => Err(ArgError { flag: name.to_string(), value: value.to_string() }),
+        value => Ok(value),
+    }
+}
+
+// a list flag that may be given more than once, e.g. `--peer a --peer b`
+pub fn flag_values<T: FromStr>(args: &[String], name: &str) -> Result<Vec<T>, ArgError> {
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| parse_flag(name, &pair[1]))
+        .collect()
+}
+
+// drops the bodies in a loose column so they actually collide with each other, always in the same order
+// stacked on whatever ground is under them, so set the terrain first
+pub async fn spawn_grid(world: &mut World, sink: &mut dyn SceneSink, bodies: u32, contact_force_threshold: Option<f32>) {
+    let side = (bodies as f32).sqrt().ceil() as u32;
+    for i in 0..bodies {
+        let (x, z) = ((i % side) as f32 * 2.5, ((i / side) % side) as f32 * 2.5);
+        let y = world.ground_height(x, z) + 2.0 + (i / (side * side)) as f32 * 2.5 + (i % 3) as f32 * 0.5;
+
+        let body = if i % 2 == 0 {
+            SceneBody::new(BodyShape::Cuboid { half_extents: Vec3::ONE }, RigidBodyType::Dynamic, vec3(x, y, z))
+        } else {
+            SceneBody { restitution: 0.7, ..SceneBody::new(BodyShape::Sphere { radius: 1.0 }, RigidBodyType::Dynamic, vec3(x, y, z)) }
+        };
+
+        // nothing imported, always spawns
+        let _ = world.spawn(sink, &SceneBody { contact_force_threshold, ..body }).await;
+    }
+}
+
+pub async fn run(opts: HeadlessOptions) {
+    let mut world = World::new().await;
+    world.set_physics_rate(opts.hz);
+    let dt = world.timestep.dt();
+    let mut recorder = SceneRecorder::new();
+
+    if let Some(path) = &opts.scene {
+        if let Err(err) = world.load_scene(&mut recorder, path).await {
+            eprintln!("could not load scene {path}: {err}");
+            return;
+        }
+    } else if let Err(err) = world.set_terrain(&mut recorder, Some(opts.terrain.clone())).await {
+        eprintln!("{err}");
+        return;
+    }
+
+    let bodies = if opts.scene.is_some() { 0 } else { opts.bodies };
+    spawn_grid(&mut world, &mut recorder, bodies, opts.contact_force_threshold).await;
+
+    for (i, path) in opts.imports.iter().enumerate() {
+        if let Err(err) = world.add_imported(&mut recorder, path, opts.import_collider, vec3(i as f32 * 4.0, 10.0, -6.0)).await {
+            eprintln!("could not import {path}: {err}");
+            return;
+        }
+    }
+
+    let articulated = [(GroupKind::Chain, opts.chains), (GroupKind::Ragdoll, opts.ragdolls), (GroupKind::RobotArm, opts.arms)]
+        .into_iter()
+        .flat_map(|(kind, count)| std::iter::repeat_n(kind, count as usize));
+    for (i, kind) in articulated.enumerate() {
+        let (x, z) = (i as f32 * 3.0, -10.0);
+        // chains hang from where the others stand
+        let y = world.ground_height(x, z) + if kind == GroupKind::Chain { 8.0 } else { 0.0 };
+        if let Err(err) = world.spawn_articulated(&mut recorder, kind, &ChainDesc::default(), vec3(x, y, z)).await {
+            eprintln!("could not spawn {}: {err}", kind.name());
+            return;
+        }
+    }
+
+    println!(
+        "headless: {} bodies, {} Hz (dt = {:.4}s), {}",
+        world.phys_meshes.len(),
+        opts.hz,
+        dt,
+        match opts.ticks {
+            Some(ticks) if opts.until_sleep => format!("up to {ticks} ticks or until every body sleeps"),
+            Some(ticks) => format!("{ticks} ticks"),
+            None => "until every body sleeps".to_string(),
+        },
+    );
+
+    let mut contacts = world.subscribe_contacts();
+    let mut contact_counts = ContactCounts::default();
+
+    let start = Instant::now();
+    let mut tick = 0;
+    let mut total_solve = 0.0;
+    let mut max_solve: f32 = 0.0;
+
+    loop {
+        if opts.ticks.is_some_and(|ticks| tick >= ticks) {
+            break;
+        }
+
+        if let Some(status) = world.step().await {
+            total_solve += status.solve_time;
+            max_solve = max_solve.max(status.solve_time);
+        }
+        tick += 1;
+        contact_counts.drain(&mut contacts);
+
+        let phys_world = world.phys_world.lock().await;
+        let stats = BodyStats::collect(&phys_world.rigid_body_set);
+        drop(phys_world);
+
+        recorder.clear_events();
+        world.sync(&mut recorder);
+
+        if opts.report_every > 0 && tick % opts.report_every == 0 {
+            let highest = recorder.bodies.values().map(|(pos, _)| pos.y).fold(f32::MIN, f32::max);
+            println!("tick {tick:>6}: {stats}, highest body at y = {highest:.2}");
+        }
+
+        if opts.until_sleep && stats.awake == 0 {
+            println!("every body is asleep after {tick} ticks");
+            break;
+        }
+    }
+
+    let wall = start.elapsed().as_secs_f32();
+
+    if let Some(path) = &opts.save {
+        match world.save_scene(path).await {
+            Ok(()) => println!("saved scene to {path}"),
+            Err(err) => eprintln!("could not save scene to {path}: {err}"),
+        }
+    }
+
+    let phys_world = world.phys_world.lock().await;
+    let stats = BodyStats::collect(&phys_world.rigid_body_set);
+
+    println!("--- headless run finished ---");
+    println!("ticks:      {tick} ({:.2}s simulated)", tick as f32 * dt);
+    println!("wall time:  {:.3}s ({:.1} ticks/s)", wall, tick as f32 / wall.max(f32::EPSILON));
+    println!(
+        "solve time: avg {:.3}ms, max {:.3}ms",
+        total_solve / tick.max(1) as f32 * 1000.0,
+        max_solve * 1000.0,
+    );
+    if let Some(status) = world.status {
+        println!("steps:      {} simulated, {} skipped", status.simulated_steps, status.skipped_steps);
+    }
+    println!("colliders:  {}", phys_world.collider_set.len());
+    println!("groups:     {} ({} articulations)", world.groups.len(), phys_world.multibody_joints.len());
+    println!("bodies:     {stats}");
+    println!("contacts:   {contact_counts}");
+    println!(
+        "scene:      {} bodies mirrored, {} transforms in the last sync",
+        recorder.bodies.len(),
+        recorder.events.iter().filter(|e| matches!(e, SceneEvent::Transform(..))).count(),
+    );
+}
+
+#[derive(Default)]
+pub struct ContactCounts {
+    pub started: u64,
+    pub stopped: u64,
+    pub forces: u64,
+    // fell too far behind the physics task and never saw these
+    pub missed: u64,
+}
+
+impl ContactCounts {
+    pub fn drain(&mut self, contacts: &mut broadcast::Receiver<ContactEvent>) {
+        loop {
+            match contacts.try_recv() {
+                Ok(ContactEvent::Started { .. }) => self.started += 1,
+                Ok(ContactEvent::Stopped { .. }) => self.stopped += 1,
+                Ok(ContactEvent::Force { .. }) => self.forces += 1,
+                Err(TryRecvError::Lagged(missed)) => self.missed += missed,
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ContactCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} started, {} stopped, {} force events", self.started, self.stopped, self.forces)?;
+        if self.missed > 0 {
+            write!(f, ", {} missed", self.missed)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct BodyStats {
+    pub total: usize,
+    pub dynamic: usize,
+    pub awake: usize,
+    pub kinetic_energy: f32,
+}
+
+impl BodyStats {
+    pub fn collect(bodies: &RigidBodySet) -> Self {
+        let mut stats = Self { total: bodies.len(), dynamic: 0, awake: 0, kinetic_energy: 0.0 };
+
+        for (_, body) in bodies.iter() {
+            if !body.is_dynamic() {
+                continue;
+            }
+
+            stats.dynamic += 1;
+            stats.kinetic_energy += body.kinetic_energy();
+            if !body.is_sleeping() {
+                stats.awake += 1;
+            }
+        }
+
+        stats
+    }
+}
+
+impl std::fmt::Display for BodyStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} total, {} dynamic, {} awake, {} sleeping, kinetic energy {:.2}",
+            self.total,
+            self.dynamic,
+            self.awake,
+            self.dynamic - self.awake,
+            self.kinetic_energy,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn rates_have_to_be_positive() {
+        assert_eq!(positive_flag_value(&args("--hz 30"), "--hz"), Ok(Some(30.0)));
+        assert_eq!(positive_flag_value(&args("--ticks 3"), "--hz"), Ok(None));
+        for bad in ["0", "-5", "NaN", "inf", "fast"] {
+            assert!(positive_flag_value(&args(&format!("--hz {bad}")), "--hz").is_err(), "{bad}");
+        }
+
+        assert!(HeadlessOptions::from_args(args("--headless --hz 0").into_iter()).is_err());
+        assert_eq!(HeadlessOptions::from_args(args("--headless --hz 120").into_iter()).unwrap().unwrap().hz, 120.0);
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fmt,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use chaos_framework::{Mat4, Vec3};
+use rapier3d::{parry::shape::Cuboid, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::lockstep::Fnv;
+
+// colliders kept built at once, a decomposition can take seconds but every scale on the slider is a new one
+pub const SHAPE_CACHE_SIZE: usize = 16;
+
+/* an imported file, cheap to copy around in a `BodyShape`: a hash of its contents, the triangles live in an `AssetStore` */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MeshAsset(pub u64);
+
+impl fmt::Display for MeshAsset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+struct Asset {
+    // where it was first read from, scenes save this to find it again
+    path: PathBuf,
+    mesh: Arc<ImportedMesh>,
+}
+
+/* every file a world has imported, nothing is ever read from disk unless `load` is called */
+#[derive(Default)]
+pub struct AssetStore {
+    assets: HashMap<MeshAsset, Asset>,
+    // most recently used last
+    shapes: VecDeque<((MeshAsset, ImportCollider, u32), SharedShape)>,
+}
+
+impl AssetStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the same contents always give back the same asset, whatever the path
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<MeshAsset, ImportError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|err| ImportError::Io(path.to_path_buf(), err))?;
+        let mut hasher = Fnv::new();
+        hasher.write_bytes(&bytes);
+        let asset = MeshAsset(hasher.0);
+
+        if let Entry::Vacant(entry) = self.assets.entry(asset) {
+            let mesh = ImportedMesh::load(path)?;
+            entry.insert(Asset { path: path.to_path_buf(), mesh: Arc::new(mesh) });
+        }
+
+        Ok(asset)
+    }
+
+    pub fn contains(&self, asset: MeshAsset) -> bool {
+        self.assets.contains_key(&asset)
+    }
+
+    pub fn path(&self, asset: MeshAsset) -> Option<&Path> {
+        self.assets.get(&asset).map(|asset| asset.path.as_path())
+    }
+
+    pub fn mesh(&self, asset: MeshAsset) -> Option<Arc<ImportedMesh>> {
+        self.assets.get(&asset).map(|asset| asset.mesh.clone())
+    }
+
+    // None for an asset this store never loaded
+    pub fn collider_shape(&mut self, asset: MeshAsset, collider: ImportCollider, scale: f32) -> Option<SharedShape> {
+        let key = (asset, collider, scale.to_bits());
+        if let Some(i) = self.shapes.iter().position(|(cached, _)| *cached == key) {
+            let entry = self.shapes.remove(i)?;
+            let shape = entry.1.clone();
+            self.shapes.push_back(entry);
+            return Some(shape);
+        }
+
+        let shape = self.assets.get(&asset)?.mesh.shape(collider, scale);
+        if self.shapes.len() == SHAPE_CACHE_SIZE {
+            self.shapes.pop_front();
+        }
+        self.shapes.push_back((key, shape.clone()));
+
+        Some(shape)
+    }
+}
+
+/* triangles from a model file, every mesh and primitive in it merged into one */
+pub struct ImportedMesh {
+    pub vertices: Vec<Point<Real>>,
+    pub indices: Vec<[u32; 3]>,
+    // furthest any vertex is from the origin along an axis, the origin of the file is the body's centre
+    pub half_size: f32,
+    // how far the lowest vertex is below the origin
+    pub depth: f32,
+}
+
+impl ImportedMesh {
+    pub fn new(vertices: Vec<Point<Real>>, indices: Vec<[u32; 3]>) -> Self {
+        let half_size = vertices.iter().map(|p| p.coords.amax()).fold(0.0, f32::max);
+        let depth = vertices.iter().map(|p| -p.y).fold(0.0, f32::max);
+        Self { vertices, indices, half_size, depth }
+    }
+
+    // what a side that never loaded the file draws instead
+    pub fn placeholder() -> Self {
+        let (vertices, indices) = Cuboid::new(vector![0.5, 0.5, 0.5]).to_trimesh();
+        Self::new(vertices, indices)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ImportError> {
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+        let mesh = match extension.as_deref() {
+            Some("obj") => load_obj(path)?,
+            Some("gltf" | "glb") => load_gltf(path)?,
+            _ => return Err(ImportError::UnknownFormat(path.to_path_buf())),
+        };
+
+        if mesh.indices.is_empty() {
+            return Err(ImportError::Empty(path.to_path_buf()));
+        }
+
+        Ok(mesh)
+    }
+
+    fn scaled(&self, scale: f32) -> Vec<Point<Real>> {
+        self.vertices.iter().map(|p| p * scale).collect()
+    }
+
+    fn shape(&self, collider: ImportCollider, scale: f32) -> SharedShape {
+        let vertices = self.scaled(scale);
+        match collider {
+            // only fails for flat meshes, which have no hull with a volume
+            ImportCollider::ConvexHull => SharedShape::convex_hull(&vertices)
+                .unwrap_or_else(|| SharedShape::trimesh(vertices, self.indices.clone())),
+            ImportCollider::Decomposition => SharedShape::convex_decomposition(&vertices, &self.indices),
+            ImportCollider::TriMesh => SharedShape::trimesh(vertices, self.indices.clone()),
+        }
+    }
+}
+
+fn load_obj(path: &Path) -> Result<ImportedMesh, ImportError> {
+    let options = tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ignore_points: true,
+        ignore_lines: true,
+    };
+    let (models, _) = tobj::load_obj(path, &options).map_err(ImportError::Obj)?;
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for model in models {
+        let offset = vertices.len() as u32;
+        vertices.extend(model.mesh.positions.chunks_exact(3).map(|p| point![p[0], p[1], p[2]]));
+        indices.extend(triangles(path, &model.mesh.indices, vertices.len() as u32 - offset, offset)?);
+    }
+
+    Ok(ImportedMesh::new(vertices, indices))
+}
+
+// the default scene with every node's transform applied, textures are never read
+fn load_gltf(path: &Path) -> Result<ImportedMesh, ImportError> {
+    let gltf = gltf::Gltf::open(path).map_err(ImportError::Gltf)?;
+    let buffers = gltf::import_buffers(&gltf.document, path.parent(), gltf.blob.clone()).map_err(ImportError::Gltf)?;
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut nodes: Vec<(gltf::Node, Mat4)> = match gltf.document.default_scene().or_else(|| gltf.document.scenes().next()) {
+        Some(scene) => scene.nodes().map(|node| (node, Mat4::IDENTITY)).collect(),
+        None => Vec::new(),
+    };
+
+    while let Some((node, parent)) = nodes.pop() {
+        let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+        nodes.extend(node.children().map(|child| (child, transform)));
+
+        let Some(mesh) = node.mesh() else {
+            continue;
+        };
+        for primitive in mesh.primitives().filter(|primitive| primitive.mode() == gltf::mesh::Mode::Triangles) {
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data.0[..]));
+            let Some(positions) = reader.read_positions() else {
+                continue;
+            };
+
+            let offset = vertices.len() as u32;
+            vertices.extend(positions.map(|p| {
+                let p = transform.transform_point3(Vec3::from_array(p));
+                point![p.x, p.y, p.z]
+            }));
+            let count = vertices.len() as u32 - offset;
+
+            // no index buffer means every three vertices are a triangle
+            let primitive_indices: Vec<u32> = match reader.read_indices() {
+                Some(read) => read.into_u32().collect(),
+                None => (0..count).collect(),
+            };
+            indices.extend(triangles(path, &primitive_indices, count, offset)?);
+        }
+    }
+
+    Ok(ImportedMesh::new(vertices, indices))
+}
+
+// a file's own indices moved past the `offset` vertices before them, each has to point at one of its `count`
+// rapier panics on a triangle that reaches past the end
+fn triangles(path: &Path, indices: &[u32], count: u32, offset: u32) -> Result<Vec<[u32; 3]>, ImportError> {
+    if let Some(index) = indices.iter().find(|index| **index >= count) {
+        return Err(ImportError::BadIndex(path.to_path_buf(), *index));
+    }
+
+    Ok(indices.chunks_exact(3).map(|t| [t[0] + offset, t[1] + offset, t[2] + offset]).collect())
+}
+
+/* how an import collides, the render mesh is always the file's own triangles */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ImportCollider {
+    ConvexHull,
+    // v-hacd, concave shapes made of several hulls
+    Decomposition,
+    // exact but hollow, rapier can only use it on fixed bodies
+    TriMesh,
+}
+
+impl ImportCollider {
+    pub const ALL: [ImportCollider; 3] = [ImportCollider::ConvexHull, ImportCollider::Decomposition, ImportCollider::TriMesh];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImportCollider::ConvexHull => "Convex hull",
+            ImportCollider::Decomposition => "Decomposition",
+            ImportCollider::TriMesh => "Triangle mesh",
+        }
+    }
+}
+
+impl FromStr for ImportCollider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hull" => Ok(ImportCollider::ConvexHull),
+            "vhacd" => Ok(ImportCollider::Decomposition),
+            "trimesh" => Ok(ImportCollider::TriMesh),
+            _ => Err(format!("unknown collider {s}, expected hull, vhacd or trimesh")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(PathBuf, io::Error),
+    Obj(tobj::LoadError),
+    Gltf(gltf::Error),
+    UnknownFormat(PathBuf),
+    Empty(PathBuf),
+    // a triangle uses a vertex the file doesn't have
+    BadIndex(PathBuf, u32),
+    UnknownAsset(MeshAsset),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io(path, err) => write!(f, "could not read {}: {err}", path.display()),
+            ImportError::Obj(err) => write!(f, "could not read obj: {err}"),
+            ImportError::Gltf(err) => write!(f, "could not read gltf: {err}"),
+            ImportError::UnknownFormat(path) => write!(f, "{} is not an .obj, .gltf or .glb file", path.display()),
+            ImportError::Empty(path) => write!(f, "{} has no triangles", path.display()),
+            ImportError::BadIndex(path, index) => write!(f, "{} has a triangle using vertex {index}, which it doesn't have", path.display()),
+            ImportError::UnknownAsset(asset) => write!(f, "mesh {asset} was never loaded"),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    #[test]
+    fn loads_an_obj() {
+        let mesh = ImportedMesh::load(&fixture("tetrahedron.obj")).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices.len(), 4);
+        assert!(mesh.indices.iter().flatten().all(|index| (*index as usize) < mesh.vertices.len()));
+        assert_eq!(mesh.half_size, 1.0);
+        assert_eq!(mesh.depth, 0.0);
+    }
+
+    #[test]
+    fn triangles_past_the_vertices_are_refused() {
+        let path = fixture("bad_index.obj");
+        // tobj already refuses this one itself, either way it never gets as far as rapier
+        assert!(matches!(ImportedMesh::load(&path), Err(ImportError::BadIndex(..) | ImportError::Obj(_))));
+
+        let result = ImportedMesh::load(&fixture("bad_index.gltf"));
+        assert!(matches!(result, Err(ImportError::BadIndex(_, 5))), "{:?}", result.err().map(|err| err.to_string()));
+
+        // after the vertices of an earlier primitive
+        assert_eq!(triangles(&path, &[0, 1, 2, 2, 1, 0], 3, 5).unwrap(), vec![[5, 6, 7], [7, 6, 5]]);
+        assert!(matches!(triangles(&path, &[0, 1, 3], 3, 5), Err(ImportError::BadIndex(_, 3))));
+        assert!(matches!(triangles(&path, &[0, 1, u32::MAX], 3, 5), Err(ImportError::BadIndex(_, u32::MAX))));
+    }
+}
@@ -0,0 +1,359 @@
+use chaos_framework::{vec3, Vec3};
+use rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::phys::{PhysMeshHandle, PhysicalWorld, PhysicsError, World};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum JointKind {
+    Fixed,
+    // turns around the axis
+    Revolute,
+    // slides along the axis
+    Prismatic,
+    // turns any way around the anchor
+    Spherical,
+    // anchors at most `length` apart, slack otherwise
+    Rope { length: f32 },
+}
+
+impl JointKind {
+    // one of each, in the order the editor lists them
+    pub const ALL: [JointKind; 5] = [
+        JointKind::Fixed,
+        JointKind::Revolute,
+        JointKind::Prismatic,
+        JointKind::Spherical,
+        JointKind::Rope { length: 4.0 },
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JointKind::Fixed => "Fixed",
+            JointKind::Revolute => "Revolute",
+            JointKind::Prismatic => "Prismatic",
+            JointKind::Spherical => "Spherical",
+            JointKind::Rope { .. } => "Rope",
+        }
+    }
+
+    pub fn same_kind(&self, other: &JointKind) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    // what limits and motors act on, nothing for the ones that can't move or only go slack
+    pub fn free_axes(&self) -> &'static [JointAxis] {
+        match self {
+            JointKind::Revolute => &[JointAxis::AngX],
+            JointKind::Prismatic => &[JointAxis::LinX],
+            JointKind::Spherical => &[JointAxis::AngX, JointAxis::AngY, JointAxis::AngZ],
+            JointKind::Fixed | JointKind::Rope { .. } => &[],
+        }
+    }
+
+    fn locked_axes(&self) -> JointAxesMask {
+        match self {
+            JointKind::Fixed => JointAxesMask::LOCKED_FIXED_AXES,
+            JointKind::Revolute => JointAxesMask::LOCKED_REVOLUTE_AXES,
+            JointKind::Prismatic => JointAxesMask::LOCKED_PRISMATIC_AXES,
+            JointKind::Spherical => JointAxesMask::LOCKED_SPHERICAL_AXES,
+            JointKind::Rope { .. } => JointAxesMask::empty(),
+        }
+    }
+}
+
+// drives the free axes towards `target_position` at `target_velocity`, a stiffness of 0 only cares about the velocity
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JointMotor {
+    pub target_position: f32,
+    pub target_velocity: f32,
+    pub stiffness: f32,
+    pub damping: f32,
+    pub max_force: f32,
+}
+
+impl Default for JointMotor {
+    fn default() -> Self {
+        Self { target_position: 0.0, target_velocity: 1.0, stiffness: 0.0, damping: 1.0, max_force: 1000.0 }
+    }
+}
+
+/* everything needed to rebuild a joint, the same in a scene file, a snapshot and the editor */
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JointDesc {
+    pub kind: JointKind,
+    // where the joint sits on each body and the axis it turns or slides along, in that body's own space
+    pub anchor1: Vec3,
+    pub anchor2: Vec3,
+    pub axis1: Vec3,
+    pub axis2: Vec3,
+    // radians for the turning kinds, metres for prismatic
+    pub limits: Option<[f32; 2]>,
+    // spherical only: how far it may lean off its axis either way, `limits` and the motor then only turn it around the axis
+    #[serde(default)]
+    pub swing: Option<f32>,
+    pub motor: Option<JointMotor>,
+    // newtons and newton metres it holds before snapping, unbreakable when unset
+    pub break_force: Option<f32>,
+    pub break_torque: Option<f32>,
+    // whether the two bodies still collide with each other
+    pub contacts: bool,
+}
+
+impl JointDesc {
+    pub fn new(kind: JointKind) -> Self {
+        Self {
+            kind,
+            anchor1: Vec3::ZERO,
+            anchor2: Vec3::ZERO,
+            axis1: Vec3::X,
+            axis2: Vec3::X,
+            limits: None,
+            swing: None,
+            motor: None,
+            break_force: None,
+            break_torque: None,
+            contacts: false,
+        }
+    }
+
+    // the same joint placed at `anchor` turning around `axis`, both in world space
+    // ropes always hang from the two bodies' centres
+    pub fn attached(self, body1: &RigidBody, body2: &RigidBody, anchor: Vec3, axis: Vec3) -> Self {
+        let (pos1, pos2) = (body1.position(), body2.position());
+        let anchor = point![anchor.x, anchor.y, anchor.z];
+        let axis = vector![axis.x, axis.y, axis.z];
+        let to_vec3 = |v: Vector<Real>| vec3(v.x, v.y, v.z);
+
+        let (anchor1, anchor2) = match self.kind {
+            JointKind::Rope { .. } => (Vec3::ZERO, Vec3::ZERO),
+            _ => (to_vec3(pos1.inverse_transform_point(&anchor).coords), to_vec3(pos2.inverse_transform_point(&anchor).coords)),
+        };
+
+        Self {
+            anchor1,
+            anchor2,
+            axis1: to_vec3(pos1.inverse_transform_vector(&axis)),
+            axis2: to_vec3(pos2.inverse_transform_vector(&axis)),
+            ..self
+        }
+    }
+
+    pub fn build(&self) -> GenericJoint {
+        let axis = |v: Vec3| UnitVector::try_new(vector![v.x, v.y, v.z], 1.0e-6).unwrap_or(Vector::x_axis());
+
+        let mut joint = GenericJointBuilder::new(self.kind.locked_axes())
+            .local_anchor1(point![self.anchor1.x, self.anchor1.y, self.anchor1.z])
+            .local_anchor2(point![self.anchor2.x, self.anchor2.y, self.anchor2.z])
+            .local_axis1(axis(self.axis1))
+            .local_axis2(axis(self.axis2))
+            .contacts_enabled(self.contacts)
+            .build();
+
+        // same as rapier's own rope joint
+        if let JointKind::Rope { length } = self.kind {
+            joint.coupled_axes = JointAxesMask::LIN_AXES;
+            joint.set_limits(JointAxis::LinX, [0.0, length.max(0.0)]);
+        }
+
+        let swing = self.swing.filter(|_| self.kind == JointKind::Spherical);
+        for &free in self.kind.free_axes() {
+            if let (Some(swing), JointAxis::AngY | JointAxis::AngZ) = (swing, free) {
+                joint.set_limits(free, [-swing.abs(), swing.abs()]);
+                // straightens the lean out instead of driving it to the twist's target
+                if let Some(motor) = self.motor {
+                    joint.set_motor(free, 0.0, 0.0, motor.stiffness, motor.damping);
+                    joint.set_motor_max_force(free, motor.max_force);
+                }
+                continue;
+            }
+
+            if let Some(limits) = self.limits {
+                joint.set_limits(free, limits);
+            }
+            if let Some(motor) = self.motor {
+                joint.set_motor(free, motor.target_position, motor.target_velocity, motor.stiffness, motor.damping);
+                joint.set_motor_max_force(free, motor.max_force);
+            }
+        }
+
+        joint
+    }
+}
+
+impl PhysicalWorld {
+    pub fn add_joint(&mut self, body1: RigidBodyHandle, body2: RigidBodyHandle, desc: JointDesc) -> ImpulseJointHandle {
+        let handle = self.impulse_joint_set.insert(body1, body2, desc.build(), true);
+        self.joints.insert(handle, desc);
+
+        handle
+    }
+
+    pub fn remove_joint(&mut self, handle: ImpulseJointHandle) -> Option<JointDesc> {
+        self.impulse_joint_set.remove(handle, true);
+        self.joints.remove(&handle)
+    }
+
+    // drops whatever the bodies' removal took with it
+    pub fn forget_removed_joints(&mut self) {
+        let joint_set = &self.impulse_joint_set;
+        self.joints.retain(|handle, _| joint_set.contains(*handle));
+        // a link cut loose becomes the root of its own multibody, which has no joint
+        let multibody_set = &self.multibody_joint_set;
+        self.multibody_joints.retain(|handle, _| multibody_set.get(*handle).is_some_and(|(_, link)| link != 0));
+        let multibody_joints = &self.multibody_joints;
+        self.multibody_links.retain(|_, handle| multibody_joints.contains_key(handle));
+    }
+
+    // snaps every joint the last step pushed past its thresholds
+    pub fn break_joints(&mut self) {
+        // rapier keeps the impulses of the last substep, not of the whole step
+        let dt = self.integration_parameters.dt / self.integration_parameters.num_solver_iterations.get() as Real;
+        let mut broken: Vec<ImpulseJointHandle> = self.joints.iter()
+            .filter(|(handle, desc)| {
+                let Some(joint) = self.impulse_joint_set.get(**handle) else {
+                    return false;
+                };
+                let force = joint.impulses.fixed_rows::<3>(0).norm() / dt;
+                let torque = joint.impulses.fixed_rows::<3>(3).norm() / dt;
+
+                desc.break_force.is_some_and(|max| force > max) || desc.break_torque.is_some_and(|max| torque > max)
+            })
+            .map(|(handle, _)| *handle)
+            .collect();
+        // the order they're freed in decides the handles handed out next, which lockstep peers have to agree on
+        broken.sort_by_key(|handle| handle.into_raw_parts());
+
+        for handle in broken {
+            self.remove_joint(handle);
+            self.broken_joints += 1;
+        }
+    }
+
+    // body centre, anchor, anchor, body centre in world space, for drawing
+    pub fn joint_points(&self, handle: ImpulseJointHandle) -> Option<[Vec3; 4]> {
+        let joint = self.impulse_joint_set.get(handle)?;
+        let pos1 = self.rigid_body_set.get(joint.body1)?.position();
+        let pos2 = self.rigid_body_set.get(joint.body2)?.position();
+        let to_vec3 = |p: Point<Real>| vec3(p.x, p.y, p.z);
+
+        Some([
+            to_vec3(pos1.translation.vector.into()),
+            to_vec3(pos1 * joint.data.local_anchor1()),
+            to_vec3(pos2 * joint.data.local_anchor2()),
+            to_vec3(pos2.translation.vector.into()),
+        ])
+    }
+}
+
+impl World {
+    // `desc`'s anchors and axes are taken as they are, see `connect` for placing them in world space
+    pub async fn add_joint(&mut self, body1: PhysMeshHandle, body2: PhysMeshHandle, desc: JointDesc) -> Result<ImpulseJointHandle, PhysicsError> {
+        if body1 == body2 {
+            return Err(PhysicsError::SelfJoint(body1));
+        }
+        let (body1, body2) = (self.phys_mesh(body1)?.body, self.phys_mesh(body2)?.body);
+
+        Ok(self.phys_world.lock().await.add_joint(body1, body2, desc))
+    }
+
+    // joins the bodies where they are right now, at `anchor` around `axis`
+    pub async fn connect(&mut self, body1: PhysMeshHandle, body2: PhysMeshHandle, desc: JointDesc, anchor: Vec3, axis: Vec3) -> Result<ImpulseJointHandle, PhysicsError> {
+        if body1 == body2 {
+            return Err(PhysicsError::SelfJoint(body1));
+        }
+        let (body1, body2) = (self.phys_mesh(body1)?.body, self.phys_mesh(body2)?.body);
+
+        let mut phys_world = self.phys_world.lock().await;
+        let body = |handle| phys_world.rigid_body_set.get(handle).ok_or(PhysicsError::StaleHandle(handle));
+        let desc = desc.attached(body(body1)?, body(body2)?, anchor, axis);
+
+        Ok(phys_world.add_joint(body1, body2, desc))
+    }
+
+    // the bodies and their offsets stay, everything else comes from `desc`
+    pub async fn edit_joint(&mut self, handle: ImpulseJointHandle, desc: JointDesc) -> Result<(), PhysicsError> {
+        let mut phys_world = self.phys_world.lock().await;
+        let current = phys_world.joints.get_mut(&handle).ok_or(PhysicsError::StaleJoint(handle))?;
+        *current = JointDesc { anchor1: current.anchor1, anchor2: current.anchor2, axis1: current.axis1, axis2: current.axis2, ..desc };
+        let joint = current.build();
+
+        let impulse_joint = phys_world.impulse_joint_set.get_mut(handle).ok_or(PhysicsError::StaleJoint(handle))?;
+        impulse_joint.data = joint;
+        let (body1, body2) = (impulse_joint.body1, impulse_joint.body2);
+        // a motor switched on under a sleeping body would do nothing until something woke it
+        for body in [body1, body2] {
+            phys_world.body_mut(body)?.wake_up(true);
+        }
+
+        Ok(())
+    }
+
+    pub async fn remove_joint(&mut self, handle: ImpulseJointHandle) -> Result<JointDesc, PhysicsError> {
+        self.phys_world.lock().await.remove_joint(handle).ok_or(PhysicsError::StaleJoint(handle))
+    }
+
+    // the two bodies it holds together and how
+    pub async fn joint(&self, handle: ImpulseJointHandle) -> Result<(PhysMeshHandle, PhysMeshHandle, JointDesc), PhysicsError> {
+        let phys_world = self.phys_world.lock().await;
+        let desc = *phys_world.joints.get(&handle).ok_or(PhysicsError::StaleJoint(handle))?;
+        let joint = phys_world.impulse_joint_set.get(handle).ok_or(PhysicsError::StaleJoint(handle))?;
+        let mesh = |body| phys_world.mesh_of(body).ok_or(PhysicsError::StaleHandle(body));
+
+        Ok((mesh(joint.body1)?, mesh(joint.body2)?, desc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{physics_util::BodyShape, scene::SceneBody, sink::SceneRecorder};
+
+    // a 10kg block on a ball joint under a fixed hook, the joint carries ~98N once it's hanging still
+    async fn hanging_weight(desc: JointDesc) -> (World, PhysMeshHandle) {
+        let mut world = World::new().await;
+        let mut sink = SceneRecorder::new();
+        let hook = world.spawn(&mut sink, &SceneBody::new(BodyShape::Sphere { radius: 0.2 }, RigidBodyType::Fixed, vec3(0.0, 5.0, 0.0))).await.unwrap();
+        let block = SceneBody { mass: Some(10.0), ..SceneBody::new(BodyShape::Cuboid { half_extents: Vec3::splat(0.3) }, RigidBodyType::Dynamic, vec3(0.0, 3.0, 0.0)) };
+        let weight = world.spawn(&mut sink, &block).await.unwrap();
+        world.connect(hook, weight, desc, vec3(0.0, 5.0, 0.0), Vec3::X).await.unwrap();
+
+        (world, weight)
+    }
+
+    async fn height(world: &World, handle: PhysMeshHandle) -> f32 {
+        let body = world.phys_mesh(handle).unwrap().body;
+        world.phys_world.lock().await.rigid_body_set[body].translation().y
+    }
+
+    #[tokio::test]
+    async fn weak_joints_snap_under_a_weight() {
+        let (mut world, weight) = hanging_weight(JointDesc { break_force: Some(50.0), ..JointDesc::new(JointKind::Spherical) }).await;
+        for _ in 0..30 {
+            world.step().await;
+        }
+
+        let phys_world = world.phys_world.lock().await;
+        assert!(phys_world.joints.is_empty());
+        assert_eq!(phys_world.impulse_joint_set.len(), 0);
+        assert_eq!(phys_world.broken_joints, 1);
+        drop(phys_world);
+        // nothing holding it up any more
+        assert!(height(&world, weight).await < 2.5);
+    }
+
+    #[tokio::test]
+    async fn strong_joints_hold_it() {
+        // twice what it carries, a threshold that means anything has to survive that
+        let (mut world, weight) = hanging_weight(JointDesc { break_force: Some(200.0), ..JointDesc::new(JointKind::Spherical) }).await;
+        for _ in 0..120 {
+            world.step().await;
+        }
+
+        let phys_world = world.phys_world.lock().await;
+        assert_eq!(phys_world.joints.len(), 1);
+        assert_eq!(phys_world.broken_joints, 0);
+        drop(phys_world);
+        assert!((height(&world, weight).await - 3.0).abs() < 0.1);
+    }
+}
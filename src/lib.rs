@@ -0,0 +1,27 @@
+pub mod phys;
+pub mod physics_util;
+pub mod server;
+pub mod client;
+pub mod viewport;
+pub mod raycaster;
+pub mod utils;
+pub mod selection;
+pub mod rb_builder;
+pub mod headless;
+pub mod import;
+pub mod joint;
+pub mod articulation;
+pub mod contact;
+pub mod sink;
+pub mod scene;
+pub mod snapshot;
+pub mod protocol;
+pub mod replication;
+pub mod quantize;
+pub mod delta;
+pub mod prediction;
+pub mod lockstep;
+pub mod connection;
+pub mod reliable;
+pub mod netsim;
+pub mod terrain;
@@ -0,0 +1,622 @@
+use std::{collections::BTreeMap, fmt, io, net::SocketAddr, time::{Duration, Instant}};
+
+use chaos_framework::vec3;
+use rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, time::{self, MissedTickBehavior}};
+
+use crate::{
+    headless::{flag_value, flag_values, positive_flag_value, spawn_grid, ArgError},
+    phys::{PhysMeshHandle, PhysicalWorld, PhysicsCommand, World},
+    protocol::{decode, encode, NetError, MAX_PACKET_SIZE},
+    sink::SceneRecorder,
+    terrain::TerrainDesc,
+};
+
+// ticks between issuing a command and it being simulated, gives it time to reach every peer
+pub const DEFAULT_INPUT_DELAY: u64 = 4;
+// every packet repeats this many of our latest ticks, so a lost one is covered by the next
+pub const INPUT_REDUNDANCY: u64 = 8;
+// ticks of hashes we keep around to compare against late peers
+pub const HASH_HISTORY: u64 = 256;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LockstepMessage {
+    // our commands for each of the listed ticks (empty still counts) and our world hash after some of them
+    Inputs { peer: u32, inputs: Vec<(u64, Vec<PhysicsCommand>)>, hashes: Vec<(u64, u64)> },
+    // hashes disagreed at `tick`, send me every body's hash so I can find the culprit
+    BodyHashesRequest { peer: u32, tick: u64 },
+    BodyHashes { peer: u32, tick: u64, bodies: Vec<(RigidBodyHandle, u64)> },
+}
+
+/* fnv-1a, std's hasher isn't promised to stay the same between builds */
+pub struct Fnv(pub u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fnv {
+    pub fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write(&mut self, value: u32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    fn write_f32s(&mut self, values: &[f32]) {
+        for value in values {
+            self.write(value.to_bits());
+        }
+    }
+}
+
+pub struct WorldHash {
+    pub hash: u64,
+    // in handle order, only looked at once something went wrong
+    pub bodies: Vec<(RigidBodyHandle, u64)>,
+}
+
+impl WorldHash {
+    pub fn of(phys_world: &PhysicalWorld) -> Self {
+        let mut bodies: Vec<(RigidBodyHandle, u64)> = phys_world.rigid_body_set.iter()
+            .map(|(handle, body)| {
+                let (pos, rot) = (body.translation(), body.rotation());
+                let (linvel, angvel) = (body.linvel(), body.angvel());
+
+                let mut hasher = Fnv::new();
+                hasher.write_f32s(&[pos.x, pos.y, pos.z, rot.i, rot.j, rot.k, rot.w]);
+                hasher.write_f32s(&[linvel.x, linvel.y, linvel.z, angvel.x, angvel.y, angvel.z]);
+
+                (handle, hasher.0)
+            })
+            .collect();
+        bodies.sort_by_key(|(handle, _)| handle.into_raw_parts());
+
+        let mut hasher = Fnv::new();
+        for (handle, hash) in &bodies {
+            let (index, generation) = handle.into_raw_parts();
+            hasher.write(index);
+            hasher.write(generation);
+            hasher.write(*hash as u32);
+            hasher.write((*hash >> 32) as u32);
+        }
+
+        Self { hash: hasher.0, bodies }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DesyncReport {
+    pub tick: u64,
+    pub peer: u32,
+    // None until the peer sent us its per-body hashes
+    pub body: Option<RigidBodyHandle>,
+    pub mesh: Option<PhysMeshHandle>,
+}
+
+impl fmt::Display for DesyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "desync with peer {} at tick {}", self.peer, self.tick)?;
+        match (self.body, self.mesh) {
+            (Some(body), Some(mesh)) => write!(f, ", first divergent body {:?} (mesh {mesh})", body.into_raw_parts()),
+            (Some(body), None) => write!(f, ", first divergent body {:?}", body.into_raw_parts()),
+            (None, _) => write!(f, ", waiting for the peer's body hashes"),
+        }
+    }
+}
+
+/* every peer runs the same world and only trades inputs, a tick is simulated once all of them are in */
+pub struct Lockstep {
+    pub socket: UdpSocket,
+    // index is the peer id, every peer sorts the same list so they all agree on it
+    pub peers: Vec<SocketAddr>,
+    pub me: u32,
+    pub world: World,
+    pub tick: u64,
+    pub delay: u64,
+    // tick -> commands from each peer, None until that peer's arrive
+    inputs: BTreeMap<u64, Vec<Option<Vec<PhysicsCommand>>>>,
+    // local commands waiting for the next tick we seal
+    pending: Vec<PhysicsCommand>,
+    // we've decided our inputs for every tick below this
+    sealed: u64,
+    hashes: BTreeMap<u64, WorldHash>,
+    remote_hashes: BTreeMap<u64, Vec<(u32, u64)>>,
+    pub desync: Option<DesyncReport>,
+}
+
+impl Lockstep {
+    // `me` picks us out of `peers` as given, every peer has to list them in the same order then
+    // without it we find ourselves by the address we bound, and the list is sorted so the order doesn't matter
+    pub async fn new(bind: SocketAddr, mut peers: Vec<SocketAddr>, me: Option<u32>, world: World, delay: u64) -> Result<Self, NetError> {
+        let socket = UdpSocket::bind(bind).await?;
+        let bind = socket.local_addr()?;
+
+        let me = match me {
+            Some(me) if (me as usize) < peers.len() => me,
+            Some(me) => {
+                let message = format!("peer index {me} but only {} peers", peers.len());
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+            }
+            // 0.0.0.0 is never what the other peers have us down as
+            None if bind.ip().is_unspecified() => {
+                let message = format!("bound to {bind}, which peer this is needs a peer index");
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+            }
+            None => {
+                if !peers.contains(&bind) {
+                    peers.push(bind);
+                }
+                peers.sort();
+                peers.iter().position(|peer| *peer == bind).unwrap() as u32
+            }
+        };
+
+        Ok(Self {
+            socket,
+            peers,
+            me,
+            world,
+            tick: 0,
+            delay,
+            inputs: BTreeMap::new(),
+            pending: Vec::new(),
+            sealed: 0,
+            hashes: BTreeMap::new(),
+            remote_hashes: BTreeMap::new(),
+            desync: None,
+        })
+    }
+
+    // goes in `delay` ticks from now on every peer
+    pub fn queue(&mut self, command: PhysicsCommand) {
+        self.pending.push(command);
+    }
+
+    fn slot(&mut self, tick: u64) -> &mut Vec<Option<Vec<PhysicsCommand>>> {
+        let peers = self.peers.len();
+        self.inputs.entry(tick).or_insert_with(|| vec![None; peers])
+    }
+
+    // fixes our inputs for every tick up to `tick + delay` and tells everyone about the latest ones
+    pub async fn send_inputs(&mut self) -> Result<(), NetError> {
+        while self.sealed <= self.tick + self.delay {
+            let tick = self.sealed;
+            let commands = std::mem::take(&mut self.pending);
+            let me = self.me as usize;
+            self.slot(tick)[me] = Some(commands);
+            self.sealed += 1;
+        }
+
+        let first = self.sealed.saturating_sub(INPUT_REDUNDANCY);
+        let inputs = (first..self.sealed)
+            .filter_map(|tick| Some((tick, self.inputs.get(&tick)?[self.me as usize].clone()?)))
+            .collect();
+        let hashes = self.hashes.range(self.tick.saturating_sub(INPUT_REDUNDANCY)..)
+            .map(|(tick, hash)| (*tick, hash.hash))
+            .collect();
+
+        self.broadcast(&LockstepMessage::Inputs { peer: self.me, inputs, hashes }).await
+    }
+
+    async fn broadcast(&self, message: &LockstepMessage) -> Result<(), NetError> {
+        let bytes = encode(message)?;
+        for (i, peer) in self.peers.iter().enumerate() {
+            if i as u32 != self.me {
+                if let Err(err) = self.socket.send_to(&bytes, peer).await {
+                    eprintln!("could not send to {peer}: {err}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn handle_message(&mut self, message: LockstepMessage) -> Result<(), NetError> {
+        match message {
+            LockstepMessage::Inputs { peer, inputs, hashes } => {
+                if peer as usize >= self.peers.len() || peer == self.me {
+                    return Ok(());
+                }
+
+                // a peer can't run far ahead without our inputs, anything further out is bogus and would only pile up
+                let newest = self.tick + self.delay + INPUT_REDUNDANCY;
+                for (tick, commands) in inputs {
+                    // already simulated, that copy was only there in case an earlier packet got lost
+                    if tick < self.tick || tick > newest {
+                        continue;
+                    }
+                    let slot = &mut self.slot(tick)[peer as usize];
+                    if slot.is_none() {
+                        *slot = Some(commands);
+                    }
+                }
+
+                for (tick, hash) in hashes {
+                    self.check_hash(peer, tick, hash).await?;
+                }
+            }
+            LockstepMessage::BodyHashesRequest { peer, tick } => {
+                if let (Some(hash), Some(addr)) = (self.hashes.get(&tick), self.peers.get(peer as usize)) {
+                    let reply = LockstepMessage::BodyHashes { peer: self.me, tick, bodies: hash.bodies.clone() };
+                    self.socket.send_to(&encode(&reply)?, addr).await?;
+                }
+            }
+            LockstepMessage::BodyHashes { peer, tick, bodies } => {
+                let Some(report) = self.desync.as_mut().filter(|r| r.peer == peer && r.tick == tick && r.body.is_none()) else {
+                    return Ok(());
+                };
+                let Some(local) = self.hashes.get(&tick) else {
+                    return Ok(());
+                };
+
+                // walk both lists in handle order, the first mismatch or missing body is the one
+                let mut theirs = bodies.iter().peekable();
+                let mut divergent = None;
+                for (handle, hash) in &local.bodies {
+                    match theirs.peek() {
+                        Some((other, other_hash)) if other == handle => {
+                            if other_hash != hash {
+                                divergent = Some(*handle);
+                                break;
+                            }
+                            theirs.next();
+                        }
+                        _ => {
+                            divergent = Some(*handle);
+                            break;
+                        }
+                    }
+                }
+                let divergent = divergent.or_else(|| theirs.next().map(|(handle, _)| *handle));
+
+                report.body = divergent;
+                report.mesh = divergent.and_then(|body| self.world.get_phys_mesh_from_handle(body));
+                println!("{report}");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn check_hash(&mut self, peer: u32, tick: u64, hash: u64) -> Result<(), NetError> {
+        match self.hashes.get(&tick) {
+            Some(local) if local.hash != hash => self.report_desync(peer, tick).await,
+            Some(_) => Ok(()),
+            None => {
+                let remote = self.remote_hashes.entry(tick).or_default();
+                if !remote.iter().any(|(p, _)| *p == peer) {
+                    remote.push((peer, hash));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn report_desync(&mut self, peer: u32, tick: u64) -> Result<(), NetError> {
+        // only the first one matters, everything after it diverges anyway
+        if self.desync.as_ref().is_some_and(|report| report.tick <= tick) {
+            return Ok(());
+        }
+
+        let report = DesyncReport { tick, peer, body: None, mesh: None };
+        println!("{report}");
+        self.desync = Some(report);
+
+        if let Some(addr) = self.peers.get(peer as usize) {
+            let request = LockstepMessage::BodyHashesRequest { peer: self.me, tick };
+            self.socket.send_to(&encode(&request)?, addr).await?;
+        }
+
+        Ok(())
+    }
+
+    // simulates every tick we have all the inputs for, at most `max` of them
+    pub async fn advance(&mut self, max: u32) -> Result<u32, NetError> {
+        let mut stepped = 0;
+
+        while stepped < max {
+            let ready = self.inputs.get(&self.tick).is_some_and(|slot| slot.iter().all(Option::is_some));
+            if !ready {
+                break;
+            }
+
+            // same commands in the same order everywhere, peer by peer
+            let slot = self.inputs.remove(&self.tick).unwrap();
+            for commands in slot.into_iter().flatten() {
+                for command in commands {
+                    if let Err(err) = self.world.queue_command(command).await {
+                        eprintln!("could not queue command: {err}");
+                    }
+                }
+            }
+            self.world.step().await;
+
+            let hash = WorldHash::of(&*self.world.phys_world.lock().await);
+            let tick = self.tick;
+            let remote = self.remote_hashes.remove(&tick).unwrap_or_default();
+            let local = hash.hash;
+            self.hashes.insert(tick, hash);
+            for (peer, remote) in remote {
+                if remote != local {
+                    self.report_desync(peer, tick).await?;
+                }
+            }
+
+            self.tick += 1;
+            stepped += 1;
+        }
+
+        let oldest = self.tick.saturating_sub(HASH_HISTORY);
+        self.hashes = self.hashes.split_off(&oldest);
+        self.remote_hashes = self.remote_hashes.split_off(&oldest);
+
+        Ok(stepped)
+    }
+
+    // the peers we're still waiting on for the current tick
+    pub fn waiting_on(&self) -> Vec<u32> {
+        match self.inputs.get(&self.tick) {
+            Some(slot) => (0..slot.len() as u32).filter(|i| slot[*i as usize].is_none()).collect(),
+            None => (0..self.peers.len() as u32).collect(),
+        }
+    }
+}
+
+pub struct LockstepOptions {
+    pub bind: SocketAddr,
+    pub peers: Vec<SocketAddr>,
+    // which of `--peer` we are, `--peer-index 1`, needed when bound to 0.0.0.0
+    pub peer_index: Option<u32>,
+    pub hz: f32,
+    pub delay: u64,
+    pub bodies: u32,
+    pub ticks: u64,
+    pub report_every: u64,
+    // scripted input so there is something to agree on
+    pub kick_every: Option<u64>,
+    // nudge a body locally without telling anyone, to see the detector fire
+    pub desync_at: Option<u64>,
+    // every peer has to be given the same one
+    pub terrain: TerrainDesc,
+}
+
+impl LockstepOptions {
+    // returns None unless `--lockstep` was passed
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Option<Self>, ArgError> {
+        let args: Vec<String> = args.collect();
+        if !args.iter().any(|arg| arg == "--lockstep") {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            bind: flag_value(&args, "--bind")?.unwrap_or_else(|| "127.0.0.1:4050".parse().unwrap()),
+            peers: flag_values(&args, "--peer")?,
+            peer_index: flag_value(&args, "--peer-index")?,
+            hz: positive_flag_value(&args, "--hz")?.unwrap_or(60.0),
+            delay: flag_value(&args, "--delay")?.unwrap_or(DEFAULT_INPUT_DELAY),
+            bodies: flag_value(&args, "--bodies")?.unwrap_or(16),
+            ticks: flag_value(&args, "--ticks")?.unwrap_or(600),
+            report_every: flag_value(&args, "--report-every")?.unwrap_or(120),
+            kick_every: flag_value(&args, "--kick-every")?,
+            desync_at: flag_value(&args, "--desync-at")?,
+            terrain: TerrainDesc::from_args(&args)?,
+        }))
+    }
+}
+
+pub async fn run(opts: LockstepOptions) {
+    let mut world = World::new().await;
+    world.set_physics_rate(opts.hz);
+    let mut recorder = SceneRecorder::new();
+    if let Err(err) = world.set_terrain(&mut recorder, Some(opts.terrain.clone())).await {
+        eprintln!("{err}");
+        return;
+    }
+    spawn_grid(&mut world, &mut recorder, opts.bodies, None).await;
+
+    let mut lockstep = match Lockstep::new(opts.bind, opts.peers, opts.peer_index, world, opts.delay).await {
+        Ok(lockstep) => lockstep,
+        Err(err) => {
+            eprintln!("could not bind {}: {err}", opts.bind);
+            return;
+        }
+    };
+    println!("lockstep: peer {} of {}, input delay {} ticks", lockstep.me, lockstep.peers.len(), lockstep.delay);
+
+    let mut bodies: Vec<RigidBodyHandle> = lockstep.world.phys_meshes.values().map(|phys_mesh| phys_mesh.body).collect();
+    bodies.sort_by_key(|body| body.into_raw_parts());
+
+    let mut frame = time::interval(Duration::from_secs_f32(1.0 / opts.hz));
+    frame.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    let mut stalled_since = Instant::now();
+    let mut issued = 0;
+
+    while lockstep.tick < opts.ticks {
+        let result = tokio::select! {
+            received = lockstep.socket.recv_from(&mut buf) => match received {
+                Ok((len, addr)) => match decode(&buf[..len]) {
+                    Ok(message) => lockstep.handle_message(message).await,
+                    Err(err) => {
+                        eprintln!("dropping packet from {addr}: {err}");
+                        Ok(())
+                    }
+                },
+                Err(err) => {
+                    eprintln!("recv failed: {err}");
+                    Ok(())
+                }
+            },
+            _ = frame.tick() => {
+                if let Some(every) = opts.kick_every.filter(|every| *every > 0) {
+                    // every peer kicks a different body, on its own schedule
+                    if lockstep.sealed % every == 0 && lockstep.sealed / every > issued && !bodies.is_empty() {
+                        issued = lockstep.sealed / every;
+                        let body = bodies[(lockstep.me as usize + issued as usize) % bodies.len()];
+                        lockstep.queue(PhysicsCommand::Impulse(vec3(0.0, 4.0, 0.0), body));
+                    }
+                }
+
+                let sent = lockstep.send_inputs().await;
+                let before = lockstep.tick;
+                let stepped = lockstep.advance(4).await;
+
+                if opts.desync_at.is_some_and(|tick| before <= tick && tick < lockstep.tick) {
+                    if let Some(body) = bodies.first() {
+                        let mut phys_world = lockstep.world.phys_world.lock().await;
+                        phys_world.rigid_body_set[*body].apply_impulse(vector![0.0, 0.01, 0.0], true);
+                        println!("tick {:>6}: nudged body {:?} locally", lockstep.tick, body.into_raw_parts());
+                    }
+                }
+
+                if opts.report_every > 0 && before / opts.report_every != lockstep.tick / opts.report_every {
+                    // always the same tick on every peer, so the lines can be compared by eye
+                    let tick = lockstep.tick / opts.report_every * opts.report_every - 1;
+                    if let Some(hash) = lockstep.hashes.get(&tick) {
+                        println!("tick {:>6}: hash {:016x}", tick, hash.hash);
+                    }
+                }
+
+                if lockstep.tick > before {
+                    stalled_since = Instant::now();
+                } else if stalled_since.elapsed() > Duration::from_secs(2) {
+                    println!("tick {:>6}: waiting on peers {:?}", lockstep.tick, lockstep.waiting_on());
+                    stalled_since = Instant::now();
+                }
+
+                sent.and(stepped.map(|_| ()))
+            }
+        };
+
+        if let Err(err) = result {
+            eprintln!("lockstep error: {err}");
+        }
+    }
+
+    // keep answering for a moment so slower peers can finish their last ticks and compare hashes
+    let linger = Instant::now();
+    while linger.elapsed() < Duration::from_secs(1) {
+        if let Ok(Ok((len, _))) = time::timeout(Duration::from_millis(50), lockstep.socket.recv_from(&mut buf)).await {
+            if let Ok(message) = decode(&buf[..len]) {
+                let _ = lockstep.handle_message(message).await;
+            }
+        }
+        let _ = lockstep.send_inputs().await;
+    }
+
+    match &lockstep.desync {
+        Some(report) => println!("done at tick {}: {report}", lockstep.tick),
+        None => println!("done at tick {}: in sync with every peer", lockstep.tick),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two peers on loopback with the same grid, fresh ports every time
+    async fn pair() -> (Lockstep, Lockstep) {
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            addrs.push(UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap());
+        }
+        // in peer id order
+        addrs.sort();
+
+        let mut nodes = Vec::new();
+        for addr in &addrs {
+            let mut world = World::new().await;
+            let mut recorder = SceneRecorder::new();
+            world.set_terrain(&mut recorder, Some(TerrainDesc::flat(40.0, 40.0))).await.unwrap();
+            spawn_grid(&mut world, &mut recorder, 8, None).await;
+            nodes.push(Lockstep::new(*addr, addrs.clone(), None, world, DEFAULT_INPUT_DELAY).await.unwrap());
+        }
+        let b = nodes.pop().unwrap();
+        (nodes.pop().unwrap(), b)
+    }
+
+    // one frame on both: send, take in whatever arrived, step
+    async fn frame(mut nodes: [&mut Lockstep; 2]) {
+        for node in &mut nodes {
+            node.send_inputs().await.unwrap();
+        }
+        time::sleep(Duration::from_millis(2)).await;
+
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        for node in nodes {
+            while let Ok((len, _)) = node.socket.try_recv_from(&mut buf) {
+                node.handle_message(decode(&buf[..len]).unwrap()).await.unwrap();
+            }
+            node.advance(4).await.unwrap();
+        }
+    }
+
+    fn first_body(node: &Lockstep) -> RigidBodyHandle {
+        node.world.phys_meshes.values().map(|phys_mesh| phys_mesh.body).min_by_key(|body| body.into_raw_parts()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn peers_agree_until_one_is_nudged() {
+        let (mut a, mut b) = pair().await;
+        assert_eq!((a.me, b.me), (0, 1));
+
+        let body = first_body(&a);
+        a.queue(PhysicsCommand::Impulse(vec3(0.0, 4.0, 0.0), body));
+        while a.tick < 60 || b.tick < 60 {
+            frame([&mut a, &mut b]).await;
+        }
+        for tick in 0..60 {
+            assert_eq!(a.hashes[&tick].hash, b.hashes[&tick].hash, "tick {tick}");
+        }
+        assert!(a.desync.is_none() && b.desync.is_none());
+
+        // behind a's back
+        b.world.phys_world.lock().await.rigid_body_set[body].apply_impulse(vector![0.0, 0.01, 0.0], true);
+        let nudged = b.tick;
+        let start = Instant::now();
+        while a.desync.as_ref().is_none_or(|report| report.body.is_none()) && start.elapsed() < Duration::from_secs(5) {
+            frame([&mut a, &mut b]).await;
+        }
+
+        let report = a.desync.as_ref().expect("a never noticed");
+        assert_eq!(report.peer, 1);
+        assert!(report.tick >= nudged);
+        assert_eq!(report.body, Some(body));
+        assert!(report.mesh.is_some());
+    }
+
+    #[tokio::test]
+    async fn inputs_far_ahead_are_dropped() {
+        let (mut a, _b) = pair().await;
+        let newest = a.tick + a.delay + INPUT_REDUNDANCY;
+        let inputs = vec![(newest, Vec::new()), (newest + 1, Vec::new()), (u64::MAX, Vec::new())];
+        a.handle_message(LockstepMessage::Inputs { peer: 1, inputs, hashes: Vec::new() }).await.unwrap();
+
+        assert!(a.inputs[&newest][1].is_some());
+        assert!(!a.inputs.contains_key(&(newest + 1)));
+        assert!(!a.inputs.contains_key(&u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn a_wildcard_bind_needs_a_peer_index() {
+        let peers: Vec<SocketAddr> = vec!["127.0.0.1:4050".parse().unwrap(), "127.0.0.1:4051".parse().unwrap()];
+        assert!(Lockstep::new("0.0.0.0:0".parse().unwrap(), peers.clone(), None, World::new().await, 2).await.is_err());
+        assert!(Lockstep::new("0.0.0.0:0".parse().unwrap(), peers.clone(), Some(2), World::new().await, 2).await.is_err());
+
+        let node = Lockstep::new("0.0.0.0:0".parse().unwrap(), peers.clone(), Some(1), World::new().await, 2).await.unwrap();
+        assert_eq!(node.me, 1);
+        assert_eq!(node.peers, peers);
+    }
+}
@@ -1,30 +1,46 @@
-mod phys;
-mod physics_util;
-mod server;
-mod client;
-mod globals;
-mod viewport;
-mod raycaster;
-mod utils;
-mod selection;
-mod rb_builder;
-
-use std::{num::NonZero, sync::{Arc, Mutex}};
+use std::collections::HashMap;
 
 use chaos_framework::*;
-use client::Client;
 use glfw::Key;
-use globals::modify_rb_overhaul_size;
-use phys::{PhysMeshHandle, World};
-use rapier3d::{prelude::*, rayon::iter::{IntoParallelIterator, ParallelIterator}};
-use raycaster::Raycaster;
-use rb_builder::RbBuilder;
-use server::Server;
-use tokio::task;
-use viewport::{AppViewport, ViewportCtx};
+use physp::{
+    client::{self, Client, ClientOptions},
+    headless::{self, flag_value, ArgError, HeadlessOptions},
+    lockstep::{self, LockstepOptions},
+    netsim::NetConditions,
+    phys::{PhysMeshHandle, World},
+    raycaster::Raycaster,
+    rb_builder::RbBuilder,
+    server::{self, ServerOptions},
+    sink::{RenderSink, SceneSink},
+    terrain::TerrainDesc,
+    viewport::{AppViewport, ViewportCtx},
+};
+
+const SCENE_PATH: &str = "scene.ron";
+const USAGE: &str = "usage: physp [--server | --connect <addr> | --lockstep | --headless] [options], or no mode for the editor";
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() {
+    if let Some(opts) = or_usage(ServerOptions::from_args(std::env::args())) {
+        server::serve(opts).await;
+        return;
+    }
+
+    if let Some(opts) = or_usage(ClientOptions::from_args(std::env::args())) {
+        client::probe(opts).await;
+        return;
+    }
+
+    if let Some(opts) = or_usage(LockstepOptions::from_args(std::env::args())) {
+        lockstep::run(opts).await;
+        return;
+    }
+
+    if let Some(opts) = or_usage(HeadlessOptions::from_args(std::env::args())) {
+        headless::run(opts).await;
+        return;
+    }
+
     let mut el = EventLoop::new(1200, 900);
     let mut renderer = Renderer::new();
 
@@ -35,45 +51,37 @@ async fn main() {
         Enable(CULL_FACE);
     }
 
-    let mut floor = Quad::new(vec3(250.0, 250.0, 250.0), Vec4::ONE).mesh();
-    floor.rotation = Quat::from_euler(EulerRot::XYZ, -3.1415 * 0.5, 0.0, 0.0);
-    floor.position = vec3(-125.0, 0.0, 125.0);
-    floor.color = vec3(0.6, 0.6, 0.9);
-    renderer.add_mesh(floor).unwrap();
-
     renderer.add_light(Light { position: Vec3::ONE, color: Vec3::ONE });
 
     el.window.glfw.set_swap_interval(SwapInterval::Sync(1));
 
     let mut world = World::new().await;
-    
-    let mut phys_world = world.phys_world.lock().await;
-    phys_world.integration_parameters.num_solver_iterations = NonZero::new(16).unwrap();
-    phys_world.integration_parameters.num_internal_stabilization_iterations = 12;
-    drop(phys_world);
-
-    world.add_floor(vec3(125.0, 0.2, 125.0));
-
-    let addr = "127.0.0.1:4040";
-    let mut server = Server::new(addr).await.unwrap();
-
-    let mut client = Client::new().await.unwrap();
-
-    task::spawn(async move {
-        server.run().await.expect("server failed to run");
-    });
-    
-    client.send_message(addr, "hi!").await.unwrap();
-
     let mut ctx = ViewportCtx::new(&mut renderer);
 
-    let mut current_handle = None;
+    let args: Vec<String> = std::env::args().collect();
+    ctx.terrain = or_usage(TerrainDesc::from_args(&args));
+    let terrain = ctx.terrain.clone();
+    if let Err(err) = world.set_terrain(&mut ctx.sink(&mut renderer), Some(terrain)).await {
+        eprintln!("{err}, starting without ground");
+    }
+
+    // `--remote <addr>` mirrors a server's world on top of the local one
+    let mut remote = match or_usage(flag_value::<String>(&args, "--remote")) {
+        Some(addr) => join_remote(&addr, or_usage(flag_value(&args, "--delay")), or_usage(NetConditions::from_args(&args))).await,
+        None => None,
+    };
+    let mut remote_meshes = HashMap::new();
+    let mut remote_selected = None;
 
     while !el.window.should_close() {
         el.update();
         renderer.update();
         let now = std::time::Instant::now();
-        world.update(&mut renderer, el.dt).await;
+        world.update(&mut ctx.sink(&mut renderer), el.dt).await;
+        if let Some(client) = &mut remote {
+            client.update(&mut RenderSink::new(&mut renderer, &mut remote_meshes), el.dt);
+        }
+        world.record_history();
         ctx.phys_time = now.elapsed().as_secs_f32();
         
         renderer.camera.input(&el);
@@ -92,35 +100,92 @@ async fn main() {
         }
 
         if el.event_handler.key_just_pressed(Key::Q) {
-            current_handle = Raycaster::get_body_from_mouse(&el, &renderer, &mut world, &ctx).await;
+            if let Some(handle) = Raycaster::get_body_from_mouse(&el, &renderer, &mut world, &ctx).await {
+                ctx.current_body_handle = Some(handle);
+            }
         }
         
+        // remote bodies are picked in the prediction world, impulses go through the server
+        if let Some(client) = &mut remote {
+            if el.event_handler.key_just_pressed(Key::Q) {
+                let (origin, dir) = Raycaster::ray_from_mouse(&el, &renderer, &ctx);
+                remote_selected = client.prediction.world.body_raycast(origin, dir)
+                    .and_then(|body| client.prediction.world.mesh_of(body));
+            }
+
+            if let Some(handle) = remote_selected {
+                if ctx.edit_mode && el.event_handler.lmb {
+                    if let Some((pos, _)) = client.prediction.pose(handle) {
+                        client.impulse(handle, renderer.camera.pos - pos);
+                    }
+                }
+            }
+        }
+
         let handles: Vec<PhysMeshHandle> = world.phys_meshes.iter().map(|v| *v.0).collect();
         if el.event_handler.key_just_pressed(Key::R) {
-            current_handle = None;
-            ctx.current_body_handle = None;
             for handle in handles {
-                world.destroy(&mut renderer, handle).await;
+                if let Err(err) = ctx.destroy(&mut world, &mut renderer, handle).await {
+                    eprintln!("could not destroy body: {err}");
+                }
             }
         }
 
         if el.event_handler.key_just_pressed(Key::F) {
-            gen_spheres(&mut world, &mut renderer).await;
+            gen_spheres(&mut world, &mut ctx.sink(&mut renderer)).await;
         }
 
         if el.event_handler.key_just_pressed(Key::J) {
             let _ = world.phys_world.lock().await; // force sync
         }
 
+        if el.event_handler.key_just_pressed(Key::F5) {
+            match world.save_scene(SCENE_PATH).await {
+                Ok(()) => println!("saved scene to {}", SCENE_PATH),
+                Err(err) => eprintln!("could not save scene: {err}"),
+            }
+        }
+
+        if el.event_handler.key_just_pressed(Key::F9) {
+            ctx.clear_selection(&mut renderer);
+            match world.load_scene(&mut ctx.sink(&mut renderer), SCENE_PATH).await {
+                Ok(()) => println!("loaded scene from {}", SCENE_PATH),
+                Err(err) => eprintln!("could not load scene: {err}"),
+            }
+        }
+
+        if el.event_handler.key_just_pressed(Key::F6) {
+            match world.bookmark().await {
+                Ok(()) => println!("bookmarked the world"),
+                Err(err) => eprintln!("could not bookmark: {err}"),
+            }
+        }
+
+        if el.event_handler.key_just_pressed(Key::F7) {
+            ctx.clear_selection(&mut renderer);
+            match world.restore_bookmark(&mut ctx.sink(&mut renderer)).await {
+                Ok(tick) => println!("back to the bookmark at tick {tick}"),
+                Err(err) => eprintln!("could not restore bookmark: {err}"),
+            }
+        }
+
+        if el.event_handler.key_just_pressed(Key::Z) {
+            ctx.clear_selection(&mut renderer);
+            match world.rewind(&mut ctx.sink(&mut renderer)).await {
+                Ok(tick) => println!("rewound to tick {tick}"),
+                Err(err) => eprintln!("could not rewind: {err}"),
+            }
+        }
+
         if el.event_handler.key_just_pressed(Key::L) {
             renderer.add_light(Light { position: renderer.camera.pos, color: Vec3::ONE });
         }
 
         if (el.time * 1000.0) as i32 % 8 == 0 && ctx.edit_mode {
-            ctx.update(&mut world, &mut renderer, &el, current_handle).await;
+            ctx.update(&mut world, &mut renderer, &el).await;
         }
 
-        RbBuilder::update(&mut world, &mut renderer, &el, &ctx).await;
+        RbBuilder::update(&mut world, &mut renderer, &el, &mut ctx).await;
 
         unsafe {
             Clear(COLOR_BUFFER_BIT | DEPTH_BUFFER_BIT);
@@ -135,9 +200,32 @@ async fn main() {
     }
 }
 
-pub async fn gen_spheres(world: &mut World, renderer: &mut Renderer) {
+// a bad flag ends the program with a short message, not a panic
+fn or_usage<T>(result: Result<T, ArgError>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{err}\n{USAGE}");
+        std::process::exit(2);
+    })
+}
+
+async fn join_remote(addr: &str, delay: Option<f32>, conditions: Option<NetConditions>) -> Option<Client> {
+    let mut client = match Client::connect(addr, conditions).await {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("could not connect to {addr}: {err}");
+            return None;
+        }
+    };
+    if let Some(delay) = delay {
+        client.replica.delay = delay;
+    }
+
+    Some(client)
+}
+
+pub async fn gen_spheres(_world: &mut World, _sink: &mut dyn SceneSink) {
     // for i in 0..128 {
-    //     let cube = world.add_cube(renderer).await;
+    //     let cube = world.add_cube(sink).await;
     //     let handle = world.phys_meshes[cube].body;
     //     world.phys_world.lock().await.rigid_body_set.get_mut(handle).unwrap()
     //         .set_translation(vector![rand_betw(-2.0, 2.0), rand_betw(0.0, 4.0), rand_betw(-2.0, 2.0)], false);
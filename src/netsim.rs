@@ -0,0 +1,348 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::mpsc::{self, error::TryRecvError, UnboundedReceiver, UnboundedSender},
+    time::{self, Instant},
+};
+
+use crate::{headless::{flag_value, ArgError}, protocol::MAX_PACKET_SIZE};
+
+// a link never queues more than this, past it packets are tail dropped like on a real router
+pub const MAX_QUEUE_DELAY: Duration = Duration::from_secs(1);
+// how much later than its neighbours a reordered packet shows up
+pub const REORDER_DELAY: Duration = Duration::from_millis(30);
+
+/* what a bad network does to every datagram, in each direction on its own */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NetConditions {
+    pub latency: Duration,
+    // uniform in [-jitter, jitter] on top of the latency
+    pub jitter: Duration,
+    // chances from 0 to 1
+    pub loss: f32,
+    pub duplicate: f32,
+    pub reorder: f32,
+    // bytes per second, None for unlimited
+    pub bandwidth: Option<u32>,
+    pub seed: u64,
+}
+
+impl Default for NetConditions {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            bandwidth: None,
+            seed: 1,
+        }
+    }
+}
+
+impl NetConditions {
+    // None unless at least one of `--latency`, `--jitter` (ms), `--loss`, `--duplicate`, `--reorder` (%)
+    // or `--bandwidth` (bytes/s) was passed
+    pub fn from_args(args: &[String]) -> Result<Option<Self>, ArgError> {
+        let latency: Option<f32> = flag_value(args, "--latency")?;
+        let jitter: Option<f32> = flag_value(args, "--jitter")?;
+        let loss: Option<f32> = flag_value(args, "--loss")?;
+        let duplicate: Option<f32> = flag_value(args, "--duplicate")?;
+        let reorder: Option<f32> = flag_value(args, "--reorder")?;
+        let bandwidth: Option<u32> = flag_value(args, "--bandwidth")?;
+
+        if latency.is_none() && jitter.is_none() && loss.is_none() && duplicate.is_none() && reorder.is_none() && bandwidth.is_none() {
+            return Ok(None);
+        }
+
+        let millis = |ms: Option<f32>| Duration::from_secs_f32(ms.unwrap_or(0.0).max(0.0) / 1000.0);
+        let percent = |p: Option<f32>| (p.unwrap_or(0.0) / 100.0).clamp(0.0, 1.0);
+
+        Ok(Some(Self {
+            latency: millis(latency),
+            jitter: millis(jitter),
+            loss: percent(loss),
+            duplicate: percent(duplicate),
+            reorder: percent(reorder),
+            bandwidth,
+            seed: flag_value(args, "--sim-seed")?.unwrap_or(1),
+        }))
+    }
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SimStats {
+    pub sent: u64,
+    pub received: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    // didn't fit in the bandwidth cap's queue
+    pub overflowed: u64,
+}
+
+/* splitmix64, seeded so a bad run can be replayed */
+struct SimRng(u64);
+
+impl SimRng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // in [0, 1)
+    fn chance(&mut self) -> f32 {
+        (self.next() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Direction {
+    // None for the connected peer
+    Out(Option<SocketAddr>),
+    In(SocketAddr),
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Delayed {
+    release: Instant,
+    // keeps packets released at the same instant in the order they came in
+    order: u64,
+    direction: Direction,
+    bytes: Vec<u8>,
+}
+
+/* one per direction, remembers when the link frees up for the bandwidth cap */
+struct Link {
+    free_at: Instant,
+}
+
+struct Simulator {
+    conditions: NetConditions,
+    rng: SimRng,
+    queue: BinaryHeap<Reverse<Delayed>>,
+    order: u64,
+    links: [Link; 2],
+    stats: Arc<Mutex<SimStats>>,
+}
+
+impl Simulator {
+    // decides the fate of one datagram, queued zero, one or two times
+    fn admit(&mut self, direction: Direction, bytes: Vec<u8>) {
+        let now = Instant::now();
+        let mut stats = self.stats.lock().unwrap();
+        match direction {
+            Direction::Out(_) => stats.sent += 1,
+            Direction::In(_) => stats.received += 1,
+        }
+
+        if self.rng.chance() < self.conditions.loss {
+            stats.dropped += 1;
+            return;
+        }
+
+        // serialization on a capped link, everything behind a big packet waits for it
+        let link = &mut self.links[matches!(direction, Direction::In(_)) as usize];
+        let departs = match self.conditions.bandwidth {
+            Some(bandwidth) => {
+                let start = link.free_at.max(now);
+                if start - now > MAX_QUEUE_DELAY {
+                    stats.overflowed += 1;
+                    return;
+                }
+                link.free_at = start + Duration::from_secs_f64(bytes.len() as f64 / bandwidth.max(1) as f64);
+                link.free_at
+            }
+            None => now,
+        };
+
+        let copies = if self.rng.chance() < self.conditions.duplicate {
+            stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let jitter = self.conditions.jitter.as_secs_f32() * (self.rng.chance() * 2.0 - 1.0);
+            let mut delay = Duration::from_secs_f32((self.conditions.latency.as_secs_f32() + jitter).max(0.0));
+            if self.rng.chance() < self.conditions.reorder {
+                stats.reordered += 1;
+                delay += REORDER_DELAY;
+            }
+
+            self.order += 1;
+            self.queue.push(Reverse(Delayed { release: departs + delay, order: self.order, direction, bytes: bytes.clone() }));
+        }
+    }
+}
+
+enum Outbound {
+    To(Vec<u8>, SocketAddr),
+    Connected(Vec<u8>),
+}
+
+struct Simulated {
+    outbound: UnboundedSender<Outbound>,
+    inbound: UnboundedReceiver<io::Result<(Vec<u8>, SocketAddr)>>,
+    stats: Arc<Mutex<SimStats>>,
+}
+
+/* a udp socket, optionally behind a simulated bad network, for both `Server` and `Client` */
+pub struct NetSocket {
+    socket: Arc<UdpSocket>,
+    sim: Option<Simulated>,
+}
+
+impl NetSocket {
+    pub async fn bind(addr: impl ToSocketAddrs, conditions: Option<NetConditions>) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let sim = conditions.map(|conditions| simulate(socket.clone(), conditions));
+
+        Ok(Self { socket, sim })
+    }
+
+    pub async fn connect(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        self.socket.connect(addr).await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn stats(&self) -> Option<SimStats> {
+        self.sim.as_ref().map(|sim| *sim.stats.lock().unwrap())
+    }
+
+    pub async fn send_to(&self, bytes: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match &self.sim {
+            Some(sim) => sim.send(Outbound::To(bytes.to_vec(), addr), bytes.len()),
+            None => self.socket.send_to(bytes, addr).await,
+        }
+    }
+
+    pub async fn send(&self, bytes: &[u8]) -> io::Result<usize> {
+        match &self.sim {
+            Some(sim) => sim.send(Outbound::Connected(bytes.to_vec()), bytes.len()),
+            None => self.socket.send(bytes).await,
+        }
+    }
+
+    pub fn try_send(&self, bytes: &[u8]) -> io::Result<usize> {
+        match &self.sim {
+            Some(sim) => sim.send(Outbound::Connected(bytes.to_vec()), bytes.len()),
+            None => self.socket.try_send(bytes),
+        }
+    }
+
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match &mut self.sim {
+            Some(sim) => match sim.inbound.recv().await {
+                Some(received) => received.map(|(bytes, addr)| (copy_into(buf, &bytes), addr)),
+                None => Err(io::ErrorKind::BrokenPipe.into()),
+            },
+            None => self.socket.recv_from(buf).await,
+        }
+    }
+
+    pub fn try_recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.sim {
+            Some(sim) => match sim.inbound.try_recv() {
+                Ok(received) => received.map(|(bytes, _)| copy_into(buf, &bytes)),
+                Err(TryRecvError::Empty) => Err(io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => Err(io::ErrorKind::BrokenPipe.into()),
+            },
+            None => self.socket.try_recv(buf),
+        }
+    }
+}
+
+// like the real socket, whatever doesn't fit is cut off
+fn copy_into(buf: &mut [u8], bytes: &[u8]) -> usize {
+    let len = bytes.len().min(buf.len());
+    buf[..len].copy_from_slice(&bytes[..len]);
+    len
+}
+
+impl Simulated {
+    fn send(&self, outbound: Outbound, len: usize) -> io::Result<usize> {
+        self.outbound.send(outbound).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(len)
+    }
+}
+
+// everything goes through one task that owns the delay queue, sending and receiving both
+fn simulate(socket: Arc<UdpSocket>, conditions: NetConditions) -> Simulated {
+    let (outbound, mut outbound_rx) = mpsc::unbounded_channel();
+    let (inbound_tx, inbound) = mpsc::unbounded_channel();
+    let stats = Arc::new(Mutex::new(SimStats::default()));
+
+    let now = Instant::now();
+    let mut sim = Simulator {
+        conditions,
+        rng: SimRng(conditions.seed),
+        queue: BinaryHeap::new(),
+        order: 0,
+        links: [Link { free_at: now }, Link { free_at: now }],
+        stats: stats.clone(),
+    };
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+
+        loop {
+            let next = sim.queue.peek().map(|Reverse(delayed)| delayed.release);
+
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok((len, addr)) => sim.admit(Direction::In(addr), buf[..len].to_vec()),
+                    // errors aren't packets, nothing to delay
+                    Err(err) => {
+                        if inbound_tx.send(Err(err)).is_err() {
+                            return;
+                        }
+                    }
+                },
+                outgoing = outbound_rx.recv() => match outgoing {
+                    Some(Outbound::To(bytes, addr)) => sim.admit(Direction::Out(Some(addr)), bytes),
+                    Some(Outbound::Connected(bytes)) => sim.admit(Direction::Out(None), bytes),
+                    // the socket was dropped
+                    None => return,
+                },
+                _ = time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {}
+            }
+
+            let now = Instant::now();
+            while sim.queue.peek().is_some_and(|Reverse(delayed)| delayed.release <= now) {
+                let Reverse(delayed) = sim.queue.pop().unwrap();
+                let sent = match delayed.direction {
+                    Direction::Out(Some(addr)) => socket.send_to(&delayed.bytes, addr).await.map(|_| ()),
+                    Direction::Out(None) => socket.send(&delayed.bytes).await.map(|_| ()),
+                    Direction::In(addr) => {
+                        if inbound_tx.send(Ok((delayed.bytes, addr))).is_err() {
+                            return;
+                        }
+                        Ok(())
+                    }
+                };
+                if let Err(err) = sent {
+                    eprintln!("simulated send failed: {err}");
+                }
+            }
+        }
+    });
+
+    Simulated { outbound, inbound, stats }
+}
@@ -1,10 +1,41 @@
-use std::{collections::HashMap, ops::{Index, IndexMut}, sync::{mpsc::channel, Arc}};
+use std::{collections::HashMap, fmt, num::NonZero, sync::Arc};
 
-use chaos_framework::{Renderer, Vec3};
+use chaos_framework::Vec3;
 use rapier3d::prelude::*;
-use tokio::sync::{mpsc::{self, error::TryRecvError, Receiver, Sender}, Mutex};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc::{self, error::TrySendError, Sender}, watch, Mutex};
 
-use crate::{globals::read_rb_overhaul_size, physics_util::PhysMesh};
+use crate::{articulation::{BodyGroup, GroupHandle}, contact::{ContactEvent, ContactStream}, import::{AssetStore, MeshAsset}, joint::JointDesc, physics_util::PhysMesh, sink::SceneSink, snapshot::{SnapshotRing, WorldSnapshot}, terrain::Terrain};
+
+pub const COMMAND_QUEUE_SIZE: usize = 1024;
+// rewind buffer: a snapshot every 15 ticks, 40 of them is ~10s at 60hz
+pub const HISTORY_INTERVAL: u64 = 15;
+pub const HISTORY_LENGTH: usize = 40;
+// physics rates are clamped into this, a zero or nan rate would make every step infinitely long
+pub const DEFAULT_PHYSICS_RATE: f32 = 60.0;
+pub const MIN_PHYSICS_RATE: f32 = 1.0;
+pub const MAX_PHYSICS_RATE: f32 = 1000.0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SimulationConfig {
+    pub gravity: Vec3,
+    pub solver_iterations: u32,
+    pub stabilization_iterations: u32,
+    pub ccd: bool,
+    pub time_scale: f32,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            gravity: Vec3::new(0.0, -9.81, 0.0),
+            solver_iterations: 16,
+            stabilization_iterations: 12,
+            ccd: false,
+            time_scale: 1.0,
+        }
+    }
+}
 
 /* TODO: add the physics meshes here to grant access to meshes */
 pub struct PhysicalWorld {
@@ -20,7 +51,27 @@ pub struct PhysicalWorld {
     pub ccd_solver: CCDSolver,
     pub query_pipeline: QueryPipeline,
     pub physics_hooks: (),
-    pub event_handler: (),
+    // fills `contacts` during a step
+    pub event_handler: ChannelEventCollector,
+    pub contacts: ContactStream,
+    pub tick: u64,
+    pub config: SimulationConfig,
+    // how every joint in `impulse_joint_set` was made, breaking thresholds included
+    pub joints: HashMap<ImpulseJointHandle, JointDesc>,
+    // the same for `multibody_joint_set`
+    pub multibody_joints: HashMap<MultibodyJointHandle, JointDesc>,
+    // the joint each articulated body hangs off, as rapier handed it out
+    pub multibody_links: HashMap<RigidBodyHandle, MultibodyJointHandle>,
+    // running total, for the status report
+    pub broken_joints: u64,
+    // where every moving body was before the last step, rendering interpolates from there to where it is now
+    pub previous_poses: HashMap<RigidBodyHandle, Isometry<Real>>,
+}
+
+impl Default for PhysicalWorld {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PhysicalWorld {
@@ -38,9 +89,9 @@ impl PhysicalWorld {
         let ccd_solver = CCDSolver::new();
         let query_pipeline = QueryPipeline::new();
         let physics_hooks = ();
-        let event_handler = ();
+        let (contacts, event_handler) = ContactStream::new();
 
-        Self {
+        let mut phys_world = Self {
             rigid_body_set,
             collider_set,
             integration_parameters,
@@ -54,14 +105,53 @@ impl PhysicalWorld {
             query_pipeline,
             physics_hooks,
             event_handler,
+            contacts,
+            tick: 0,
+            config: SimulationConfig::default(),
+            joints: HashMap::new(),
+            multibody_joints: HashMap::new(),
+            multibody_links: HashMap::new(),
+            broken_joints: 0,
+            previous_poses: HashMap::new(),
+        };
+        phys_world.set_config(SimulationConfig::default());
+
+        phys_world
+    }
+
+    pub fn set_config(&mut self, config: SimulationConfig) {
+        self.integration_parameters.num_solver_iterations = NonZero::new(config.solver_iterations.max(1) as usize).unwrap();
+        self.integration_parameters.num_internal_stabilization_iterations = config.stabilization_iterations as usize;
+
+        if config.ccd != self.config.ccd {
+            for (_, body) in self.rigid_body_set.iter_mut() {
+                if body.is_dynamic() {
+                    body.enable_ccd(config.ccd);
+                }
+            }
         }
+
+        self.config = config;
     }
 
     pub fn step(&mut self, dt: f32) {
+        // still counts as a tick when paused so the fixed timestep stays in lockstep
+        self.tick += 1;
+        // sleeping and fixed bodies aren't about to move, a missing entry means it stayed where it is
+        self.previous_poses.clear();
+        self.previous_poses.extend(self.rigid_body_set.iter()
+            .filter(|(_, body)| !body.is_sleeping() && !body.is_fixed())
+            .map(|(handle, body)| (handle, *body.position())));
+
+        let dt = dt * self.config.time_scale;
+        if dt <= 0.0 {
+            return;
+        }
         self.integration_parameters.dt = dt;
 
+        let gravity = self.config.gravity;
         self.physics_pipeline.step(
-            &vector![0.0, -9.81, 0.0], // gravity
+            &vector![gravity.x, gravity.y, gravity.z],
             &self.integration_parameters,
             &mut self.island_manager,
             &mut self.broad_phase,
@@ -75,108 +165,504 @@ impl PhysicalWorld {
             &self.physics_hooks,
             &self.event_handler,
         );
+        self.break_joints();
+        self.contacts.publish(self.tick, &self.rigid_body_set, &self.collider_set);
+    }
+
+    pub fn apply_command(&mut self, command: PhysicsCommand) -> Result<(), PhysicsError> {
+        match command {
+            PhysicsCommand::Impulse(v, rigid_body_handle) => {
+                let body = self.body_mut(rigid_body_handle)?;
+                body.apply_impulse(vector![v.x, v.y, v.z], true);
+            }
+            PhysicsCommand::SetType(rigid_body_type, rigid_body_handle) => {
+                let body = self.body_mut(rigid_body_handle)?;
+                body.set_body_type(rigid_body_type, false);
+            }
+            PhysicsCommand::Translate(v, rigid_body_handle) => {
+                let body = self.body_mut(rigid_body_handle)?;
+                // wake it up, a teleported body that stays asleep would never be replicated
+                body.set_position(vector![v.x, v.y, v.z].into(), true);
+            }
+            PhysicsCommand::SetConfig(config) => self.set_config(config),
+        }
+
+        Ok(())
+    }
+
+    pub fn body_mut(&mut self, handle: RigidBodyHandle) -> Result<&mut RigidBody, PhysicsError> {
+        self.rigid_body_set.get_mut(handle).ok_or(PhysicsError::StaleHandle(handle))
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PhysicsCommand {
     Impulse(Vec3, RigidBodyHandle),
     SetType(RigidBodyType, RigidBodyHandle),
     Translate(Vec3, RigidBodyHandle),
+    SetConfig(SimulationConfig),
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PhysicsError {
+    StaleHandle(RigidBodyHandle),
+    StaleMesh(PhysMeshHandle),
+    StaleJoint(ImpulseJointHandle),
+    SelfJoint(PhysMeshHandle),
+    Articulated(PhysMeshHandle),
+    StaleGroup(GroupHandle),
+    // an imported shape whose file was never loaded on this side
+    UnknownAsset(MeshAsset),
+    QueueFull,
+    Disconnected,
+}
+
+impl std::fmt::Display for PhysicsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhysicsError::StaleHandle(handle) => write!(f, "no rigid body for handle {:?}", handle.into_raw_parts()),
+            PhysicsError::StaleMesh(handle) => write!(f, "no body {handle}, it was destroyed or never existed"),
+            PhysicsError::StaleJoint(handle) => write!(f, "no joint for handle {:?}", handle.into_raw_parts()),
+            PhysicsError::SelfJoint(handle) => write!(f, "body {handle} can't be jointed to itself"),
+            PhysicsError::Articulated(handle) => write!(f, "body {handle} already hangs off another body or would close a loop"),
+            PhysicsError::StaleGroup(handle) => write!(f, "no group {handle}, it was destroyed or never existed"),
+            PhysicsError::UnknownAsset(asset) => write!(f, "mesh {asset} was never loaded here"),
+            PhysicsError::QueueFull => write!(f, "physics command queue is full"),
+            PhysicsError::Disconnected => write!(f, "physics task is gone"),
+        }
+    }
+}
+
+impl std::error::Error for PhysicsError {}
+
+#[derive(Copy, Clone, Default)]
 pub struct PhyisicsStatus {
     pub solve_time: f32,
+
+    // running totals since the world was created, a reader that skips reports still sees every command counted
+    pub applied_commands: u64,
+    pub failed_commands: u64,
+    // the newest failure so far, kept until another one replaces it
+    pub last_error: Option<PhysicsError>,
+    pub simulated_steps: u64,
+    pub skipped_steps: u64,
+    pub broken_joints: u64,
+}
+
+// one batch of work for the physics task, `skipped` are steps the accumulator threw away
+#[derive(Copy, Clone)]
+pub struct StepRequest {
+    pub steps: u32,
+    pub skipped: u32,
+    pub dt: f32,
+}
+
+#[derive(Copy, Clone)]
+pub struct FixedTimestep {
+    pub hz: f32,
+    pub max_substeps: u32,
+    pub accumulator: f32,
+}
+
+impl FixedTimestep {
+    pub fn new(hz: f32) -> Self {
+        Self { hz: Self::clamp_rate(hz), max_substeps: 8, accumulator: 0.0 }
+    }
+
+    // anything that isn't a number falls back to the default, the rest is clamped into range
+    pub fn clamp_rate(hz: f32) -> f32 {
+        if hz.is_nan() {
+            DEFAULT_PHYSICS_RATE
+        } else {
+            hz.clamp(MIN_PHYSICS_RATE, MAX_PHYSICS_RATE)
+        }
+    }
+
+    // starts the accumulator over, the time it held was measured in the old steps
+    pub fn set_rate(&mut self, hz: f32) {
+        self.hz = Self::clamp_rate(hz);
+        self.accumulator = 0.0;
+    }
+
+    pub fn dt(&self) -> f32 {
+        1.0 / self.hz
+    }
+
+    // eats a frame's worth of time and returns how many fixed steps are due and how many were dropped
+    pub fn advance(&mut self, frame_dt: f32) -> (u32, u32) {
+        let dt = self.dt();
+        self.accumulator += frame_dt;
+
+        let steps = (self.accumulator / dt) as u32;
+        self.accumulator -= steps as f32 * dt;
+
+        // spiral of death guard: anything past the cap is thrown away
+        let skipped = steps.saturating_sub(self.max_substeps);
+
+        (steps - skipped, skipped)
+    }
+
+    // how far we are between the last two physics states, for interpolation
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.dt()).clamp(0.0, 1.0)
+    }
 }
 
 pub struct World {
     pub phys_world: Arc<Mutex<PhysicalWorld>>,
     pub phys_meshes: HashMap<PhysMeshHandle, PhysMesh>,
-    dt_sender: Sender<f32>,
-    pub command_sender: Sender<PhysicsCommand>,
-    pub report_receiver: Receiver<PhyisicsStatus>,
-    pub status: Result<PhyisicsStatus, TryRecvError>,
+    // the other way around, kept in step with `phys_meshes`
+    pub body_meshes: HashMap<RigidBodyHandle, PhysMeshHandle>,
+    pub timestep: FixedTimestep,
+    step_sender: Sender<StepRequest>,
+    command_sender: Sender<PhysicsCommand>,
+    pub report_receiver: watch::Receiver<Option<PhyisicsStatus>>,
+    // a clone of the physics world's, so subscribing never waits on a step
+    contact_sender: broadcast::Sender<ContactEvent>,
+    pub status: Option<PhyisicsStatus>,
+    // last config handed to the physics task
+    pub config: SimulationConfig,
+    pub terrain: Option<Terrain>,
+    pub terrain_collider: Option<ColliderHandle>,
+    // articulated bodies handled as one, see `articulation`
+    pub groups: HashMap<GroupHandle, BodyGroup>,
+    pub next_group: u32,
+    pub history: SnapshotRing,
+    pub bookmark: Option<WorldSnapshot>,
+    // every file imported into this world, bodies refer to them by content hash
+    pub assets: AssetStore,
+    requested_steps: u64,
+    pub mesh_handles: HandleAllocator,
 }
 
 impl World {
     pub async fn new() -> Self {
-        let (dt_sender, mut dt_receiver) = mpsc::channel(1);
-        let (command_sender, mut command_receiver) = mpsc::channel(16);
-        let (report_sender, mut report_receiver) = mpsc::channel(16);
+        let (step_sender, mut step_receiver) = mpsc::channel::<StepRequest>(1);
+        let (command_sender, mut command_receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let (report_sender, report_receiver) = watch::channel(None);
 
-        let phys_world = Arc::new(Mutex::new(PhysicalWorld::new()));
+        let phys_world = PhysicalWorld::new();
+        let contact_sender = phys_world.contacts.sender();
+        let phys_world = Arc::new(Mutex::new(phys_world));
         let phys_world_clone = phys_world.clone();
         tokio::task::spawn(async move {
-            let mut elapsed = 0.0;
-
-            while let Some(dt) = dt_receiver.recv().await {
-                if let Ok(mut phys_world) = phys_world_clone.try_lock() {
-                    let now = std::time::Instant::now();
-                    phys_world.step(dt);
-                    elapsed = now.elapsed().as_secs_f32();
-
-                    if let Ok(command) = command_receiver.try_recv() {
-                        match command {
-                            PhysicsCommand::Impulse(v, rigid_body_handle) => {
-                                let body = &mut phys_world.rigid_body_set[rigid_body_handle];
-                                body.apply_impulse(vector![v.x, v.y, v.z], true);
-                            }
-                            PhysicsCommand::SetType(rigid_body_type, rigid_body_handle) => {
-                                let body = &mut phys_world.rigid_body_set[rigid_body_handle];
-                                body.set_body_type(rigid_body_type, false);
-                            }
-                            PhysicsCommand::Translate(v, rigid_body_handle) => {
-                                let body = &mut phys_world.rigid_body_set[rigid_body_handle];
-                                body.set_position(vector![v.x, v.y, v.z].into(), false);
-                            }
-                        }
-                    }
+            let mut simulated_steps = 0;
+            let mut skipped_steps = 0;
+            let mut applied_commands = 0;
+            let mut failed_commands = 0;
+            let mut last_error = None;
 
-                    // std::thread::sleep_ms(16);
+            while let Some(request) = step_receiver.recv().await {
+                // wait our turn instead of dropping the tick when someone else holds the world
+                let mut phys_world = phys_world_clone.lock().await;
 
-                    report_sender.try_send(
-                        PhyisicsStatus {
-                            solve_time: elapsed,
+                // everything queued since the last batch goes in, in order, before stepping
+                while let Ok(command) = command_receiver.try_recv() {
+                    match phys_world.apply_command(command) {
+                        Ok(()) => applied_commands += 1,
+                        Err(err) => {
+                            failed_commands += 1;
+                            last_error = Some(err);
                         }
-                    ).unwrap();
+                    }
+                }
+
+                let now = std::time::Instant::now();
+                for _ in 0..request.steps {
+                    phys_world.step(request.dt);
                 }
+                let elapsed = now.elapsed().as_secs_f32();
+                let broken_joints = phys_world.broken_joints;
+                drop(phys_world);
+
+                simulated_steps += request.steps as u64;
+                skipped_steps += request.skipped as u64;
+
+                // a watch only keeps the latest value, so a slow reader can never back this up
+                report_sender.send_replace(Some(PhyisicsStatus {
+                    solve_time: elapsed,
+                    applied_commands,
+                    failed_commands,
+                    last_error,
+                    simulated_steps,
+                    skipped_steps,
+                    broken_joints,
+                }));
             };
         });
 
-        let status = report_receiver.try_recv();
-
-        Self { phys_world, phys_meshes: HashMap::new(), dt_sender, command_sender, report_receiver, status, }
+        Self {
+            phys_world,
+            phys_meshes: HashMap::new(),
+            body_meshes: HashMap::new(),
+            timestep: FixedTimestep::new(DEFAULT_PHYSICS_RATE),
+            step_sender,
+            command_sender,
+            report_receiver,
+            contact_sender,
+            status: None,
+            config: SimulationConfig::default(),
+            terrain: None,
+            terrain_collider: None,
+            groups: HashMap::new(),
+            next_group: 0,
+            history: SnapshotRing::new(HISTORY_LENGTH, HISTORY_INTERVAL),
+            bookmark: None,
+            assets: AssetStore::new(),
+            requested_steps: 0,
+            mesh_handles: HandleAllocator::default(),
+        }
     }
 
-    pub async fn update(&mut self, renderer: &mut Renderer, dt: f32) {
+    pub async fn update(&mut self, sink: &mut dyn SceneSink, dt: f32) {
         /* TODO: every N frames, force the simulation to synchronize */
-        if let Ok(mut phys_world) = self.phys_world.try_lock() {
-            for phys_mesh in self.phys_meshes.values_mut() {
-                phys_mesh.update(renderer, &mut phys_world);
+        self.sync(sink);
+
+        self.tick(dt).await;
+    }
+
+    pub fn sync(&mut self, sink: &mut dyn SceneSink) {
+        let alpha = self.timestep.alpha();
+
+        if let Ok(phys_world) = self.phys_world.try_lock() {
+            for (handle, phys_mesh) in &mut self.phys_meshes {
+                phys_mesh.update(*handle, sink, &phys_world, alpha);
             }
         }
+    }
+
+    // never blocks, a full queue is reported back instead
+    pub fn send_command(&self, command: PhysicsCommand) -> Result<(), PhysicsError> {
+        self.command_sender.try_send(command).map_err(|err| match err {
+            TrySendError::Full(_) => PhysicsError::QueueFull,
+            TrySendError::Closed(_) => PhysicsError::Disconnected,
+        })
+    }
+
+    // waits for room in the queue instead of failing when it is full
+    pub async fn queue_command(&self, command: PhysicsCommand) -> Result<(), PhysicsError> {
+        self.command_sender.send(command).await.map_err(|_| PhysicsError::Disconnected)
+    }
+
+    // every collision starting or stopping from the next step on, and the contact forces bodies asked for
+    pub fn subscribe_contacts(&self) -> broadcast::Receiver<ContactEvent> {
+        self.contact_sender.subscribe()
+    }
 
-        self.status = self.report_receiver.try_recv();
+    pub fn set_config(&mut self, config: SimulationConfig) -> Result<(), PhysicsError> {
+        self.send_command(PhysicsCommand::SetConfig(config))?;
+        self.config = config;
+
+        Ok(())
+    }
 
-        self.dt_sender.send(dt).await.unwrap();
+    // clamped, see `FixedTimestep::clamp_rate`
+    pub fn set_physics_rate(&mut self, hz: f32) {
+        self.timestep.set_rate(hz);
+    }
+
+    // feeds frame time into the accumulator and runs however many fixed steps are due
+    pub async fn tick(&mut self, frame_dt: f32) {
+        self.status = *self.report_receiver.borrow();
+
+        let (steps, skipped) = self.timestep.advance(frame_dt);
+        if steps > 0 {
+            self.request_steps(steps, skipped).await;
+        }
+    }
+
+    // runs exactly one fixed step and waits for the physics task to report back
+    pub async fn step(&mut self) -> Option<PhyisicsStatus> {
+        self.request_steps(1, 0).await;
+        self.wait_for_steps().await
+    }
+
+    // waits until every step requested so far has been simulated
+    pub async fn wait_for_steps(&mut self) -> Option<PhyisicsStatus> {
+        let requested = self.requested_steps;
+        let status = self.report_receiver
+            .wait_for(|status| status.is_some_and(|s| s.simulated_steps >= requested))
+            .await
+            .ok()
+            .and_then(|status| *status);
+        self.status = status;
+
+        status
+    }
+
+    pub fn phys_mesh(&self, handle: PhysMeshHandle) -> Result<&PhysMesh, PhysicsError> {
+        self.phys_meshes.get(&handle).ok_or(PhysicsError::StaleMesh(handle))
+    }
+
+    pub fn phys_mesh_mut(&mut self, handle: PhysMeshHandle) -> Result<&mut PhysMesh, PhysicsError> {
+        self.phys_meshes.get_mut(&handle).ok_or(PhysicsError::StaleMesh(handle))
+    }
+
+    async fn request_steps(&mut self, steps: u32, skipped: u32) {
+        let request = StepRequest { steps, skipped, dt: self.timestep.dt() };
+        self.step_sender.send(request).await.expect("physics task died");
+        self.requested_steps += steps as u64;
     }
 }
 
-#[derive(Eq, Hash, PartialEq, Copy, Clone)]
+// `id` is a slot that gets reused, `generation` tells the bodies that lived in it apart
+#[derive(Eq, Hash, PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct PhysMeshHandle {
     pub id: u32,
+    pub generation: u32,
+}
+
+// bit 64 marks a tagged body, rapier leaves `user_data` at 0 for the ones we never tagged
+const USER_DATA_TAG: u128 = 1 << 64;
+
+impl PhysMeshHandle {
+    pub fn to_user_data(self) -> u128 {
+        USER_DATA_TAG | (self.generation as u128) << 32 | self.id as u128
+    }
+
+    pub fn from_user_data(user_data: u128) -> Option<Self> {
+        (user_data & USER_DATA_TAG != 0).then_some(Self { id: user_data as u32, generation: (user_data >> 32) as u32 })
+    }
 }
 
-impl Index<PhysMeshHandle> for HashMap<PhysMeshHandle, PhysMesh> {
-    type Output = PhysMesh;
+impl fmt::Display for PhysMeshHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.generation {
+            0 => write!(f, "{}", self.id),
+            generation => write!(f, "{}v{}", self.id, generation),
+        }
+    }
+}
+
+/* hands out mesh handles, a freed slot comes back with the next generation so old handles go stale */
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct HandleAllocator {
+    // current generation of every slot ever handed out and whether it's in use
+    slots: Vec<(u32, bool)>,
+    free: Vec<u32>,
+}
+
+impl HandleAllocator {
+    pub fn alloc(&mut self) -> PhysMeshHandle {
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None => {
+                self.slots.push((0, false));
+                self.slots.len() as u32 - 1
+            }
+        };
+        let slot = &mut self.slots[id as usize];
+        slot.1 = true;
+
+        PhysMeshHandle { id, generation: slot.0 }
+    }
 
-    fn index(&self, handle: PhysMeshHandle) -> &Self::Output {
-        self.get(&handle).expect("no entry for key")
+    // false for a handle that was already freed (or never allocated), nothing changes then
+    pub fn free(&mut self, handle: PhysMeshHandle) -> bool {
+        if !self.is_live(handle) {
+            return false;
+        }
+
+        self.slots[handle.id as usize] = (handle.generation.wrapping_add(1), false);
+        self.free.push(handle.id);
+
+        true
+    }
+
+    pub fn is_live(&self, handle: PhysMeshHandle) -> bool {
+        self.slots.get(handle.id as usize) == Some(&(handle.generation, true))
     }
 }
 
-impl IndexMut<PhysMeshHandle> for HashMap<PhysMeshHandle, PhysMesh> {
-    fn index_mut(&mut self, handle: PhysMeshHandle) -> &mut Self::Output {
-        self.get_mut(&handle).expect("no entry for key")
+#[cfg(test)]
+mod tests {
+    use chaos_framework::vec3;
+
+    use super::*;
+    use crate::{physics_util::BodyShape, scene::SceneBody, sink::SceneRecorder};
+
+    #[test]
+    fn accumulator_steps_and_keeps_the_remainder() {
+        let mut timestep = FixedTimestep::new(50.0);
+        assert_eq!(timestep.advance(0.07), (3, 0));
+        assert!((timestep.alpha() - 0.5).abs() < 1e-3, "alpha {}", timestep.alpha());
+
+        // the leftover half step plus another half is one more step
+        assert_eq!(timestep.advance(0.01), (1, 0));
+        assert!(timestep.alpha() < 1e-3);
+
+        assert_eq!(timestep.advance(0.005), (0, 0));
+        assert!((timestep.alpha() - 0.25).abs() < 1e-3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn steps_past_the_cap_are_skipped() {
+        let mut timestep = FixedTimestep::new(50.0);
+        assert_eq!(timestep.advance(20.5 * 0.02), (timestep.max_substeps, 20 - timestep.max_substeps));
+        // skipped steps are gone, not owed
+        assert!(timestep.accumulator < timestep.dt());
+        assert_eq!(timestep.advance(0.0), (0, 0));
+    }
+
+    #[test]
+    fn alpha_stays_between_zero_and_one() {
+        let mut timestep = FixedTimestep::new(60.0);
+        assert_eq!(timestep.alpha(), 0.0);
+        timestep.accumulator = 10.0;
+        assert_eq!(timestep.alpha(), 1.0);
+        timestep.accumulator = -1.0;
+        assert_eq!(timestep.alpha(), 0.0);
+    }
+
+    #[test]
+    fn rates_are_clamped() {
+        for hz in [0.0, -60.0, f32::NEG_INFINITY] {
+            assert_eq!(FixedTimestep::new(hz).hz, MIN_PHYSICS_RATE);
+        }
+        assert_eq!(FixedTimestep::new(f32::INFINITY).hz, MAX_PHYSICS_RATE);
+        assert_eq!(FixedTimestep::new(f32::NAN).hz, DEFAULT_PHYSICS_RATE);
+        assert!(FixedTimestep::new(0.0).dt().is_finite());
+
+        let mut timestep = FixedTimestep::new(60.0);
+        timestep.accumulator = 0.01;
+        timestep.set_rate(0.0);
+        assert_eq!(timestep.hz, MIN_PHYSICS_RATE);
+        assert_eq!(timestep.accumulator, 0.0);
+    }
+
+    #[test]
+    fn freed_slots_come_back_a_generation_later() {
+        let mut handles = HandleAllocator::default();
+        let first = handles.alloc();
+        let second = handles.alloc();
+        assert_ne!(first.id, second.id);
+
+        assert!(handles.free(first));
+        assert!(!handles.is_live(first));
+        assert!(!handles.free(first), "freed twice");
+
+        let reused = handles.alloc();
+        assert_eq!(reused.id, first.id);
+        assert_eq!(reused.generation, first.generation + 1);
+        assert!(handles.is_live(reused) && handles.is_live(second));
+        assert!(!handles.is_live(first));
+        // never handed out at all
+        assert!(!handles.is_live(PhysMeshHandle { id: 7, generation: 0 }));
+    }
+
+    #[tokio::test]
+    async fn stale_handles_destroy_nothing() {
+        let mut world = World::new().await;
+        let mut sink = SceneRecorder::new();
+        let body = SceneBody::new(BodyShape::Sphere { radius: 0.5 }, RigidBodyType::Dynamic, vec3(0.0, 2.0, 0.0));
+
+        let old = world.spawn(&mut sink, &body).await.unwrap();
+        world.destroy(&mut sink, old).await.unwrap();
+        let new = world.spawn(&mut sink, &body).await.unwrap();
+        assert_eq!(new.id, old.id);
+
+        assert!(matches!(world.destroy(&mut sink, old).await, Err(PhysicsError::StaleMesh(handle)) if handle == old));
+        assert!(world.phys_mesh(new).is_ok());
+        assert_eq!(world.phys_world.lock().await.rigid_body_set.len(), 1);
+    }
+}
@@ -1,40 +1,12 @@
-use chaos_framework::{quat, vec3, Cuboid, MeshHandle, Renderer, Sphere, Vec3, Vec4};
+use chaos_framework::{quat, vec3, Quat, Vec3};
 use rapier3d::{parry::query::Ray, prelude::*};
+use serde::{Deserialize, Serialize};
 
-use crate::{globals::read_rb_overhaul_size, phys::{self, PhysMeshHandle, World}};
+use crate::{import::{AssetStore, ImportCollider, ImportError, MeshAsset}, phys::{self, PhysMeshHandle, PhysicsError, World}, scene::SceneBody, sink::SceneSink, terrain::{Terrain, TerrainDesc, TerrainError}};
 
 impl phys::PhysicalWorld {
-    pub fn add_floor(&mut self, size: Vec3) -> ColliderHandle {
-        let ground_collider = ColliderBuilder::cuboid(size.x, size.y, size.z)
-            .translation(vector![0.0, -size.y, 0.0])
-            .build();
-        self.collider_set.insert(ground_collider)
-    }
-
-    pub fn add_sphere_rigidbody(&mut self, x: f32, y: f32, z: f32, r: f32) -> RigidBodyHandle {
-        let r = read_rb_overhaul_size();
-        let rb = RigidBodyBuilder::dynamic()
-            .translation(vector![x, y, z])
-            .build();
-        let collider = ColliderBuilder::ball(r).restitution(0.7).friction(0.5).build();
-        let body_handle = self.rigid_body_set.insert(rb.clone());
-
-        self.collider_set.insert_with_parent(collider.clone(), body_handle, &mut self.rigid_body_set);
-
-        return body_handle;
-    }
-
-    pub fn add_cube_rigidbody(&mut self, x: f32, y: f32, z: f32, r: f32) -> RigidBodyHandle {
-        let r = read_rb_overhaul_size();
-        let rb = RigidBodyBuilder::dynamic()
-            .translation(vector![x, y, z])
-            .build();
-        let collider = ColliderBuilder::cuboid(r, r, r).restitution(0.3).friction(0.5).build();
-        let body_handle = self.rigid_body_set.insert(rb.clone());
-
-        self.collider_set.insert_with_parent(collider.clone(), body_handle, &mut self.rigid_body_set);
-
-        return body_handle;
+    pub fn add_terrain(&mut self, terrain: &Terrain) -> ColliderHandle {
+        self.collider_set.insert(terrain.collider())
     }
 
     pub fn body_raycast(&mut self, origin: Vec3, direction: Vec3) -> Option<RigidBodyHandle> {
@@ -43,22 +15,16 @@ impl phys::PhysicalWorld {
             vector![direction.x, direction.y, direction.z]
         );
 
-        if let Some((handle, _hit)) = self.query_pipeline.cast_ray(
+        let (handle, _hit) = self.query_pipeline.cast_ray(
             &self.rigid_body_set,
             &self.collider_set,          
             &ray,               
             1000.0,            
             true,   
             QueryFilter::default() 
-        ) {
-            if let Some(collider) = self.collider_set.get(handle) {
-                return collider.parent();
-            } else {
-                return None;
-            }
-        } else {
-            return None;
-        };
+        )?;
+
+        self.collider_set.get(handle)?.parent()
     }
     
     pub fn pos_raycast(&mut self, origin: Vec3, direction: Vec3) -> Option<Vec3> {
@@ -67,21 +33,40 @@ impl phys::PhysicalWorld {
             vector![direction.x, direction.y, direction.z]
         );
 
-        if let Some((_handle, dist)) = self.query_pipeline.cast_ray(
+        let (_handle, dist) = self.query_pipeline.cast_ray(
             &self.rigid_body_set,
             &self.collider_set,          
             &ray,               
             1000.0,            
             true,   
             QueryFilter::default() 
-        ) {
-            return Some(origin + (direction * dist));
-        } else {
-            return None;
-        };
+        )?;
+
+        Some(origin + (direction * dist))
+    }
+
+    pub fn remove_collider(&mut self, handle: ColliderHandle) {
+        self.collider_set.remove(handle, &mut self.island_manager, &mut self.rigid_body_set, true);
+    }
+
+    // lets raycasts and contacts on `body` get back to its mesh without a search
+    pub fn tag_body(&mut self, body: RigidBodyHandle, handle: PhysMeshHandle) {
+        if let Some(body) = self.rigid_body_set.get_mut(body) {
+            body.user_data = handle.to_user_data();
+        }
+    }
+
+    pub fn mesh_of(&self, body: RigidBodyHandle) -> Option<PhysMeshHandle> {
+        self.rigid_body_set.get(body).and_then(|body| PhysMeshHandle::from_user_data(body.user_data))
     }
 
     pub fn remove_rigidbody(&mut self, handle: RigidBodyHandle) {
+        if let (Some(body), Some(mesh)) = (self.rigid_body_set.get(handle), self.mesh_of(handle)) {
+            for collider in body.colliders() {
+                self.contacts.collider_removed(*collider, mesh);
+            }
+        }
+        self.cut_from_multibody(handle);
         self.rigid_body_set.remove(
             handle, 
             &mut self.island_manager, 
@@ -90,100 +75,233 @@ impl phys::PhysicalWorld {
             &mut self.multibody_joint_set, 
             true,
         );
+        self.forget_removed_joints();
     }
 }
 
-pub struct PhysMesh {
-    pub mesh: MeshHandle,
-    pub body: RigidBodyHandle,
+// capsules, cylinders and cones stand along y
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BodyShape {
+    Sphere { radius: f32 },
+    Cuboid { half_extents: Vec3 },
+    Capsule { half_height: f32, radius: f32 },
+    Cylinder { half_height: f32, radius: f32 },
+    Cone { half_height: f32, radius: f32 },
+    // a cuboid grown by `border_radius` on every side, so the full size is `half_extents + border_radius`
+    RoundCuboid { half_extents: Vec3, border_radius: f32 },
+    Imported { asset: MeshAsset, collider: ImportCollider, scale: f32 },
 }
 
-impl PhysMesh {
-    pub fn sphere(renderer: &mut Renderer, phys_world: &mut phys::PhysicalWorld) -> Self {
-        let mut sphere_mesh = Sphere::new(16, read_rb_overhaul_size(), Vec4::ONE).mesh();
-        for face in sphere_mesh.indices.chunks_mut(3) {
-            face.reverse();
+impl BodyShape {
+    // one of each, in the order the editor lists them
+    pub const ALL: [BodyShape; 6] = [
+        BodyShape::Sphere { radius: 1.0 },
+        BodyShape::Cuboid { half_extents: Vec3::ONE },
+        BodyShape::Capsule { half_height: 0.5, radius: 0.5 },
+        BodyShape::Cylinder { half_height: 0.5, radius: 0.5 },
+        BodyShape::Cone { half_height: 0.5, radius: 0.5 },
+        BodyShape::RoundCuboid { half_extents: Vec3::splat(0.8), border_radius: 0.2 },
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BodyShape::Sphere { .. } => "Sphere",
+            BodyShape::Cuboid { .. } => "Cuboid",
+            BodyShape::Capsule { .. } => "Capsule",
+            BodyShape::Cylinder { .. } => "Cylinder",
+            BodyShape::Cone { .. } => "Cone",
+            BodyShape::RoundCuboid { .. } => "Round cuboid",
+            BodyShape::Imported { .. } => "Imported",
         }
-        Self {
-            mesh: renderer.add_mesh(
-                sphere_mesh
-            ).unwrap(),
-            body: phys_world.add_sphere_rigidbody(0.0, 1.0, 0.0, 1.0)
+    }
+
+    // same variant, whatever the dimensions
+    pub fn same_kind(&self, other: &BodyShape) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    // how far the shape reaches from its centre along its longest axis, an asset `assets` never loaded counts as its placeholder
+    pub fn half_size(&self, assets: &AssetStore) -> f32 {
+        match *self {
+            BodyShape::Sphere { radius } => radius,
+            BodyShape::Cuboid { half_extents } => half_extents.max_element(),
+            BodyShape::Capsule { half_height, radius } => half_height + radius,
+            BodyShape::Cylinder { half_height, radius } | BodyShape::Cone { half_height, radius } => half_height.max(radius),
+            BodyShape::RoundCuboid { half_extents, border_radius } => half_extents.max_element() + border_radius,
+            BodyShape::Imported { asset, scale, .. } => assets.mesh(asset).map_or(0.5, |mesh| mesh.half_size) * scale,
         }
     }
 
-    pub fn cube(renderer: &mut Renderer, phys_world: &mut phys::PhysicalWorld) -> Self {
-        let mut cube_mesh = Cuboid::new(Vec3::ONE * 2.0 * read_rb_overhaul_size(), Vec4::ONE).mesh();
-        for face in cube_mesh.indices.chunks_mut(3) {
-            face.reverse();
+    // how far the shape reaches below its centre when upright, what it stands on
+    pub fn depth(&self, assets: &AssetStore) -> f32 {
+        match *self {
+            BodyShape::Sphere { radius } => radius,
+            BodyShape::Cuboid { half_extents } => half_extents.y,
+            BodyShape::Capsule { half_height, radius } => half_height + radius,
+            BodyShape::Cylinder { half_height, .. } | BodyShape::Cone { half_height, .. } => half_height,
+            BodyShape::RoundCuboid { half_extents, border_radius } => half_extents.y + border_radius,
+            BodyShape::Imported { asset, scale, .. } => assets.mesh(asset).map_or(0.5, |mesh| mesh.depth) * scale,
         }
+    }
+
+    // an imported shape only resolves if its file was loaded into `assets`, nothing is read from disk here
+    pub fn shared_shape(&self, assets: &mut AssetStore) -> Result<SharedShape, PhysicsError> {
+        Ok(match *self {
+            BodyShape::Sphere { radius } => SharedShape::ball(radius),
+            BodyShape::Cuboid { half_extents: h } => SharedShape::cuboid(h.x, h.y, h.z),
+            BodyShape::Capsule { half_height, radius } => SharedShape::capsule_y(half_height, radius),
+            BodyShape::Cylinder { half_height, radius } => SharedShape::cylinder(half_height, radius),
+            BodyShape::Cone { half_height, radius } => SharedShape::cone(half_height, radius),
+            BodyShape::RoundCuboid { half_extents: h, border_radius } => SharedShape::round_cuboid(h.x, h.y, h.z, border_radius),
+            BodyShape::Imported { asset, collider, scale } => {
+                return assets.collider_shape(asset, collider, scale).ok_or(PhysicsError::UnknownAsset(asset));
+            }
+        })
+    }
+
+    // a triangle mesh has no volume and so no mass, rapier can't move it
+    pub fn fixed_only(&self) -> bool {
+        matches!(self, BodyShape::Imported { collider: ImportCollider::TriMesh, .. })
+    }
+}
+
+pub struct PhysMesh {
+    pub body: RigidBodyHandle,
+    pub shape: BodyShape,
+
+    // last two physics states seen, rendering interpolates between them
+    pub prev_pose: (Vec3, Quat),
+    pub pose: (Vec3, Quat),
+    pub pose_tick: Option<u64>,
+}
+
+impl PhysMesh {
+    pub fn new(body: RigidBodyHandle, shape: BodyShape) -> Self {
         Self {
-            mesh: renderer.add_mesh(
-                cube_mesh
-            ).unwrap(),
-            body: phys_world.add_cube_rigidbody(0.0, 1.0, 0.0, 1.0)
+            body,
+            shape,
+            prev_pose: (Vec3::ZERO, Quat::IDENTITY),
+            pose: (Vec3::ZERO, Quat::IDENTITY),
+            pose_tick: None,
         }
     }
 
-    pub fn update(&mut self, renderer: &mut Renderer, phys_world: &mut phys::PhysicalWorld) {
+    pub fn update(&mut self, handle: PhysMeshHandle, sink: &mut dyn SceneSink, phys_world: &phys::PhysicalWorld, alpha: f32) {
                         // once told me the world is gonna roll me
-        if let Some(body) = phys_world.rigid_body_set.get_mut(self.body) {
-            let pos = body.translation();
-            let rot = body.rotation();
+        if let Some(body) = phys_world.rigid_body_set.get(self.body) {
+            if self.pose_tick != Some(phys_world.tick) {
+                let pose = to_pose(body.position());
+
+                // the pose a tick ago, not whatever the last frame saw, a frame can run several steps
+                self.prev_pose = phys_world.previous_poses.get(&self.body).map_or(pose, to_pose);
+                self.pose = pose;
+                self.pose_tick = Some(phys_world.tick);
+            }
+
+            let pos = self.prev_pose.0.lerp(self.pose.0, alpha);
+            let rot = self.prev_pose.1.slerp(self.pose.1, alpha);
     
-            renderer.meshes[self.mesh].position = vec3(pos.x, pos.y, pos.z);
-            renderer.meshes[self.mesh].rotation = quat(rot.i, rot.j, rot.k, rot.w);
+            sink.transform(handle, pos, rot);
         }
     }
 }
 
+fn to_pose(position: &Isometry<Real>) -> (Vec3, Quat) {
+    let (pos, rot) = (position.translation.vector, position.rotation);
+    (vec3(pos.x, pos.y, pos.z), quat(rot.i, rot.j, rot.k, rot.w))
+}
+
 impl World {
-    pub async fn add_sphere(&mut self, renderer: &mut Renderer) -> PhysMeshHandle {
-        let mut phys_world = self.phys_world.lock().await;
-        let sphere = PhysMesh::sphere(renderer, &mut phys_world);
-        let handle = PhysMeshHandle {
-            id: self.phys_meshes.len() as u32,
-        };
+    // fails for an imported shape whose file this world never loaded
+    pub async fn spawn(&mut self, sink: &mut dyn SceneSink, body: &SceneBody) -> Result<PhysMeshHandle, PhysicsError> {
+        // built before the physics world is locked, a decomposition can take seconds
+        let shape = body.shape.shared_shape(&mut self.assets)?;
+
+        Ok(self.spawn_shaped(sink, body, shape).await)
+    }
 
-        self.phys_meshes.insert(handle, sphere);
+    async fn spawn_shaped(&mut self, sink: &mut dyn SceneSink, body: &SceneBody, shape: SharedShape) -> PhysMeshHandle {
+        let body_handle = body.spawn(&mut *self.phys_world.lock().await, shape);
 
-        handle
+        self.insert_phys_mesh(sink, PhysMesh::new(body_handle, body.shape)).await
     }
 
-    pub fn get_phys_mesh_from_handle(&self, handle: RigidBodyHandle) -> Option<PhysMeshHandle> {
-        for (phys_mesh_handle, phys_mesh) in &self.phys_meshes {
-            if phys_mesh.body == handle {
-                return Some(*phys_mesh_handle);
-            }
-        }
+    // reads an .obj, .gltf or .glb file, or reuses it if the same contents were imported before
+    // dynamic unless its collider can only be fixed
+    pub async fn add_imported(&mut self, sink: &mut dyn SceneSink, path: &str, collider: ImportCollider, position: Vec3) -> Result<PhysMeshHandle, ImportError> {
+        let asset = self.assets.load(path)?;
+        let body = SceneBody::new(BodyShape::Imported { asset, collider, scale: 1.0 }, RigidBodyType::Dynamic, position);
+        let shape = self.assets.collider_shape(asset, collider, 1.0).ok_or(ImportError::UnknownAsset(asset))?;
 
-        return None;
+        Ok(self.spawn_shaped(sink, &body, shape).await)
     }
 
-    pub async fn add_cube(&mut self, renderer: &mut Renderer) -> PhysMeshHandle {
-        let mut phys_world = self.phys_world.lock().await;
-        let cube = PhysMesh::cube(renderer, &mut phys_world);
-        let handle = PhysMeshHandle {
-            id: self.phys_meshes.len() as u32,
-        };
+    // registers a body that already lives in the physics world and spawns its mesh
+    pub async fn insert_phys_mesh(&mut self, sink: &mut dyn SceneSink, phys_mesh: PhysMesh) -> PhysMeshHandle {
+        let handle = self.mesh_handles.alloc();
 
-        self.phys_meshes.insert(handle, cube);
+        self.phys_world.lock().await.tag_body(phys_mesh.body, handle);
+        sink.spawn(handle, &phys_mesh.shape, &self.assets);
+        self.body_meshes.insert(phys_mesh.body, handle);
+        self.phys_meshes.insert(handle, phys_mesh);
 
         handle
     }
 
-    pub fn add_floor(&mut self, size: Vec3) {
-        if let Ok(mut phys_world) = self.phys_world.try_lock() {
-            phys_world.add_floor(size);
+    pub fn get_phys_mesh_from_handle(&self, handle: RigidBodyHandle) -> Option<PhysMeshHandle> {
+        self.body_meshes.get(&handle).copied()
+    }
+
+    // `None` takes the ground away, a heightmap that can't be read leaves the old terrain where it was
+    pub async fn set_terrain(&mut self, sink: &mut dyn SceneSink, desc: Option<TerrainDesc>) -> Result<(), TerrainError> {
+        let terrain = desc.map(Terrain::generate).transpose()?;
+        self.replace_terrain(sink, terrain).await;
+
+        Ok(())
+    }
+
+    // 0 off the edge of the terrain or without one
+    pub fn ground_height(&self, x: f32, z: f32) -> f32 {
+        self.terrain.as_ref().and_then(|terrain| terrain.height_at(x, z)).unwrap_or(0.0)
+    }
+
+    pub async fn replace_terrain(&mut self, sink: &mut dyn SceneSink, terrain: Option<Terrain>) {
+        let mut phys_world = self.phys_world.lock().await;
+        if let Some(old) = self.terrain_collider.take() {
+            phys_world.remove_collider(old);
         }
+        self.terrain_collider = terrain.as_ref().map(|terrain| phys_world.add_terrain(terrain));
+        drop(phys_world);
+
+        sink.terrain(terrain.as_ref());
+        self.terrain = terrain;
     }
 
-    pub async fn destroy(&mut self, renderer: &mut Renderer, handle: PhysMeshHandle) {
+    // takes the body, its colliders and joints, its mesh and its handle with it, a stale handle changes nothing
+    pub async fn destroy(&mut self, sink: &mut dyn SceneSink, handle: PhysMeshHandle) -> Result<PhysMesh, PhysicsError> {
+        let phys_mesh = self.phys_meshes.remove(&handle).ok_or(PhysicsError::StaleMesh(handle))?;
+
+        self.body_meshes.remove(&phys_mesh.body);
         let mut phys_world = self.phys_world.lock().await;
-        
-        let phys_mesh = &self.phys_meshes[handle];
         phys_world.remove_rigidbody(phys_mesh.body);
-        renderer.destroy_mesh(phys_mesh.mesh);
-        self.phys_meshes.remove(&handle);
+        sink.despawn(handle);
+        self.mesh_handles.free(handle);
+
+        // a group is what still hangs off its root, losing the root breaks it up into loose bodies
+        let phys_meshes = &self.phys_meshes;
+        let multibody = |mesh: &PhysMeshHandle| phys_meshes.get(mesh)
+            .and_then(|phys_mesh| phys_world.multibody_joint_set.rigid_body_link(phys_mesh.body))
+            .map(|link| link.multibody);
+        self.groups.retain(|_, group| {
+            let Some(&root) = group.bodies.first().filter(|root| **root != handle) else {
+                return false;
+            };
+            // `handle` has no mesh any more, so it never matches
+            let articulation = multibody(&root);
+            group.bodies.retain(|body| *body == root || (articulation.is_some() && multibody(body) == articulation));
+            true
+        });
+
+        Ok(phys_mesh)
     }
 }
\ No newline at end of file
@@ -0,0 +1,306 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, time::Instant};
+
+use chaos_framework::{quat, vec3, Quat, Vec3};
+use rapier3d::{na::{Quaternion, UnitQuaternion}, prelude::*};
+
+use crate::{
+    import::AssetStore,
+    phys::{FixedTimestep, PhysMeshHandle, PhysicalWorld},
+    physics_util::BodyShape,
+    replication::{Poses, Replica},
+    scene::SceneBody,
+    sink::SceneSink,
+    terrain::{Terrain, TerrainDesc, TerrainError},
+};
+
+// seconds a body stays predicted after its last input was confirmed
+pub const PREDICTION_LINGER: f32 = 1.0;
+// how fast a correction fades out, per second
+pub const SMOOTHING_RATE: f32 = 10.0;
+
+pub struct PendingInput {
+    pub input: u32,
+    // local tick the impulse went in at, replays put it back at the same spot
+    pub tick: u64,
+    pub handle: PhysMeshHandle,
+    pub impulse: Vec3,
+    pub sent_at: Instant,
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct PredictionStats {
+    pub corrections: u64,
+    pub replayed_steps: u64,
+    pub max_error: f32,
+}
+
+/* runs the bodies we poke at ahead of the server, everything else is a kinematic stand-in */
+pub struct Prediction {
+    pub world: PhysicalWorld,
+    pub timestep: FixedTimestep,
+    pub tick: u64,
+    pub inputs: VecDeque<PendingInput>,
+    pub next_input: u32,
+    // network id -> local stand-in
+    pub bodies: HashMap<PhysMeshHandle, RigidBodyHandle>,
+    // bodies we simulate ourselves, with the seconds left before handing them back
+    pub predicted: HashMap<PhysMeshHandle, f32>,
+    // drawn pose minus simulated pose, shrinks a bit every frame
+    pub errors: HashMap<PhysMeshHandle, (Vec3, Quat)>,
+    // smoothed round trip, sets how far ahead of the newest snapshot we run
+    pub rtt: f32,
+    pub stats: PredictionStats,
+    // the last two authoritative states, enough to guess velocities from
+    authority: VecDeque<(u64, Poses)>,
+    terrain: Option<ColliderHandle>,
+}
+
+fn isometry(position: Vec3, rotation: Quat) -> Isometry<Real> {
+    Isometry::from_parts(
+        vector![position.x, position.y, position.z].into(),
+        UnitQuaternion::new_normalize(Quaternion::new(rotation.w, rotation.x, rotation.y, rotation.z)),
+    )
+}
+
+impl Prediction {
+    pub fn new(tick_rate: f32) -> Self {
+        Self {
+            world: PhysicalWorld::new(),
+            timestep: FixedTimestep::new(tick_rate),
+            tick: 0,
+            inputs: VecDeque::new(),
+            next_input: 1,
+            bodies: HashMap::new(),
+            predicted: HashMap::new(),
+            errors: HashMap::new(),
+            rtt: 0.0,
+            stats: PredictionStats::default(),
+            authority: VecDeque::with_capacity(2),
+            terrain: None,
+        }
+    }
+
+    // built from the same description the server used, so we land on the same heights
+    pub fn set_terrain(&mut self, desc: Option<TerrainDesc>) -> Result<(), TerrainError> {
+        let terrain = desc.map(Terrain::generate).transpose()?;
+        if let Some(old) = self.terrain.take() {
+            self.world.remove_collider(old);
+        }
+        self.terrain = terrain.map(|terrain| self.world.add_terrain(&terrain));
+
+        Ok(())
+    }
+
+    // adds a stand-in for every replicated body we don't have yet and drops the ones that are gone
+    pub fn sync_bodies(&mut self, shapes: &HashMap<PhysMeshHandle, BodyShape>, assets: &mut AssetStore) {
+        let gone: Vec<PhysMeshHandle> = self.bodies.keys().filter(|handle| !shapes.contains_key(handle)).copied().collect();
+        for handle in gone {
+            let body = self.bodies.remove(&handle).unwrap();
+            self.world.remove_rigidbody(body);
+            self.predicted.remove(&handle);
+            self.errors.remove(&handle);
+        }
+
+        for (handle, shape) in shapes {
+            if !self.bodies.contains_key(handle) {
+                let position = self.authority.back()
+                    .and_then(|(_, poses)| poses.get(handle))
+                    .map_or(Vec3::ZERO, |pose| pose.0);
+                let collider = shape.shared_shape(assets).unwrap_or_else(|_| {
+                    // an import we never loaded collides like the box it's drawn as
+                    let half_size = shape.half_size(assets);
+                    SharedShape::cuboid(half_size, half_size, half_size)
+                });
+                let body = SceneBody::new(*shape, RigidBodyType::KinematicPositionBased, position).spawn(&mut self.world, collider);
+                self.world.tag_body(body, *handle);
+                self.bodies.insert(*handle, body);
+            }
+        }
+    }
+
+    pub fn pose(&self, handle: PhysMeshHandle) -> Option<(Vec3, Quat)> {
+        let body = self.world.rigid_body_set.get(*self.bodies.get(&handle)?)?;
+        let (pos, rot) = (body.translation(), body.rotation());
+
+        Some((vec3(pos.x, pos.y, pos.z), quat(rot.i, rot.j, rot.k, rot.w)))
+    }
+
+    // applies the impulse locally right away, returns the input number to send along with it
+    pub fn input(&mut self, handle: PhysMeshHandle, impulse: Vec3) -> Option<u32> {
+        let body = *self.bodies.get(&handle)?;
+
+        if !self.predicted.contains_key(&handle) {
+            self.take_over(handle);
+        }
+        self.predicted.insert(handle, PREDICTION_LINGER);
+        self.world.rigid_body_set[body].apply_impulse(vector![impulse.x, impulse.y, impulse.z], true);
+
+        let input = self.next_input;
+        self.next_input += 1;
+        self.inputs.push_back(PendingInput { input, tick: self.tick, handle, impulse, sent_at: Instant::now() });
+
+        Some(input)
+    }
+
+    // turns a kinematic stand-in into a simulated body moving the way the server last had it
+    fn take_over(&mut self, handle: PhysMeshHandle) {
+        let (linvel, angvel) = self.estimate_velocity(handle);
+        let body = &mut self.world.rigid_body_set[self.bodies[&handle]];
+
+        body.set_body_type(RigidBodyType::Dynamic, true);
+        body.set_linvel(vector![linvel.x, linvel.y, linvel.z], true);
+        body.set_angvel(vector![angvel.x, angvel.y, angvel.z], true);
+    }
+
+    fn estimate_velocity(&self, handle: PhysMeshHandle) -> (Vec3, Vec3) {
+        let (Some((t0, old)), Some((t1, new))) = (self.authority.front(), self.authority.back()) else {
+            return (Vec3::ZERO, Vec3::ZERO);
+        };
+        let (Some(a), Some(b)) = (old.get(&handle), new.get(&handle)) else {
+            return (Vec3::ZERO, Vec3::ZERO);
+        };
+        if t1 <= t0 {
+            return (Vec3::ZERO, Vec3::ZERO);
+        }
+
+        let dt = (t1 - t0) as f32 * self.timestep.dt();
+        let (axis, mut angle) = (b.1 * a.1.inverse()).to_axis_angle();
+        if angle > std::f32::consts::PI {
+            angle -= std::f32::consts::TAU;
+        }
+
+        ((b.0 - a.0) / dt, axis * angle / dt)
+    }
+
+    // snaps to the server state at `tick`, then replays every input it hasn't seen yet
+    pub fn reconcile(&mut self, tick: u64, poses: &Poses, input_ack: u32) {
+        while self.inputs.front().is_some_and(|input| input.input <= input_ack) {
+            let input = self.inputs.pop_front().unwrap();
+            let sample = input.sent_at.elapsed().as_secs_f32();
+            self.rtt = if self.rtt == 0.0 { sample } else { self.rtt + (sample - self.rtt) * 0.1 };
+        }
+
+        if self.authority.len() == 2 {
+            self.authority.pop_front();
+        }
+        self.authority.push_back((tick, poses.clone()));
+
+        let before: Vec<(PhysMeshHandle, (Vec3, Quat))> = self.predicted.keys()
+            .filter_map(|handle| Some((*handle, self.drawn_pose(*handle, None)?)))
+            .collect();
+
+        for (handle, body) in &self.bodies {
+            let Some(&(position, rotation)) = poses.get(handle) else {
+                continue;
+            };
+            let body = &mut self.world.rigid_body_set[*body];
+            body.set_position(isometry(position, rotation), true);
+            if body.is_kinematic() {
+                body.set_next_kinematic_position(isometry(position, rotation));
+            }
+        }
+        let predicted: Vec<PhysMeshHandle> = self.predicted.keys().copied().collect();
+        for handle in predicted {
+            self.take_over(handle);
+        }
+
+        // run a round trip ahead of the server so our inputs land about when we drew them
+        let lead = (self.rtt * self.timestep.hz).ceil() as u64;
+        let target = tick + lead;
+        if self.tick < target || self.tick > target + (self.timestep.hz / 4.0) as u64 {
+            self.tick = target;
+        }
+
+        let dt = self.timestep.dt();
+        let mut next = 0;
+        for t in tick..self.tick {
+            while let Some(input) = self.inputs.get(next).filter(|input| input.tick <= t) {
+                self.replay_input(input.handle, input.impulse);
+                next += 1;
+            }
+            self.world.step(dt);
+            self.stats.replayed_steps += 1;
+        }
+        // inputs from a clock that was running ahead, better late than never
+        while let Some(input) = self.inputs.get(next) {
+            self.replay_input(input.handle, input.impulse);
+            next += 1;
+        }
+
+        for (handle, drawn) in before {
+            let Some(pose) = self.pose(handle) else {
+                continue;
+            };
+            let error = (drawn.0 - pose.0, drawn.1 * pose.1.inverse());
+
+            self.stats.corrections += 1;
+            self.stats.max_error = self.stats.max_error.max(error.0.length());
+            self.errors.insert(handle, error);
+        }
+    }
+
+    fn replay_input(&mut self, handle: PhysMeshHandle, impulse: Vec3) {
+        if let Some(body) = self.bodies.get(&handle).and_then(|body| self.world.rigid_body_set.get_mut(*body)) {
+            body.apply_impulse(vector![impulse.x, impulse.y, impulse.z], true);
+        }
+    }
+
+    // simulated pose for predicted bodies, the replica's otherwise, plus whatever error is left
+    fn drawn_pose(&self, handle: PhysMeshHandle, replica: Option<&Replica>) -> Option<(Vec3, Quat)> {
+        let base = if self.predicted.contains_key(&handle) {
+            self.pose(handle)?
+        } else {
+            *replica?.poses.get(&handle)?
+        };
+        let error = self.errors.get(&handle).copied().unwrap_or((Vec3::ZERO, Quat::IDENTITY));
+
+        Some((base.0 + error.0, error.1 * base.1))
+    }
+
+    // steps our bodies forward and draws them over what the replica drew
+    pub fn update(&mut self, sink: &mut dyn SceneSink, dt: f32, replica: &Replica) {
+        let (steps, _) = self.timestep.advance(dt);
+        for _ in 0..steps {
+            self.world.step(self.timestep.dt());
+            self.tick += 1;
+        }
+
+        let pending: HashSet<PhysMeshHandle> = self.inputs.iter().map(|input| input.handle).collect();
+        let mut released = Vec::new();
+        for (handle, left) in &mut self.predicted {
+            if pending.contains(handle) {
+                *left = PREDICTION_LINGER;
+            } else {
+                *left -= dt;
+                if *left <= 0.0 {
+                    released.push(*handle);
+                }
+            }
+        }
+
+        // hand them back to interpolation, the jump to the delayed pose gets smoothed like any other
+        for handle in released {
+            let drawn = self.drawn_pose(handle, Some(replica));
+            self.predicted.remove(&handle);
+            self.world.rigid_body_set[self.bodies[&handle]].set_body_type(RigidBodyType::KinematicPositionBased, true);
+
+            if let (Some(drawn), Some(pose)) = (drawn, replica.poses.get(&handle)) {
+                self.errors.insert(handle, (drawn.0 - pose.0, drawn.1 * pose.1.inverse()));
+            }
+        }
+
+        let decay = (-dt * SMOOTHING_RATE).exp();
+        for (position, rotation) in self.errors.values_mut() {
+            *position *= decay;
+            *rotation = Quat::IDENTITY.slerp(*rotation, decay);
+        }
+        self.errors.retain(|_, (position, rotation)| position.length() > 1e-3 || rotation.angle_between(Quat::IDENTITY) > 1e-3);
+
+        let drawn: HashSet<PhysMeshHandle> = self.predicted.keys().chain(self.errors.keys()).copied().collect();
+        for handle in drawn {
+            if let Some((position, rotation)) = self.drawn_pose(handle, Some(replica)) {
+                sink.transform(handle, position, rotation);
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::{fmt, io};
+
+use chaos_framework::Vec3;
+use rapier3d::prelude::RigidBodyType;
+use serde::{Deserialize, Serialize};
+
+use crate::{phys::PhysMeshHandle, physics_util::BodyShape, reliable::Packet, scene::SceneBody, terrain::TerrainDesc};
+
+// biggest datagram we ever read, anything larger is cut off by the socket
+pub const MAX_PACKET_SIZE: usize = 65507;
+// bump on any change to the messages below, the oldest one we still talk is the minimum
+pub const PROTOCOL_VERSION: u32 = 9;
+pub const MIN_PROTOCOL_VERSION: u32 = 9;
+
+// reliable ordered channels, each one in order on its own so a lost message only holds up its own
+// body changes (spawns, removals, edits) in both directions
+pub const CHANNEL_WORLD: u8 = 0;
+// everything else that must arrive, e.g. rejections
+pub const CHANNEL_CONTROL: u8 = 1;
+pub const RELIABLE_CHANNELS: usize = 2;
+
+// everything but `Connect` has to carry the session the server handed out
+pub type ClientPacket = Packet<ClientMessage>;
+pub type ServerPacket = Packet<ServerMessage>;
+
+/* client -> server, snapshot acks, impulses and heartbeats go unreliably, the rest on `CHANNEL_WORLD` */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ClientMessage {
+    // repeated until welcomed, `nonce` tells a retry apart from a reconnect from the same address
+    Connect { min_version: u32, max_version: u32, nonce: u64 },
+    Disconnect,
+    // keeps the session alive when there's nothing else to send
+    Heartbeat,
+    Spawn { body: SceneBody },
+    // `input` numbers predicted impulses from 1 up, 0 when the client isn't predicting
+    Impulse { handle: PhysMeshHandle, impulse: Vec3, input: u32 },
+    SetType { handle: PhysMeshHandle, body_type: RigidBodyType },
+    Translate { handle: PhysMeshHandle, position: Vec3 },
+    Remove { handle: PhysMeshHandle },
+    // the newest snapshot we decoded, the server deltas against it from then on
+    Ack { tick: u64 },
+}
+
+// the messages that hand `terrain` over, in the order they have to go on one reliable channel
+pub fn terrain_messages(terrain: Option<&TerrainDesc>) -> Vec<ServerMessage> {
+    let Some((desc, chunks)) = terrain.map(TerrainDesc::split) else {
+        return vec![ServerMessage::Terrain { terrain: None }];
+    };
+
+    std::iter::once(ServerMessage::Terrain { terrain: Some(desc) })
+        .chain(chunks.into_iter().map(|heights| ServerMessage::TerrainSamples { heights }))
+        .collect()
+}
+
+/* server -> client, snapshots and heartbeats go unreliably */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ServerMessage {
+    // the handshake answer, followed by the terrain and every body the server already has
+    // `timeout` is in seconds, `player` is the body spawned for this client if any
+    Welcome {
+        session: u64,
+        version: u32,
+        timeout: f32,
+        tick_rate: f32,
+        send_rate: f32,
+        player: Option<PhysMeshHandle>,
+    },
+    Spawned { handle: PhysMeshHandle, shape: BodyShape },
+    Removed { handle: PhysMeshHandle },
+    // the ground was swapped out, `None` when there is none left
+    // a heightmap comes without its samples, see `TerrainDesc::split`, they follow as `TerrainSamples`
+    Terrain { terrain: Option<TerrainDesc> },
+    TerrainSamples { heights: Vec<u16> },
+    // bit packed body poses, see `delta`, only what changed since `baseline`
+    // `input_ack` is the newest predicted input that made it into this state
+    Snapshot { tick: u64, baseline: Option<u64>, input_ack: u32, data: Vec<u8> },
+    Rejected { reason: String },
+    Heartbeat,
+    // refused to connect, or the session is over
+    Disconnected { reason: DisconnectReason },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    Left,
+    TimedOut,
+    ServerFull,
+    // no version both ends speak, the server's range is attached
+    VersionMismatch { min: u32, max: u32 },
+    // the server doesn't know us (anymore), connect again
+    UnknownSession,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Left => write!(f, "left"),
+            DisconnectReason::TimedOut => write!(f, "timed out"),
+            DisconnectReason::ServerFull => write!(f, "server is full"),
+            DisconnectReason::VersionMismatch { min, max } => write!(f, "server speaks protocol {min} to {max}, we speak {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"),
+            DisconnectReason::UnknownSession => write!(f, "unknown session"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    Encode(bincode::Error),
+    Decode(bincode::Error),
+    MissingBaseline(u64),
+    Malformed,
+    NotConnected,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::Io(err) => write!(f, "io error: {err}"),
+            NetError::Encode(err) => write!(f, "could not encode message: {err}"),
+            NetError::Decode(err) => write!(f, "could not decode message: {err}"),
+            NetError::MissingBaseline(tick) => write!(f, "snapshot is a delta against tick {tick} which we no longer have"),
+            NetError::Malformed => write!(f, "malformed snapshot"),
+            NetError::NotConnected => write!(f, "not connected"),
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+impl From<io::Error> for NetError {
+    fn from(err: io::Error) -> Self {
+        NetError::Io(err)
+    }
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>, NetError> {
+    bincode::serialize(message).map_err(NetError::Encode)
+}
+
+pub fn decode<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, NetError> {
+    bincode::deserialize(bytes).map_err(NetError::Decode)
+}
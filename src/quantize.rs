@@ -0,0 +1,249 @@
+use chaos_framework::{Quat, Vec3};
+
+// positions live in [-POSITION_BOUND, POSITION_BOUND] on every axis, ~1mm steps
+pub const POSITION_BOUND: f32 = 512.0;
+pub const POSITION_BITS: u32 = 20;
+// smallest three: 2 bits for which component was dropped, the rest at this precision
+pub const ROTATION_BITS: u32 = 10;
+pub const ROTATION_BOUND: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct QuantizedPose {
+    pub position: [u32; 3],
+    pub rotation: u32,
+}
+
+fn quantize_float(value: f32, bound: f32, bits: u32) -> u32 {
+    let max = ((1u64 << bits) - 1) as f32;
+    let normalized = (value.clamp(-bound, bound) + bound) / (2.0 * bound);
+
+    (normalized * max).round() as u32
+}
+
+fn dequantize_float(value: u32, bound: f32, bits: u32) -> f32 {
+    let max = ((1u64 << bits) - 1) as f32;
+
+    value as f32 / max * 2.0 * bound - bound
+}
+
+pub fn quantize_position(position: Vec3) -> [u32; 3] {
+    position.to_array().map(|v| quantize_float(v, POSITION_BOUND, POSITION_BITS))
+}
+
+pub fn dequantize_position(position: [u32; 3]) -> Vec3 {
+    Vec3::from_array(position.map(|v| dequantize_float(v, POSITION_BOUND, POSITION_BITS)))
+}
+
+pub fn quantize_rotation(rotation: Quat) -> u32 {
+    let mut q = rotation.normalize().to_array();
+
+    let largest = (0..4)
+        .max_by(|a, b| q[*a].abs().total_cmp(&q[*b].abs()))
+        .unwrap();
+    // q and -q are the same rotation, flip so the dropped one is positive and can be rebuilt
+    if q[largest] < 0.0 {
+        q = q.map(|v| -v);
+    }
+
+    let mut packed = largest as u32;
+    for (i, v) in q.iter().enumerate() {
+        if i != largest {
+            packed = (packed << ROTATION_BITS) | quantize_float(*v, ROTATION_BOUND, ROTATION_BITS);
+        }
+    }
+
+    packed
+}
+
+pub fn dequantize_rotation(packed: u32) -> Quat {
+    let largest = (packed >> (3 * ROTATION_BITS)) as usize & 0b11;
+    let mask = (1 << ROTATION_BITS) - 1;
+
+    let mut q = [0.0; 4];
+    let mut sum = 0.0;
+    let mut shift = 3 * ROTATION_BITS;
+    for (i, v) in q.iter_mut().enumerate() {
+        if i != largest {
+            shift -= ROTATION_BITS;
+            *v = dequantize_float((packed >> shift) & mask, ROTATION_BOUND, ROTATION_BITS);
+            sum += *v * *v;
+        }
+    }
+    q[largest] = (1.0 - sum).max(0.0).sqrt();
+
+    Quat::from_array(q).normalize()
+}
+
+impl QuantizedPose {
+    pub fn new(position: Vec3, rotation: Quat) -> Self {
+        Self { position: quantize_position(position), rotation: quantize_rotation(rotation) }
+    }
+
+    pub fn position(&self) -> Vec3 {
+        dequantize_position(self.position)
+    }
+
+    pub fn rotation(&self) -> Quat {
+        dequantize_rotation(self.rotation)
+    }
+}
+
+/* msb first, the last byte is zero padded */
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                self.bytes[self.bits / 8] |= 0x80 >> (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write(value as u32, 1);
+    }
+
+    pub fn append(&mut self, other: &BitWriter) {
+        let mut reader = BitReader::new(&other.bytes);
+        for _ in 0..other.bits {
+            self.write(reader.read(1).unwrap(), 1);
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    bits: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, bits: 0 }
+    }
+
+    // None once we run off the end of the buffer
+    pub fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.bytes.get(self.bits / 8)?;
+            let bit = (byte >> (7 - self.bits % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.bits += 1;
+        }
+
+        Some(value)
+    }
+
+    pub fn read_bool(&mut self) -> Option<bool> {
+        self.read(1).map(|bit| bit == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_framework::vec3;
+
+    use super::*;
+
+    #[test]
+    fn bits_round_trip() {
+        let values = [(0b1, 1), (0b101, 3), (0x3ff, 10), (0, 7), (0xdead_beef, 32), (0x12345, 20), (1, 2)];
+        let mut writer = BitWriter::new();
+        for (value, bits) in values {
+            writer.write(value, bits);
+        }
+        writer.write_bool(true);
+        assert_eq!(writer.bits(), 76);
+
+        let bytes = writer.into_bytes();
+        assert_eq!(bytes.len(), 10);
+        let mut reader = BitReader::new(&bytes);
+        for (value, bits) in values {
+            assert_eq!(reader.read(bits), Some(value));
+        }
+        assert_eq!(reader.read_bool(), Some(true));
+        // the padding is still readable, past it isn't
+        assert_eq!(reader.read(4), Some(0));
+        assert_eq!(reader.read(1), None);
+    }
+
+    #[test]
+    fn append_keeps_unaligned_bits() {
+        let mut tail = BitWriter::new();
+        tail.write(0b10110, 5);
+        tail.write(0x7f, 7);
+
+        let mut writer = BitWriter::new();
+        writer.write(0b011, 3);
+        writer.append(&tail);
+        assert_eq!(writer.bits(), 15);
+
+        let bytes = writer.into_bytes();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read(3), Some(0b011));
+        assert_eq!(reader.read(5), Some(0b10110));
+        assert_eq!(reader.read(7), Some(0x7f));
+    }
+
+    #[test]
+    fn positions_round_trip_within_a_step() {
+        let step = 2.0 * POSITION_BOUND / ((1 << POSITION_BITS) - 1) as f32;
+        for position in [Vec3::ZERO, vec3(1.234, -56.78, 300.5), Vec3::splat(-POSITION_BOUND), Vec3::splat(POSITION_BOUND)] {
+            let back = dequantize_position(quantize_position(position));
+            assert!((back - position).abs().max_element() <= step, "{position} came back as {back}");
+        }
+
+        // out of bounds clamps instead of wrapping
+        assert_eq!(dequantize_position(quantize_position(Vec3::splat(1e6))), Vec3::splat(POSITION_BOUND));
+    }
+
+    #[test]
+    fn rotations_round_trip() {
+        let rotations = [
+            Quat::IDENTITY,
+            Quat::from_rotation_x(1.0),
+            Quat::from_rotation_y(-2.5),
+            Quat::from_rotation_z(std::f32::consts::PI),
+            Quat::from_euler(chaos_framework::EulerRot::XYZ, 0.3, -1.2, 2.9),
+            // every component the same size, any of them may be the one dropped
+            Quat::from_xyzw(0.5, -0.5, 0.5, -0.5),
+            // largest component negative, sent as -q
+            -Quat::from_rotation_y(0.7),
+        ];
+
+        for rotation in rotations {
+            let back = dequantize_rotation(quantize_rotation(rotation));
+            // q and -q are the same rotation
+            let error = 1.0 - rotation.dot(back).abs();
+            assert!(error < 1e-4, "{rotation} came back as {back}");
+            assert!(rotation.angle_between(back) < 0.01, "{rotation} came back as {back}");
+        }
+    }
+
+    #[test]
+    fn pose_requantizes_to_itself() {
+        let pose = QuantizedPose::new(vec3(10.0, 2.0, -3.0), Quat::from_rotation_y(0.4));
+        assert_eq!(QuantizedPose::new(pose.position(), pose.rotation()), pose);
+    }
+}
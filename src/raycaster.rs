@@ -1,5 +1,5 @@
 use chaos_framework::{vec2, EventLoop, Renderer, Vec3};
-use rapier3d::prelude::{RigidBody, RigidBodyHandle};
+use rapier3d::prelude::RigidBodyHandle;
 
 use crate::{phys::World, utils::get_ray_from_mouse, viewport::ViewportCtx};
 
@@ -8,34 +8,34 @@ pub struct Raycaster {
 }
 
 impl Raycaster {
-    pub async fn get_body_from_mouse(
-        el: &EventLoop, 
-        renderer: &Renderer,
-        world: &mut World,
-        ctx: &ViewportCtx,
-    ) -> Option<RigidBodyHandle> {
+    pub fn ray_from_mouse(el: &EventLoop, renderer: &Renderer, ctx: &ViewportCtx) -> (Vec3, Vec3) {
         let mouse_pos = el.event_handler.mouse_pos;
         let (w, h) = el.window.get_size();
         let window_size = vec2(w as f32, h as f32);
         let projection = renderer.camera.proj;
         let view = renderer.camera.view;
 
-        let (origin, dir) = get_ray_from_mouse(
+        get_ray_from_mouse(
             mouse_pos, 
             window_size, 
             projection, 
             ctx.w_padding as f32,
             ctx.h_padding as f32,
             view
-        );
+        )
+    }
+
+    pub async fn get_body_from_mouse(
+        el: &EventLoop, 
+        renderer: &Renderer,
+        world: &mut World,
+        ctx: &ViewportCtx,
+    ) -> Option<RigidBodyHandle> {
+        let (origin, dir) = Self::ray_from_mouse(el, renderer, ctx);
 
         let mut phys_world = world.phys_world.lock().await;
         
-        if let Some(handle) = phys_world.body_raycast(origin, dir) {
-            return Some(handle)
-        } else {
-            return None;
-        }
+        phys_world.body_raycast(origin, dir)
     }
 
     pub async fn get_world_pos_from_mouse(
@@ -44,27 +44,10 @@ impl Raycaster {
         world: &mut World,
         ctx: &ViewportCtx,
     ) -> Option<Vec3> {
-        let mouse_pos = el.event_handler.mouse_pos;
-        let (w, h) = el.window.get_size();
-        let window_size = vec2(w as f32, h as f32);
-        let projection = renderer.camera.proj;
-        let view = renderer.camera.view;
-
-        let (origin, dir) = get_ray_from_mouse(
-            mouse_pos, 
-            window_size, 
-            projection, 
-            ctx.w_padding as f32,
-            ctx.h_padding as f32,
-            view
-        );
+        let (origin, dir) = Self::ray_from_mouse(el, renderer, ctx);
 
         let mut phys_world = world.phys_world.lock().await;
         
-        if let Some(pos) = phys_world.pos_raycast(origin, dir) {
-            return Some(pos)
-        } else {
-            return None;
-        }
+        phys_world.pos_raycast(origin, dir)
     }
 }
\ No newline at end of file
@@ -1,37 +1,57 @@
 use chaos_framework::{vec3, EventLoop, Renderer, Vec3};
 
-use crate::globals::read_rb_overhaul_size;
+use crate::articulation::GroupKind;
+use crate::physics_util::BodyShape;
+use crate::scene::SceneBody;
+use crate::sink::SceneSink;
 use crate::{phys::World, raycaster::Raycaster, viewport::ViewportCtx};
 
-use crate::phys::PhysicsCommand;
-
-pub struct RbBuilderCtx {
-
-}
-
 pub struct RbBuilder {
 
 }
 
 impl RbBuilder {
-    pub async fn update(world: &mut World, renderer: &mut Renderer, el: &EventLoop, ctx: &ViewportCtx) {
+    pub async fn update(world: &mut World, renderer: &mut Renderer, el: &EventLoop, ctx: &mut ViewportCtx) {
         if el.event_handler.rmb {
-            if let Some(pos) = Raycaster::get_world_pos_from_mouse(&el, renderer, world, ctx).await {
-                add_cube(world, renderer, pos).await;
+            if let Some(pos) = Raycaster::get_world_pos_from_mouse(el, renderer, world, ctx).await {
+                let body = ctx.spawn;
+                spawn_at(world, &mut ctx.sink(renderer), &body, pos).await;
             }
         }
 
+        // cycles through the shapes right click and F place, with their default dimensions
+        if el.event_handler.key_just_pressed(glfw::Key::T) {
+            let next = BodyShape::ALL.iter().position(|shape| shape.same_kind(&ctx.spawn.shape)).map_or(0, |i| (i + 1) % BodyShape::ALL.len());
+            ctx.spawn.shape = BodyShape::ALL[next];
+        }
+
         if el.event_handler.key_just_pressed(glfw::Key::F) {
-            if let Some(pos) = Raycaster::get_world_pos_from_mouse(&el, renderer, world, ctx).await {
-                add_cube(world, renderer, pos).await;
+            if let Some(pos) = Raycaster::get_world_pos_from_mouse(el, renderer, world, ctx).await {
+                let body = ctx.spawn;
+                spawn_at(world, &mut ctx.sink(renderer), &body, pos).await;
+            }
+        }
+
+        if el.event_handler.key_just_pressed(glfw::Key::G) {
+            if let Some(pos) = Raycaster::get_world_pos_from_mouse(el, renderer, world, ctx).await {
+                // a chain hangs down from where it starts, so it starts high enough to clear the ground
+                let pos = match ctx.articulated {
+                    GroupKind::Chain => pos + vec3(0.0, ctx.chain.links as f32 * ctx.chain.link_length + 1.0, 0.0),
+                    _ => pos,
+                };
+                let (kind, chain) = (ctx.articulated, ctx.chain);
+                if let Err(err) = world.spawn_articulated(&mut ctx.sink(renderer), kind, &chain, pos).await {
+                    eprintln!("could not spawn {}: {err}", kind.name());
+                }
             }
         }
     }   
 }
 
-pub async fn add_cube(world: &mut World, renderer: &mut Renderer, pos: Vec3) {
-    let cube = world.add_cube(renderer).await;
-    let handle = world.phys_meshes[cube].body;
-
-    world.command_sender.send(PhysicsCommand::Translate(pos + vec3(0.0, read_rb_overhaul_size(), 0.0), handle)).await.unwrap();
-}
\ No newline at end of file
+// `body` as configured, resting on top of `pos` instead of half sunk into it
+pub async fn spawn_at(world: &mut World, sink: &mut dyn SceneSink, body: &SceneBody, pos: Vec3) {
+    let body = SceneBody { position: pos + vec3(0.0, body.shape.depth(&world.assets), 0.0), ..*body };
+    if let Err(err) = world.spawn(sink, &body).await {
+        eprintln!("could not spawn {}: {err}", body.shape.name());
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+// bytes of messages we put in one datagram, reliable and unreliable together, headers aside
+pub const PACKET_BUDGET: usize = 1100;
+// packets we remember sending, older ones fell out of the ack bitfield and count as lost
+pub const SENT_PACKET_HISTORY: usize = 64;
+// unacked messages in flight per channel, the receiver never buffers more than this
+pub const SEND_WINDOW: u32 = 256;
+pub const INITIAL_RTO: Duration = Duration::from_millis(200);
+pub const MIN_RTO: Duration = Duration::from_millis(50);
+pub const MAX_RTO: Duration = Duration::from_secs(1);
+
+/* every datagram in either direction, sequence 0 is for talking to someone we have no endpoint for */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Packet<M> {
+    pub session: u64,
+    pub sequence: u32,
+    // newest packet we got from the other end, and the 32 before it as bits (lsb is `ack - 1`)
+    pub ack: u32,
+    pub ack_bits: u32,
+    pub reliable: Vec<ReliableMessage<M>>,
+    pub message: Option<M>,
+}
+
+impl<M> Packet<M> {
+    pub fn unconnected(session: u64, message: M) -> Self {
+        Self { session, sequence: 0, ack: 0, ack_bits: 0, reliable: Vec::new(), message: Some(message) }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReliableMessage<M> {
+    pub channel: u8,
+    pub id: u32,
+    pub message: M,
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ReliableStats {
+    pub packets_sent: u64,
+    pub messages_sent: u64,
+    pub resent: u64,
+    pub acked: u64,
+    pub delivered: u64,
+    pub duplicates: u64,
+}
+
+struct Pending<M> {
+    message: M,
+    size: usize,
+    last_sent: Option<Instant>,
+}
+
+// ids are u32 so they never wrap in any session we'd realistically run
+struct SendChannel<M> {
+    next_id: u32,
+    pending: BTreeMap<u32, Pending<M>>,
+}
+
+struct RecvChannel<M> {
+    next_id: u32,
+    // arrived ahead of a message we're still missing
+    buffered: BTreeMap<u32, M>,
+}
+
+struct SentPacket {
+    sequence: u32,
+    sent_at: Instant,
+    messages: Vec<(u8, u32)>,
+}
+
+/* one per connection on each end: numbers packets, acks the other side's and keeps channels in order
+   `S` is what we send, `R` what the other end sends us */
+pub struct Endpoint<S, R> {
+    pub session: u64,
+    next_sequence: u32,
+    sent: VecDeque<SentPacket>,
+    // newest sequence we got and the bitfield for the 32 before it
+    remote: u32,
+    remote_bits: u32,
+    send: Vec<SendChannel<S>>,
+    recv: Vec<RecvChannel<R>>,
+    // smoothed round trip and its variance, rfc 6298 style
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    pub stats: ReliableStats,
+}
+
+impl<S: Clone + Serialize, R> Endpoint<S, R> {
+    pub fn new(session: u64, channels: usize) -> Self {
+        Self {
+            session,
+            next_sequence: 1,
+            sent: VecDeque::with_capacity(SENT_PACKET_HISTORY),
+            remote: 0,
+            remote_bits: 0,
+            send: (0..channels).map(|_| SendChannel { next_id: 0, pending: BTreeMap::new() }).collect(),
+            recv: (0..channels).map(|_| RecvChannel { next_id: 0, buffered: BTreeMap::new() }).collect(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            stats: ReliableStats::default(),
+        }
+    }
+
+    // goes out with the next packet and keeps going out until acked
+    pub fn send(&mut self, channel: u8, message: S) {
+        let channel = &mut self.send[channel as usize];
+        let size = bincode::serialized_size(&message).unwrap_or(0) as usize;
+        channel.pending.insert(channel.next_id, Pending { message, size, last_sent: None });
+        channel.next_id += 1;
+    }
+
+    pub fn rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO),
+            None => INITIAL_RTO,
+        }
+    }
+
+    // never sent, or sent longer than a retransmit timeout ago
+    fn due(&self, now: Instant) -> Vec<(u8, u32)> {
+        let rto = self.rto();
+        let mut due = Vec::new();
+
+        for (channel, send) in self.send.iter().enumerate() {
+            let Some(oldest) = send.pending.keys().next() else {
+                continue;
+            };
+            for (id, pending) in send.pending.range(..oldest + SEND_WINDOW) {
+                if pending.last_sent.is_none_or(|last_sent| now - last_sent >= rto) {
+                    due.push((channel as u8, *id));
+                }
+            }
+        }
+
+        due
+    }
+
+    // whether `flush` would send anything right now
+    pub fn has_due(&self) -> bool {
+        !self.due(Instant::now()).is_empty()
+    }
+
+    // wraps `message` with our acks and as many due reliable messages as fit next to it
+    pub fn packet(&mut self, message: Option<S>) -> Packet<S> {
+        let now = Instant::now();
+        let mut reliable = Vec::new();
+        let mut messages = Vec::new();
+        let mut size = message.as_ref().map_or(0, |message| bincode::serialized_size(message).unwrap_or(0) as usize);
+
+        for (channel, id) in self.due(now) {
+            let pending = self.send[channel as usize].pending.get_mut(&id).unwrap();
+            // an empty packet always takes one, a message bigger than the budget still has to go out somehow
+            if size > 0 && size + pending.size > PACKET_BUDGET {
+                break;
+            }
+            size += pending.size;
+
+            if pending.last_sent.is_some() {
+                self.stats.resent += 1;
+            }
+            pending.last_sent = Some(now);
+            reliable.push(ReliableMessage { channel, id, message: pending.message.clone() });
+            messages.push((channel, id));
+            self.stats.messages_sent += 1;
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        if self.sent.len() == SENT_PACKET_HISTORY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(SentPacket { sequence, sent_at: now, messages });
+        self.stats.packets_sent += 1;
+
+        Packet {
+            session: self.session,
+            sequence,
+            ack: self.remote,
+            ack_bits: self.remote_bits,
+            reliable,
+            message,
+        }
+    }
+
+    // packets carrying nothing but reliable messages, until none are due
+    pub fn flush(&mut self) -> Vec<Packet<S>> {
+        let mut packets = Vec::new();
+        while self.has_due() {
+            packets.push(self.packet(None));
+        }
+
+        packets
+    }
+
+    // takes in the other end's acks, returns the messages ready to be handled in order
+    pub fn receive(&mut self, packet: Packet<R>) -> Vec<R> {
+        if packet.sequence != 0 {
+            self.acked(packet.ack, packet.ack_bits);
+            self.record(packet.sequence);
+        }
+
+        let mut delivered = Vec::new();
+        for ReliableMessage { channel, id, message } in packet.reliable {
+            let Some(recv) = self.recv.get_mut(channel as usize) else {
+                continue;
+            };
+            if id < recv.next_id || recv.buffered.contains_key(&id) {
+                self.stats.duplicates += 1;
+                continue;
+            }
+            if id >= recv.next_id + SEND_WINDOW {
+                continue;
+            }
+            recv.buffered.insert(id, message);
+
+            while let Some(message) = recv.buffered.remove(&recv.next_id) {
+                delivered.push(message);
+                recv.next_id += 1;
+                self.stats.delivered += 1;
+            }
+        }
+        delivered.extend(packet.message);
+
+        delivered
+    }
+
+    fn record(&mut self, sequence: u32) {
+        if sequence > self.remote {
+            let shift = sequence - self.remote;
+            // the old newest becomes bit `shift - 1`
+            self.remote_bits = self.remote_bits.checked_shl(shift).unwrap_or(0);
+            if self.remote != 0 {
+                self.remote_bits |= 1u32.checked_shl(shift - 1).unwrap_or(0);
+            }
+            self.remote = sequence;
+        } else if sequence < self.remote {
+            self.remote_bits |= 1u32.checked_shl(self.remote - sequence - 1).unwrap_or(0);
+        }
+    }
+
+    fn acked(&mut self, ack: u32, ack_bits: u32) {
+        let now = Instant::now();
+        let is_acked = |sequence: u32| {
+            sequence == ack || (sequence < ack && ack - sequence <= 32 && ack_bits & (1 << (ack - sequence - 1)) != 0)
+        };
+
+        let mut i = 0;
+        while i < self.sent.len() {
+            if !is_acked(self.sent[i].sequence) {
+                i += 1;
+                continue;
+            }
+            let packet = self.sent.remove(i).unwrap();
+
+            // every packet has its own sequence, so unlike tcp a resend never muddies the sample
+            let sample = now - packet.sent_at;
+            match self.srtt {
+                None => {
+                    self.srtt = Some(sample);
+                    self.rttvar = sample / 2;
+                }
+                Some(srtt) => {
+                    self.rttvar = (self.rttvar * 3 + srtt.abs_diff(sample)) / 4;
+                    self.srtt = Some((srtt * 7 + sample) / 8);
+                }
+            }
+
+            for (channel, id) in packet.messages {
+                if self.send[channel as usize].pending.remove(&id).is_some() {
+                    self.stats.acked += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, the same losses every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn chance(&mut self, p: f64) -> bool {
+            (self.next() % 1000) as f64 / 1000.0 < p
+        }
+    }
+
+    // what a bad link does to one round of packets: drops, duplicates and shuffles them, some arrive a round late
+    fn transmit<M: Clone>(rng: &mut Rng, packets: Vec<Packet<M>>, late: &mut Vec<Packet<M>>) -> Vec<Packet<M>> {
+        let mut arrived = std::mem::take(late);
+        for packet in packets {
+            if rng.chance(0.3) {
+                continue;
+            }
+            if rng.chance(0.15) {
+                arrived.push(packet.clone());
+            }
+            if rng.chance(0.2) {
+                late.push(packet);
+            } else {
+                arrived.push(packet);
+            }
+        }
+        for i in (1..arrived.len()).rev() {
+            arrived.swap(i, rng.next() as usize % (i + 1));
+        }
+
+        arrived
+    }
+
+    // as if a retransmit timeout passed, without waiting for one
+    fn expire<S, R>(endpoint: &mut Endpoint<S, R>) {
+        for channel in &mut endpoint.send {
+            for pending in channel.pending.values_mut() {
+                pending.last_sent = pending.last_sent.and_then(|last_sent| last_sent.checked_sub(MAX_RTO));
+            }
+        }
+    }
+
+    #[test]
+    fn lossy_link_delivers_in_order_exactly_once() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut a: Endpoint<u32, u32> = Endpoint::new(1, 2);
+        let mut b: Endpoint<u32, u32> = Endpoint::new(1, 2);
+        // more than a window on the first channel, so some wait for the front to be acked
+        for i in 0..600 {
+            a.send(0, i);
+        }
+        for i in 0..100 {
+            a.send(1, 10_000 + i);
+        }
+
+        let (mut to_b, mut to_a) = (Vec::new(), Vec::new());
+        let mut delivered = Vec::new();
+        for _ in 0..500 {
+            let mut packets = a.flush();
+            packets.push(a.packet(None));
+            for packet in transmit(&mut rng, packets, &mut to_b) {
+                delivered.extend(b.receive(packet));
+            }
+
+            // b has nothing of its own to say, it still has to ack
+            for packet in transmit(&mut rng, vec![b.packet(None)], &mut to_a) {
+                a.receive(packet);
+            }
+
+            expire(&mut a);
+            if a.send.iter().all(|channel| channel.pending.is_empty()) {
+                break;
+            }
+        }
+
+        let first: Vec<u32> = delivered.iter().copied().filter(|i| *i < 10_000).collect();
+        let second: Vec<u32> = delivered.iter().copied().filter(|i| *i >= 10_000).collect();
+        assert_eq!(first, (0..600).collect::<Vec<_>>());
+        assert_eq!(second, (10_000..10_100).collect::<Vec<_>>());
+        assert_eq!(b.stats.delivered, 700);
+        assert_eq!(a.stats.acked, 700);
+        assert!(a.stats.resent > 0);
+        assert!(b.stats.duplicates > 0);
+    }
+
+    #[test]
+    fn unacked_messages_are_resent_after_the_rto() {
+        let mut a: Endpoint<u32, u32> = Endpoint::new(1, 1);
+        let mut b: Endpoint<u32, u32> = Endpoint::new(1, 1);
+
+        a.send(0, 7);
+        let lost = a.packet(None);
+        assert_eq!(lost.reliable.len(), 1);
+        // still in flight as far as `a` knows
+        assert!(!a.has_due());
+        assert!(a.flush().is_empty());
+
+        std::thread::sleep(INITIAL_RTO);
+        assert!(a.has_due());
+        let packets = a.flush();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(a.stats.resent, 1);
+
+        let resent = packets.into_iter().next().unwrap();
+        assert_eq!(resent.reliable, lost.reliable);
+        assert_eq!(b.receive(resent), vec![7]);
+        // the first copy turning up late is a duplicate, not a second delivery
+        assert!(b.receive(lost).is_empty());
+        assert_eq!(b.stats.duplicates, 1);
+
+        a.receive(b.packet(None));
+        assert_eq!(a.stats.acked, 1);
+        assert!(a.srtt.is_some());
+        std::thread::sleep(a.rto());
+        assert!(!a.has_due());
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::{HashMap, VecDeque};
+
+use chaos_framework::{Quat, Vec3};
+
+use crate::{delta::DeltaDecoder, import::AssetStore, phys::PhysMeshHandle, physics_util::BodyShape, protocol::ServerMessage, sink::SceneSink};
+
+// how far behind the newest snapshot we render, in seconds
+pub const DEFAULT_INTERP_DELAY: f32 = 0.1;
+// a second of snapshots at 60hz, way more than any sane delay needs
+pub const MAX_BUFFERED_SNAPSHOTS: usize = 64;
+
+pub type Poses = HashMap<PhysMeshHandle, (Vec3, Quat)>;
+
+pub struct TransformSnapshot {
+    pub tick: u64,
+    pub bodies: Poses,
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ReplicationStats {
+    pub received: u64,
+    pub bytes: u64,
+    // decoded against a baseline instead of sent whole
+    pub deltas: u64,
+    pub duplicates: u64,
+    // arrived after a newer one but still early enough to be used
+    pub reordered: u64,
+    // arrived after we had already rendered past it
+    pub stale: u64,
+    // deltas against a snapshot we never got or already forgot
+    pub undecodable: u64,
+}
+
+/* the client's copy of the server world, rendered `delay` seconds in the past */
+pub struct Replica {
+    // network id -> shape, every body the server told us about
+    pub bodies: HashMap<PhysMeshHandle, BodyShape>,
+    // sorted by tick, oldest first
+    pub snapshots: VecDeque<TransformSnapshot>,
+    pub delay: f32,
+    pub tick_rate: f32,
+    pub render_tick: Option<f64>,
+    pub stats: ReplicationStats,
+    pub decoder: DeltaDecoder,
+    // where each body was drawn on the last update
+    pub poses: Poses,
+}
+
+impl Replica {
+    pub fn new(delay: f32) -> Self {
+        Self {
+            bodies: HashMap::new(),
+            snapshots: VecDeque::new(),
+            delay,
+            tick_rate: 60.0,
+            render_tick: None,
+            stats: ReplicationStats::default(),
+            decoder: DeltaDecoder::new(),
+            poses: HashMap::new(),
+        }
+    }
+
+    // returns the tick to acknowledge when `message` was a snapshot we could decode
+    pub fn apply(&mut self, sink: &mut dyn SceneSink, message: ServerMessage, assets: &AssetStore) -> Option<u64> {
+        match message {
+            ServerMessage::Welcome { tick_rate, .. } => self.tick_rate = tick_rate,
+            ServerMessage::Spawned { handle, shape } => {
+                // a repeated spawn for a body we already have is just a duplicate
+                if self.bodies.get(&handle) != Some(&shape) {
+                    if self.bodies.contains_key(&handle) {
+                        sink.despawn(handle);
+                    }
+                    sink.spawn(handle, &shape, assets);
+                    self.bodies.insert(handle, shape);
+                }
+            }
+            ServerMessage::Removed { handle } => {
+                if self.bodies.remove(&handle).is_some() {
+                    self.poses.remove(&handle);
+                    sink.despawn(handle);
+                }
+            }
+            ServerMessage::Snapshot { tick, baseline, data, .. } => {
+                self.stats.bytes += data.len() as u64;
+
+                let Ok(poses) = self.decoder.decode(tick, baseline, &data) else {
+                    self.stats.undecodable += 1;
+                    return None;
+                };
+                if baseline.is_some() {
+                    self.stats.deltas += 1;
+                }
+                let bodies = poses.iter()
+                    .map(|(handle, pose)| (*handle, (pose.position(), pose.rotation())))
+                    .collect();
+                self.push_snapshot(TransformSnapshot { tick, bodies });
+
+                return Some(tick);
+            }
+            ServerMessage::Terrain { .. } | ServerMessage::TerrainSamples { .. } | ServerMessage::Rejected { .. } | ServerMessage::Heartbeat | ServerMessage::Disconnected { .. } => {}
+        }
+
+        None
+    }
+
+    // datagrams can show up late, twice or not at all, keep the buffer sorted and deduplicated
+    pub fn push_snapshot(&mut self, snapshot: TransformSnapshot) {
+        self.stats.received += 1;
+
+        if self.render_tick.is_some_and(|render_tick| (snapshot.tick as f64) < render_tick)
+            && self.snapshots.front().is_some_and(|front| snapshot.tick < front.tick)
+        {
+            self.stats.stale += 1;
+            return;
+        }
+
+        let i = self.snapshots.partition_point(|s| s.tick < snapshot.tick);
+        if self.snapshots.get(i).is_some_and(|s| s.tick == snapshot.tick) {
+            self.stats.duplicates += 1;
+            return;
+        }
+        if i < self.snapshots.len() {
+            self.stats.reordered += 1;
+        }
+
+        self.snapshots.insert(i, snapshot);
+        if self.snapshots.len() > MAX_BUFFERED_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn snapshot(&self, tick: u64) -> Option<&TransformSnapshot> {
+        self.snapshots.iter().find(|s| s.tick == tick)
+    }
+
+    pub fn newest_tick(&self) -> Option<u64> {
+        self.snapshots.back().map(|s| s.tick)
+    }
+
+    // advances the render clock by `dt` and moves every replicated body to where it was back then
+    pub fn update(&mut self, sink: &mut dyn SceneSink, dt: f32) {
+        let Some(newest) = self.newest_tick() else {
+            return;
+        };
+
+        let delay_ticks = (self.delay * self.tick_rate) as f64;
+        let target = newest as f64 - delay_ticks;
+        let render_tick = match self.render_tick {
+            // way off (first snapshot, a stall, a big loss burst), just jump
+            Some(render_tick) if (target - render_tick).abs() <= delay_ticks.max(1.0) => {
+                // otherwise drift gently towards the target so motion never snaps
+                let render_tick = render_tick + (dt * self.tick_rate) as f64;
+                render_tick + (target - render_tick) * (dt as f64 * 2.0).min(1.0)
+            }
+            _ => target,
+        };
+        self.render_tick = Some(render_tick);
+
+        // keep exactly one snapshot at or before the render tick to interpolate from
+        while self.snapshots.get(1).is_some_and(|s| s.tick as f64 <= render_tick) {
+            self.snapshots.pop_front();
+        }
+
+        let from = &self.snapshots[0];
+        let to = self.snapshots.get(1).unwrap_or(from);
+        let t = if to.tick > from.tick {
+            ((render_tick - from.tick as f64) / (to.tick - from.tick) as f64).clamp(0.0, 1.0) as f32
+        } else {
+            0.0
+        };
+
+        for handle in self.bodies.keys() {
+            let pose = match (from.bodies.get(handle), to.bodies.get(handle)) {
+                (Some(a), Some(b)) => (a.0.lerp(b.0, t), a.1.slerp(b.1, t)),
+                (Some(pose), None) | (None, Some(pose)) => *pose,
+                (None, None) => continue,
+            };
+
+            sink.transform(*handle, pose.0, pose.1);
+            self.poses.insert(*handle, pose);
+        }
+    }
+}
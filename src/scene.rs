@@ -0,0 +1,558 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, fs, io, ops::RangeInclusive, path::{Path, PathBuf}};
+
+use chaos_framework::{Quat, Vec3};
+use rapier3d::{na::{Quaternion, UnitQuaternion}, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{articulation::{BodyGroup, GroupKind}, import::{ImportError, MeshAsset}, joint::JointDesc, phys::{PhysMeshHandle, PhysicalWorld, SimulationConfig, World}, physics_util::{BodyShape, PhysMesh}, sink::SceneSink, terrain::{Terrain, TerrainDesc, TerrainError}};
+
+// bump this whenever the layout below changes
+pub const SCENE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct SceneFile {
+    pub version: u32,
+    pub config: SimulationConfig,
+    pub terrain: Option<TerrainDesc>,
+    pub bodies: Vec<SceneBody>,
+    pub joints: Vec<SceneJoint>,
+    // multibody joints, `body2` hangs off `body1`
+    pub articulations: Vec<SceneJoint>,
+    pub groups: Vec<SceneGroup>,
+    // where the files behind imported bodies were read from
+    pub assets: Vec<SceneAsset>,
+}
+
+// the most a body taken from a client may have, past these it's a typo or an attempt to stall the solver
+pub const MAX_BODY_SIZE: f32 = 100.0;
+pub const MAX_BODY_SPEED: f32 = 1000.0;
+pub const MAX_DENSITY: f32 = 1000.0;
+pub const MAX_MASS: f32 = 1.0e6;
+pub const MAX_FRICTION: f32 = 10.0;
+// enough to take the heaviest body allowed to the fastest speed allowed
+pub const MAX_IMPULSE: f32 = MAX_MASS * MAX_BODY_SPEED;
+
+/* everything needed to spawn a body, the same in a scene file, over the network and in the editor */
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneBody {
+    pub shape: BodyShape,
+    pub body_type: RigidBodyType,
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub linvel: Vec3,
+    pub angvel: Vec3,
+    pub density: f32,
+    // overrides `density` when set
+    pub mass: Option<f32>,
+    pub friction: f32,
+    pub restitution: f32,
+    // contact force events once a contact pushes harder than this, none when unset
+    pub contact_force_threshold: Option<f32>,
+}
+
+// bodies are referenced by their index in `SceneFile::bodies`
+#[derive(Serialize, Deserialize)]
+pub struct SceneJoint {
+    pub body1: usize,
+    pub body2: usize,
+    pub joint: JointDesc,
+}
+
+// the file is read again on load, if it changed since the bodies follow the new contents
+#[derive(Serialize, Deserialize)]
+pub struct SceneAsset {
+    pub asset: MeshAsset,
+    pub path: PathBuf,
+}
+
+// bodies by index again, root first
+#[derive(Serialize, Deserialize)]
+pub struct SceneGroup {
+    pub kind: GroupKind,
+    pub bodies: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(io::Error),
+    Parse(ron::error::SpannedError),
+    Serialize(ron::Error),
+    UnsupportedVersion(u32),
+    MissingCollider(PhysMeshHandle),
+    BadJoint(usize),
+    BadArticulation(usize),
+    BadGroup(usize),
+    // a body whose imported mesh isn't in the scene's asset list
+    UnknownAsset(usize),
+    Terrain(TerrainError),
+    Import(ImportError),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(err) => write!(f, "io error: {err}"),
+            SceneError::Parse(err) => write!(f, "could not parse scene: {err}"),
+            SceneError::Serialize(err) => write!(f, "could not write scene: {err}"),
+            SceneError::UnsupportedVersion(version) => {
+                write!(f, "scene version {version} is not supported (expected {SCENE_VERSION})")
+            }
+            SceneError::MissingCollider(handle) => write!(f, "body {:?} has no collider", handle),
+            SceneError::BadJoint(i) => write!(f, "joint {i} points at a body that does not exist"),
+            SceneError::BadArticulation(i) => write!(f, "articulation {i} points at a body that does not exist, already hangs off another or closes a loop"),
+            SceneError::BadGroup(i) => write!(f, "group {i} points at a body that does not exist"),
+            SceneError::UnknownAsset(i) => write!(f, "body {i} uses an imported mesh the scene doesn't list"),
+            SceneError::Terrain(err) => write!(f, "{err}"),
+            SceneError::Import(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+// a field of a `SceneBody` that is NaN, infinite or out of range
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BodyError {
+    pub field: &'static str,
+    pub value: f32,
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "body {} can't be {}", self.field, self.value)
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+fn check(field: &'static str, value: f32, range: RangeInclusive<f32>) -> Result<(), BodyError> {
+    if value.is_finite() && range.contains(&value) {
+        Ok(())
+    } else {
+        Err(BodyError { field, value })
+    }
+}
+
+impl From<io::Error> for SceneError {
+    fn from(err: io::Error) -> Self {
+        SceneError::Io(err)
+    }
+}
+
+fn to_vec3(v: &Vector<Real>) -> Vec3 {
+    Vec3::new(v.x, v.y, v.z)
+}
+
+// the first articulation that points nowhere, gives a body a second parent or closes a loop
+fn bad_articulation(scene: &SceneFile) -> Option<usize> {
+    let mut parents = HashMap::new();
+    for (i, joint) in scene.articulations.iter().enumerate() {
+        if joint.body1 >= scene.bodies.len() || joint.body2 >= scene.bodies.len() || parents.insert(joint.body2, joint.body1).is_some() {
+            return Some(i);
+        }
+
+        // walking up from the new parent must never come back around to the child
+        let mut body = Some(joint.body1);
+        while let Some(current) = body {
+            if current == joint.body2 {
+                return Some(i);
+            }
+            body = parents.get(&current).copied();
+        }
+    }
+
+    None
+}
+
+// where a client may put a body, checked the same way for a spawn and a move
+pub fn validate_position(position: Vec3) -> Result<(), BodyError> {
+    for value in position.to_array() {
+        check("position", value, f32::MIN..=f32::MAX)?;
+    }
+
+    Ok(())
+}
+
+// a client's kick, a NaN in here would spread through every body it touches
+pub fn validate_impulse(impulse: Vec3) -> Result<(), BodyError> {
+    for value in impulse.to_array() {
+        check("impulse", value, f32::MIN..=f32::MAX)?;
+    }
+    check("impulse", impulse.length(), 0.0..=MAX_IMPULSE)
+}
+
+impl SceneBody {
+    // a body at rest with the same material the interactive spawners use
+    pub fn new(shape: BodyShape, body_type: RigidBodyType, position: Vec3) -> Self {
+        Self {
+            shape,
+            body_type,
+            position,
+            rotation: Quat::IDENTITY,
+            linvel: Vec3::ZERO,
+            angvel: Vec3::ZERO,
+            density: 1.0,
+            mass: None,
+            friction: 0.5,
+            restitution: 0.3,
+            contact_force_threshold: None,
+        }
+    }
+
+    // anything rapier would choke on or that could never settle, checked before a client's body is spawned
+    pub fn validate(&self) -> Result<(), BodyError> {
+        let size = f32::MIN_POSITIVE..=MAX_BODY_SIZE;
+        match self.shape {
+            BodyShape::Sphere { radius } => check("radius", radius, size)?,
+            BodyShape::Cuboid { half_extents } => {
+                for value in half_extents.to_array() {
+                    check("half extent", value, size.clone())?;
+                }
+            }
+            BodyShape::Capsule { half_height, radius } | BodyShape::Cylinder { half_height, radius } | BodyShape::Cone { half_height, radius } => {
+                check("half height", half_height, size.clone())?;
+                check("radius", radius, size)?;
+            }
+            BodyShape::RoundCuboid { half_extents, border_radius } => {
+                for value in half_extents.to_array() {
+                    check("half extent", value, size.clone())?;
+                }
+                check("border radius", border_radius, size)?;
+            }
+            BodyShape::Imported { scale, .. } => check("scale", scale, size)?,
+        }
+
+        validate_position(self.position)?;
+        // normalized on spawn, which only works if there is a direction to keep
+        check("rotation length", self.rotation.length(), f32::EPSILON..=f32::MAX)?;
+        check("speed", self.linvel.length(), 0.0..=MAX_BODY_SPEED)?;
+        check("angular speed", self.angvel.length(), 0.0..=MAX_BODY_SPEED)?;
+        check("density", self.density, f32::MIN_POSITIVE..=MAX_DENSITY)?;
+        if let Some(mass) = self.mass {
+            check("mass", mass, f32::MIN_POSITIVE..=MAX_MASS)?;
+        }
+        check("friction", self.friction, 0.0..=MAX_FRICTION)?;
+        check("restitution", self.restitution, 0.0..=1.0)?;
+        if let Some(threshold) = self.contact_force_threshold {
+            check("contact force threshold", threshold, 0.0..=f32::MAX)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn capture(phys_world: &PhysicalWorld, handle: PhysMeshHandle, phys_mesh: &PhysMesh) -> Result<Self, SceneError> {
+        let body = &phys_world.rigid_body_set[phys_mesh.body];
+        let collider = body.colliders().first()
+            .and_then(|c| phys_world.collider_set.get(*c))
+            .ok_or(SceneError::MissingCollider(handle))?;
+        let rot = body.rotation();
+
+        Ok(Self {
+            shape: phys_mesh.shape,
+            body_type: body.body_type(),
+            position: to_vec3(body.translation()),
+            rotation: Quat::from_xyzw(rot.i, rot.j, rot.k, rot.w),
+            linvel: to_vec3(body.linvel()),
+            angvel: to_vec3(body.angvel()),
+            density: collider.density(),
+            mass: None,
+            friction: collider.friction(),
+            restitution: collider.restitution(),
+            contact_force_threshold: collider.active_events().contains(ActiveEvents::CONTACT_FORCE_EVENTS)
+                .then(|| collider.contact_force_event_threshold()),
+        })
+    }
+
+    // `shape` is `self.shape` built, see `BodyShape::shared_shape`
+    pub fn spawn(&self, phys_world: &mut PhysicalWorld, shape: SharedShape) -> RigidBodyHandle {
+        let (p, r) = (self.position, self.rotation);
+        let body_type = if self.shape.fixed_only() { RigidBodyType::Fixed } else { self.body_type };
+        let rb = RigidBodyBuilder::new(body_type)
+            .position(Isometry::from_parts(
+                vector![p.x, p.y, p.z].into(),
+                UnitQuaternion::new_normalize(Quaternion::new(r.w, r.x, r.y, r.z)),
+            ))
+            .linvel(vector![self.linvel.x, self.linvel.y, self.linvel.z])
+            .angvel(vector![self.angvel.x, self.angvel.y, self.angvel.z])
+            .ccd_enabled(phys_world.config.ccd)
+            .build();
+
+        let force_events = if self.contact_force_threshold.is_some() { ActiveEvents::CONTACT_FORCE_EVENTS } else { ActiveEvents::empty() };
+        let collider = ColliderBuilder::new(shape)
+            .friction(self.friction)
+            .restitution(self.restitution)
+            .active_events(ActiveEvents::COLLISION_EVENTS | force_events)
+            .contact_force_event_threshold(self.contact_force_threshold.unwrap_or(0.0));
+        let collider = match self.mass {
+            Some(mass) => collider.mass(mass),
+            None => collider.density(self.density),
+        }
+        .build();
+
+        let body_handle = phys_world.rigid_body_set.insert(rb);
+        phys_world.collider_set.insert_with_parent(collider, body_handle, &mut phys_world.rigid_body_set);
+
+        body_handle
+    }
+}
+
+impl World {
+    pub async fn capture_scene(&self) -> Result<SceneFile, SceneError> {
+        let phys_world = self.phys_world.lock().await;
+
+        // sorted so the same world always produces the same file
+        let mut meshes: Vec<(&PhysMeshHandle, &PhysMesh)> = self.phys_meshes.iter().collect();
+        meshes.sort_by_key(|(handle, _)| handle.id);
+
+        let mut bodies = Vec::with_capacity(meshes.len());
+        let mut indices = HashMap::new();
+        for (&handle, phys_mesh) in meshes {
+            if !phys_world.rigid_body_set.contains(phys_mesh.body) {
+                continue;
+            }
+
+            indices.insert(phys_mesh.body, bodies.len());
+            bodies.push(SceneBody::capture(&phys_world, handle, phys_mesh)?);
+        }
+
+        let joints = phys_world.impulse_joint_set.iter()
+            .filter_map(|(handle, joint)| {
+                Some(SceneJoint {
+                    body1: *indices.get(&joint.body1)?,
+                    body2: *indices.get(&joint.body2)?,
+                    joint: *phys_world.joints.get(&handle)?,
+                })
+            })
+            .collect();
+
+        let mut multibody_joints: Vec<_> = phys_world.multibody_joints.iter().collect();
+        multibody_joints.sort_by_key(|(handle, _)| handle.into_raw_parts());
+        let articulations = multibody_joints.into_iter()
+            .filter_map(|(handle, desc)| {
+                let (multibody, link) = phys_world.multibody_joint_set.get(*handle)?;
+                let link = multibody.link(link)?;
+                let parent = multibody.link(link.parent_id()?)?;
+
+                Some(SceneJoint {
+                    body1: *indices.get(&parent.rigid_body_handle())?,
+                    body2: *indices.get(&link.rigid_body_handle())?,
+                    joint: *desc,
+                })
+            })
+            .collect();
+
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort_by_key(|(handle, _)| handle.0);
+        let groups = groups.into_iter()
+            .map(|(_, group)| SceneGroup {
+                kind: group.kind,
+                bodies: group.bodies.iter()
+                    .filter_map(|mesh| indices.get(&self.phys_meshes.get(mesh)?.body).copied())
+                    .collect(),
+            })
+            .filter(|group| !group.bodies.is_empty())
+            .collect();
+
+        let assets: BTreeMap<u64, SceneAsset> = bodies.iter()
+            .filter_map(|body| match body.shape {
+                BodyShape::Imported { asset, .. } => Some(SceneAsset { asset, path: self.assets.path(asset)?.to_path_buf() }),
+                _ => None,
+            })
+            .map(|entry| (entry.asset.0, entry))
+            .collect();
+
+        Ok(SceneFile {
+            version: SCENE_VERSION,
+            config: phys_world.config,
+            terrain: self.terrain.as_ref().map(|terrain| terrain.desc.clone()),
+            bodies,
+            joints,
+            articulations,
+            groups,
+            assets: assets.into_values().collect(),
+        })
+    }
+
+    pub async fn save_scene(&self, path: impl AsRef<Path>) -> Result<(), SceneError> {
+        let scene = self.capture_scene().await?;
+        let text = ron::ser::to_string_pretty(&scene, ron::ser::PrettyConfig::default())
+            .map_err(SceneError::Serialize)?;
+
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub async fn load_scene(&mut self, sink: &mut dyn SceneSink, path: impl AsRef<Path>) -> Result<(), SceneError> {
+        let text = fs::read_to_string(path)?;
+        let scene: SceneFile = ron::from_str(&text).map_err(SceneError::Parse)?;
+
+        self.apply_scene(sink, &scene).await
+    }
+
+    // throws away every body in the world and rebuilds it from `scene`
+    pub async fn apply_scene(&mut self, sink: &mut dyn SceneSink, scene: &SceneFile) -> Result<(), SceneError> {
+        if scene.version != SCENE_VERSION {
+            return Err(SceneError::UnsupportedVersion(scene.version));
+        }
+        if let Some(i) = scene.joints.iter().position(|j| j.body1 >= scene.bodies.len() || j.body2 >= scene.bodies.len() || j.body1 == j.body2) {
+            return Err(SceneError::BadJoint(i));
+        }
+        if let Some(i) = bad_articulation(scene) {
+            return Err(SceneError::BadArticulation(i));
+        }
+        if let Some(i) = scene.groups.iter().position(|g| g.bodies.is_empty() || g.bodies.iter().any(|body| *body >= scene.bodies.len())) {
+            return Err(SceneError::BadGroup(i));
+        }
+
+        // before anything is torn down, so a missing heightmap leaves the world as it was
+        let terrain = scene.terrain.clone().map(Terrain::generate).transpose().map_err(SceneError::Terrain)?;
+
+        // same for the imported files, and every collider is built before the physics world is locked
+        let mut loaded = HashMap::new();
+        for entry in &scene.assets {
+            loaded.insert(entry.asset, self.assets.load(&entry.path).map_err(SceneError::Import)?);
+        }
+        let mut bodies = Vec::with_capacity(scene.bodies.len());
+        for (i, body) in scene.bodies.iter().enumerate() {
+            let mut body = *body;
+            if let BodyShape::Imported { asset, .. } = &mut body.shape {
+                *asset = *loaded.get(asset).ok_or(SceneError::UnknownAsset(i))?;
+            }
+            let shape = body.shape.shared_shape(&mut self.assets).map_err(|_| SceneError::UnknownAsset(i))?;
+            bodies.push((body, shape));
+        }
+
+        let handles: Vec<PhysMeshHandle> = self.phys_meshes.keys().copied().collect();
+        for handle in handles {
+            // every key is live, nothing can go wrong here
+            let _ = self.destroy(sink, handle).await;
+        }
+
+        self.replace_terrain(sink, terrain).await;
+
+        self.phys_world.lock().await.set_config(scene.config);
+        self.config = scene.config;
+
+        let mut body_handles = Vec::with_capacity(scene.bodies.len());
+        let mut mesh_handles = Vec::with_capacity(scene.bodies.len());
+        for (body, shape) in bodies {
+            let body_handle = body.spawn(&mut *self.phys_world.lock().await, shape);
+            mesh_handles.push(self.insert_phys_mesh(sink, PhysMesh::new(body_handle, body.shape)).await);
+            body_handles.push(body_handle);
+        }
+
+        for group in &scene.groups {
+            let bodies = group.bodies.iter().map(|i| mesh_handles[*i]).collect();
+            self.insert_group(BodyGroup { kind: group.kind, bodies });
+        }
+
+        let mut phys_world = self.phys_world.lock().await;
+        for joint in &scene.joints {
+            phys_world.add_joint(body_handles[joint.body1], body_handles[joint.body2], joint.joint);
+        }
+        for joint in &scene.articulations {
+            // `bad_articulation` already turned down everything rapier would
+            phys_world.add_multibody_joint(body_handles[joint.body1], body_handles[joint.body2], joint.joint);
+        }
+        // the angles aren't saved, they are read back off where the bodies were
+        let children: Vec<_> = scene.articulations.iter().map(|joint| body_handles[joint.body2]).collect();
+        phys_world.bend_to_bodies(&children);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_framework::vec3;
+
+    use super::*;
+    use crate::{articulation::ChainDesc, joint::JointKind, sink::SceneRecorder, terrain::HeightSource};
+
+    fn to_ron(scene: &SceneFile) -> String {
+        ron::ser::to_string_pretty(scene, ron::ser::PrettyConfig::default()).unwrap()
+    }
+
+    // a bit of everything a scene can hold
+    async fn furnished_world(sink: &mut SceneRecorder) -> World {
+        let mut world = World::new().await;
+        let terrain = TerrainDesc { source: HeightSource::Noise { seed: 3, octaves: 2, frequency: 4.0 }, resolution: 16, size: vec3(30.0, 2.0, 30.0) };
+        world.set_terrain(sink, Some(terrain)).await.unwrap();
+
+        let crate_body = SceneBody {
+            rotation: Quat::from_rotation_y(0.3),
+            mass: Some(2.0),
+            contact_force_threshold: Some(10.0),
+            ..SceneBody::new(BodyShape::Cuboid { half_extents: vec3(0.5, 0.25, 1.0) }, RigidBodyType::Dynamic, vec3(0.0, 5.0, 0.0))
+        };
+        let a = world.spawn(sink, &crate_body).await.unwrap();
+        let b = world.spawn(sink, &SceneBody::new(BodyShape::Sphere { radius: 0.5 }, RigidBodyType::Dynamic, vec3(2.0, 5.0, 0.0))).await.unwrap();
+        let desc = JointDesc { anchor1: vec3(1.0, 0.0, 0.0), anchor2: vec3(-1.0, 0.0, 0.0), break_force: Some(100.0), ..JointDesc::new(JointKind::Revolute) };
+        world.add_joint(a, b, desc).await.unwrap();
+
+        let chain = ChainDesc { links: 4, ..ChainDesc::default() };
+        world.spawn_articulated(sink, GroupKind::Chain, &chain, vec3(-5.0, 8.0, 0.0)).await.unwrap();
+
+        world
+    }
+
+    #[tokio::test]
+    async fn saved_scenes_load_back_the_same() {
+        let mut sink = SceneRecorder::new();
+        let mut world = furnished_world(&mut sink).await;
+        for _ in 0..5 {
+            world.step().await;
+        }
+
+        let path = std::env::temp_dir().join(format!("physp-scene-{}.ron", std::process::id()));
+        world.save_scene(&path).await.unwrap();
+        let saved = world.capture_scene().await.unwrap();
+        assert!(saved.terrain.is_some());
+        assert_eq!(saved.joints.len(), 1);
+        assert_eq!(saved.articulations.len(), 4);
+        assert_eq!(saved.groups.len(), 1);
+
+        let mut loaded = World::new().await;
+        let mut loaded_sink = SceneRecorder::new();
+        let result = loaded.load_scene(&mut loaded_sink, &path).await;
+        let _ = fs::remove_file(&path);
+        result.unwrap();
+
+        // rapier renormalises rotations on the way in, the last bit can move
+        let mut reloaded = loaded.capture_scene().await.unwrap();
+        assert_eq!(reloaded.bodies.len(), saved.bodies.len());
+        for (body, original) in reloaded.bodies.iter_mut().zip(&saved.bodies) {
+            assert!(body.rotation.abs_diff_eq(original.rotation, 1e-6), "{} vs {}", body.rotation, original.rotation);
+            body.rotation = original.rotation;
+        }
+        assert_eq!(to_ron(&reloaded), to_ron(&saved));
+        assert_eq!(loaded.phys_meshes.len(), world.phys_meshes.len());
+        assert_eq!(loaded.groups.len(), 1);
+        assert_eq!(loaded_sink.bodies.len(), sink.bodies.len());
+    }
+
+    #[test]
+    fn client_moves_and_kicks_are_checked() {
+        assert_eq!(validate_position(vec3(1.0, -2.0, 3.0)), Ok(()));
+        assert!(validate_position(vec3(0.0, f32::NAN, 0.0)).is_err());
+        assert!(validate_position(vec3(f32::INFINITY, 0.0, 0.0)).is_err());
+
+        assert_eq!(validate_impulse(vec3(0.0, 40.0, 0.0)), Ok(()));
+        assert!(validate_impulse(vec3(f32::NAN, 0.0, 0.0)).is_err());
+        assert!(validate_impulse(vec3(0.0, 0.0, f32::NEG_INFINITY)).is_err());
+        // every component fine on its own, the length isn't
+        assert!(validate_impulse(Vec3::splat(MAX_IMPULSE)).is_err());
+    }
+
+    #[tokio::test]
+    async fn other_versions_are_refused_before_anything_changes() {
+        let mut sink = SceneRecorder::new();
+        let mut world = furnished_world(&mut sink).await;
+        let mut scene = world.capture_scene().await.unwrap();
+        let before = to_ron(&scene);
+        scene.version = SCENE_VERSION + 1;
+
+        assert!(matches!(world.apply_scene(&mut sink, &scene).await, Err(SceneError::UnsupportedVersion(version)) if version == SCENE_VERSION + 1));
+        assert_eq!(to_ron(&world.capture_scene().await.unwrap()), before);
+    }
+}
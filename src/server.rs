@@ -1,28 +1,511 @@
-use std::io;
+use std::{collections::{HashMap, HashSet, VecDeque}, io, net::SocketAddr, time::Duration};
 
-use tokio::net::UdpSocket;
+use chaos_framework::{vec3, Quat};
+use rapier3d::prelude::*;
+use tokio::time::{self, MissedTickBehavior};
 
-pub struct Server {
-    pub socket: UdpSocket,
+use crate::{
+    connection::{random_id, Connection, ConnectionEvent, DEFAULT_MAX_CLIENTS, DEFAULT_TIMEOUT, HEARTBEAT_INTERVAL},
+    headless::{flag_value, positive_flag_value, ArgError},
+    netsim::{NetConditions, NetSocket},
+    delta::{BodyState, DeltaEncoder},
+    phys::{PhysMeshHandle, PhysicsCommand, World},
+    physics_util::BodyShape,
+    protocol::{
+        decode, encode, terrain_messages, ClientMessage, ClientPacket, DisconnectReason, NetError, ServerMessage, ServerPacket,
+        CHANNEL_CONTROL, CHANNEL_WORLD, MAX_PACKET_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, RELIABLE_CHANNELS,
+    },
+    quantize::QuantizedPose,
+    reliable::{Endpoint, Packet},
+    scene::{validate_impulse, validate_position, SceneBody},
+    sink::{SceneEvent, SceneRecorder},
+    terrain::TerrainDesc,
+};
+
+pub struct ServerOptions {
     pub addr: String,
+    pub hz: f32,
+    pub send_rate: f32,
+    pub max_clients: usize,
+    pub timeout: Duration,
+    // give every client a ball of its own while it's connected
+    pub player_bodies: bool,
+    // a bad network to hide behind, for testing
+    pub conditions: Option<NetConditions>,
+    pub terrain: TerrainDesc,
+}
+
+impl ServerOptions {
+    // returns None unless `--server` was passed
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Option<Self>, ArgError> {
+        let args: Vec<String> = args.collect();
+        if !args.iter().any(|arg| arg == "--server") {
+            return Ok(None);
+        }
+
+        // seconds on the command line, past what a `Duration` holds is as wrong as zero
+        let timeout = match positive_flag_value(&args, "--timeout")? {
+            Some(secs) => Duration::try_from_secs_f32(secs).map_err(|_| ArgError { flag: "--timeout".to_string(), value: secs.to_string() })?,
+            None => DEFAULT_TIMEOUT,
+        };
+
+        Ok(Some(Self {
+            addr: flag_value(&args, "--addr")?.unwrap_or_else(|| "127.0.0.1:4040".to_string()),
+            hz: positive_flag_value(&args, "--hz")?.unwrap_or(60.0),
+            send_rate: positive_flag_value(&args, "--send-rate")?.unwrap_or(20.0),
+            max_clients: flag_value(&args, "--max-clients")?.unwrap_or(DEFAULT_MAX_CLIENTS),
+            timeout,
+            player_bodies: args.iter().any(|arg| arg == "--players"),
+            conditions: NetConditions::from_args(&args)?,
+            terrain: TerrainDesc::from_args(&args)?,
+        }))
+    }
+}
+
+pub struct RemoteClient {
+    pub connection: Connection,
+    pub endpoint: Endpoint<ServerMessage, ClientMessage>,
+    // what the client sent with its connect, a repeat of it just gets the welcome again
+    pub nonce: u64,
+    pub player: Option<PhysMeshHandle>,
+    pub encoder: DeltaEncoder,
+    // newest predicted input we got, and the newest one a physics step has actually seen
+    pub received_input: u32,
+    pub applied_input: u32,
+}
+
+/* owns the authoritative world, clients only ever ask it to do things */
+pub struct Server {
+    pub socket: NetSocket,
+    pub world: World,
+    pub clients: HashMap<SocketAddr, RemoteClient>,
+    // transform broadcasts per second, independent of the physics rate
+    pub send_rate: f32,
+    pub max_clients: usize,
+    pub timeout: Duration,
+    pub player_bodies: bool,
+    // connects and disconnects not yet reacted to, see `handle_connection_events`
+    pub events: VecDeque<ConnectionEvent>,
+    recorder: SceneRecorder,
 }
 
 impl Server {
-    pub async fn new(addr: &str) -> io::Result<Self> {
-        let socket = UdpSocket::bind(addr).await?;
-        println!("Server is listening on {:?}", addr);
+    pub async fn new(addr: &str, world: World, conditions: Option<NetConditions>) -> io::Result<Self> {
+        let socket = NetSocket::bind(addr, conditions).await?;
+        println!("Server is listening on {:?}", socket.local_addr()?);
+        if let Some(conditions) = conditions {
+            println!("simulating {conditions:?}");
+        }
 
-        Ok(Self { socket, addr: addr.to_string() })
+        Ok(Self {
+            socket,
+            world,
+            clients: HashMap::new(),
+            send_rate: 20.0,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            timeout: DEFAULT_TIMEOUT,
+            player_bodies: false,
+            events: VecDeque::new(),
+            recorder: SceneRecorder::new(),
+        })
     }
 
-    pub async fn run(&mut self) -> io::Result<()> {
-        let mut buf = vec![0u8; 1024]; 
+    pub async fn run(&mut self) -> Result<(), NetError> {
+        let mut physics = time::interval(Duration::from_secs_f32(self.world.timestep.dt()));
+        let mut broadcast = time::interval(Duration::from_secs_f32(1.0 / self.send_rate));
+        broadcast.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut keepalive = time::interval(HEARTBEAT_INTERVAL / 2);
+        keepalive.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
 
         loop {
-            let (len, addr) = self.socket.recv_from(&mut buf).await?;
-            println!("Received {} bytes from {}", len, addr);
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, addr)) => match decode(&buf[..len]) {
+                        Ok(packet) => self.handle_packet(addr, packet).await?,
+                        Err(err) => eprintln!("dropping packet from {addr}: {err}"),
+                    },
+                    // e.g. icmp port unreachable from a client that went away, not our problem
+                    Err(err) => eprintln!("recv failed: {err}"),
+                },
+                _ = physics.tick() => {
+                    self.world.step().await;
+
+                    // everything queued before the step went into it
+                    for client in self.clients.values_mut() {
+                        client.applied_input = client.received_input;
+                    }
+
+                    // reliable messages nothing else carried, and the ones due for a resend
+                    self.flush_reliable().await?;
+                }
+                _ = broadcast.tick() => self.broadcast_transforms().await?,
+                _ = keepalive.tick() => self.keepalive().await?,
+            }
+
+            self.handle_connection_events().await;
+            self.flush_events().await?;
+        }
+    }
+
+    async fn handle_packet(&mut self, addr: SocketAddr, packet: ClientPacket) -> Result<(), NetError> {
+        if let Some(ClientMessage::Connect { min_version, max_version, nonce }) = packet.message {
+            return self.accept(addr, min_version, max_version, nonce).await;
+        }
+
+        // anything else needs a session, and it has to be the one this address was given
+        let messages = match self.clients.get_mut(&addr) {
+            Some(client) if client.connection.session == packet.session => {
+                client.connection.heard();
+                client.endpoint.receive(packet)
+            }
+            _ => {
+                // a client we timed out (or that never connected) learns it has to start over
+                let reason = DisconnectReason::UnknownSession;
+                return self.send_to(addr, &ServerMessage::Disconnected { reason }).await;
+            }
+        };
+
+        for message in messages {
+            self.handle_message(addr, message).await?;
+        }
+
+        Ok(())
+    }
+
+    // the connect half of the handshake, answered with a welcome or a reason why not
+    async fn accept(&mut self, addr: SocketAddr, min_version: u32, max_version: u32, nonce: u64) -> Result<(), NetError> {
+        match self.clients.get(&addr) {
+            // our welcome got lost, send it again
+            Some(client) if client.nonce == nonce => return self.welcome(addr).await,
+            // same address, new nonce: the client restarted without saying goodbye
+            Some(_) => self.disconnect(addr, DisconnectReason::Left),
+            None => {}
+        }
+
+        let version = max_version.min(PROTOCOL_VERSION);
+        if version < min_version.max(MIN_PROTOCOL_VERSION) {
+            let reason = DisconnectReason::VersionMismatch { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION };
+            return self.send_to(addr, &ServerMessage::Disconnected { reason }).await;
+        }
+        if self.clients.len() >= self.max_clients {
+            return self.send_to(addr, &ServerMessage::Disconnected { reason: DisconnectReason::ServerFull }).await;
+        }
+
+        let session = random_id();
+        self.clients.insert(addr, RemoteClient {
+            connection: Connection::new(session, version, self.timeout),
+            endpoint: Endpoint::new(session, RELIABLE_CHANNELS),
+            nonce,
+            player: None,
+            encoder: DeltaEncoder::default(),
+            received_input: 0,
+            applied_input: 0,
+        });
+        self.events.push_back(ConnectionEvent::Connected { session, addr });
+        println!("{addr} connected, session {session:016x}, protocol {version}");
+
+        // the player body has to exist before the welcome names it
+        self.handle_connection_events().await;
+        self.welcome(addr).await?;
+
+        // only once, a repeated welcome must not queue them all again
+        for message in terrain_messages(self.world.terrain.as_ref().map(|terrain| &terrain.desc)) {
+            self.send_reliable(addr, CHANNEL_WORLD, message);
+        }
+        let mut bodies: Vec<(PhysMeshHandle, BodyShape)> = self.world.phys_meshes.iter()
+            .map(|(handle, phys_mesh)| (*handle, phys_mesh.shape))
+            .collect();
+        bodies.sort_by_key(|(handle, _)| handle.id);
+        for (handle, shape) in bodies {
+            self.send_reliable(addr, CHANNEL_WORLD, ServerMessage::Spawned { handle, shape });
+        }
+
+        Ok(())
+    }
+
+    async fn welcome(&mut self, addr: SocketAddr) -> Result<(), NetError> {
+        let Some(client) = self.clients.get(&addr) else {
+            return Ok(());
+        };
+        let welcome = ServerMessage::Welcome {
+            session: client.connection.session,
+            version: client.connection.version,
+            timeout: self.timeout.as_secs_f32(),
+            tick_rate: self.world.timestep.hz,
+            send_rate: self.send_rate,
+            player: client.player,
+        };
+        self.send_to(addr, &welcome).await
+    }
+
+    // forgets the client and queues the event, the caller decides whether it still gets told
+    fn disconnect(&mut self, addr: SocketAddr, reason: DisconnectReason) {
+        if let Some(client) = self.clients.remove(&addr) {
+            println!("{addr} disconnected: {reason}");
+            self.events.push_back(ConnectionEvent::Disconnected {
+                session: client.connection.session,
+                addr,
+                reason,
+                player: client.player,
+            });
+        }
+    }
+
+    // drops clients we haven't heard from and pings the ones we have nothing to send
+    async fn keepalive(&mut self) -> Result<(), NetError> {
+        let timed_out: Vec<SocketAddr> = self.clients.iter()
+            .filter(|(_, client)| client.connection.timed_out())
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in timed_out {
+            self.disconnect(addr, DisconnectReason::TimedOut);
+            // in case it's still listening and only its packets get lost
+            self.send_to(addr, &ServerMessage::Disconnected { reason: DisconnectReason::TimedOut }).await?;
+        }
 
-            self.socket.send_to(&buf[..len], &addr).await?;
+        let idle: Vec<SocketAddr> = self.clients.iter()
+            .filter(|(_, client)| client.connection.needs_heartbeat())
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in idle {
+            self.send_to(addr, &ServerMessage::Heartbeat).await?;
         }
+
+        Ok(())
     }
-}
\ No newline at end of file
+
+    // the world's reaction to clients coming and going, a ball per player when enabled
+    async fn handle_connection_events(&mut self) {
+        while let Some(event) = self.events.pop_front() {
+            match event {
+                ConnectionEvent::Connected { addr, .. } => {
+                    if !self.player_bodies {
+                        continue;
+                    }
+                    let shape = BodyShape::Sphere { radius: 0.5 };
+                    // spread out a little so they don't all start inside each other
+                    let x = self.clients.len() as f32 * 1.5;
+                    let position = vec3(x, self.world.ground_height(x, 0.0) + 5.0, 0.0);
+                    let Ok(handle) = self.world.spawn(&mut self.recorder, &SceneBody::new(shape, RigidBodyType::Dynamic, position)).await else {
+                        continue;
+                    };
+                    if let Some(client) = self.clients.get_mut(&addr) {
+                        client.player = Some(handle);
+                    }
+                }
+                ConnectionEvent::Disconnected { player, .. } => {
+                    // already gone if someone removed it by hand
+                    if let Some(player) = player {
+                        let _ = self.world.destroy(&mut self.recorder, player).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, addr: SocketAddr, message: ClientMessage) -> Result<(), NetError> {
+        match message {
+            // handled before we get here
+            ClientMessage::Connect { .. } | ClientMessage::Heartbeat => {}
+            ClientMessage::Disconnect => self.disconnect(addr, DisconnectReason::Left),
+            ClientMessage::Ack { tick } => {
+                if let Some(client) = self.clients.get_mut(&addr) {
+                    client.encoder.ack(tick);
+                }
+            }
+            ClientMessage::Spawn { body } => {
+                // an imported shape only spawns if this server loaded that file itself
+                if let Err(err) = body.validate() {
+                    self.reject(addr, err.to_string()).await?;
+                } else if let Err(err) = self.world.spawn(&mut self.recorder, &body).await {
+                    self.reject(addr, err.to_string()).await?;
+                }
+            }
+            ClientMessage::Impulse { handle, impulse, input } => {
+                // even a rejected input counts as handled, the client must stop replaying it
+                if let Some(client) = self.clients.get_mut(&addr) {
+                    client.received_input = client.received_input.max(input);
+                }
+                if let Err(err) = validate_impulse(impulse) {
+                    self.reject(addr, err.to_string()).await?;
+                } else {
+                    self.command(addr, handle, |body| PhysicsCommand::Impulse(impulse, body)).await?;
+                }
+            }
+            ClientMessage::SetType { handle, body_type } => {
+                self.command(addr, handle, |body| PhysicsCommand::SetType(body_type, body)).await?;
+            }
+            ClientMessage::Translate { handle, position } => {
+                if let Err(err) = validate_position(position) {
+                    self.reject(addr, err.to_string()).await?;
+                } else {
+                    self.command(addr, handle, |body| PhysicsCommand::Translate(position, body)).await?;
+                }
+            }
+            ClientMessage::Remove { handle } => {
+                if let Err(err) = self.world.destroy(&mut self.recorder, handle).await {
+                    self.reject(addr, err.to_string()).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn command(
+        &mut self,
+        addr: SocketAddr,
+        handle: PhysMeshHandle,
+        command: impl FnOnce(RigidBodyHandle) -> PhysicsCommand,
+    ) -> Result<(), NetError> {
+        let result = self.world.phys_mesh(handle).and_then(|phys_mesh| self.world.send_command(command(phys_mesh.body)));
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => self.reject(addr, err.to_string()).await,
+        }
+    }
+
+    async fn reject(&mut self, addr: SocketAddr, reason: String) -> Result<(), NetError> {
+        self.send_reliable(addr, CHANNEL_CONTROL, ServerMessage::Rejected { reason });
+        Ok(())
+    }
+
+    // tells every client about bodies that came or went since the last call
+    async fn flush_events(&mut self) -> Result<(), NetError> {
+        let events: Vec<SceneEvent> = self.recorder.events.drain(..).collect();
+
+        for event in events {
+            let messages = match event {
+                SceneEvent::Spawn(handle, shape) => vec![ServerMessage::Spawned { handle, shape }],
+                SceneEvent::Despawn(handle) => vec![ServerMessage::Removed { handle }],
+                SceneEvent::Terrain(terrain) => terrain_messages(terrain.as_ref()),
+                SceneEvent::Transform(..) => continue,
+            };
+            for client in self.clients.values_mut() {
+                for message in &messages {
+                    client.endpoint.send(CHANNEL_WORLD, message.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn broadcast_transforms(&mut self) -> Result<(), NetError> {
+        if self.clients.is_empty() {
+            return Ok(());
+        }
+
+        let phys_world = self.world.phys_world.lock().await;
+        let island_manager = &phys_world.island_manager;
+        let awake: HashSet<RigidBodyHandle> = island_manager.active_dynamic_bodies().iter()
+            .chain(island_manager.active_kinematic_bodies())
+            .copied()
+            .collect();
+
+        let mut bodies: Vec<BodyState> = self.world.phys_meshes.iter()
+            .filter_map(|(handle, phys_mesh)| {
+                let body = phys_world.rigid_body_set.get(phys_mesh.body)?;
+                let (pos, rot) = (body.translation(), body.rotation());
+
+                Some(BodyState {
+                    handle: *handle,
+                    pose: QuantizedPose::new(vec3(pos.x, pos.y, pos.z), Quat::from_xyzw(rot.i, rot.j, rot.k, rot.w)),
+                    // fixed bodies are never in the active set but can still be moved around
+                    resting: !body.is_fixed() && !awake.contains(&phys_mesh.body),
+                    speed: body.linvel().norm(),
+                })
+            })
+            .collect();
+        bodies.sort_by_key(|body| body.handle.id);
+        let tick = phys_world.tick;
+        drop(phys_world);
+
+        for (addr, client) in &mut self.clients {
+            // sending faster than the world steps, nothing new to say until the next tick
+            let Some(snapshot) = client.encoder.encode(tick, &bodies) else {
+                continue;
+            };
+            let message = ServerMessage::Snapshot {
+                tick,
+                baseline: snapshot.baseline,
+                input_ack: client.applied_input,
+                data: snapshot.data,
+            };
+
+            let packet = client.endpoint.packet(Some(message));
+            if let Err(err) = self.socket.send_to(&encode(&packet)?, *addr).await {
+                eprintln!("could not send to {addr}: {err}");
+            }
+            client.connection.sent();
+        }
+
+        Ok(())
+    }
+
+    // queued on the client's endpoint, goes out with the next packet or flush
+    fn send_reliable(&mut self, addr: SocketAddr, channel: u8, message: ServerMessage) {
+        if let Some(client) = self.clients.get_mut(&addr) {
+            client.endpoint.send(channel, message);
+        }
+    }
+
+    async fn flush_reliable(&mut self) -> Result<(), NetError> {
+        for (addr, client) in &mut self.clients {
+            for packet in client.endpoint.flush() {
+                if let Err(err) = self.socket.send_to(&encode(&packet)?, *addr).await {
+                    eprintln!("could not send to {addr}: {err}");
+                }
+                client.connection.sent();
+            }
+        }
+
+        Ok(())
+    }
+
+    // unreliable, to anyone: connected clients get it with their acks, strangers without
+    async fn send_to(&mut self, addr: SocketAddr, message: &ServerMessage) -> Result<(), NetError> {
+        let packet: ServerPacket = match self.clients.get_mut(&addr) {
+            Some(client) => {
+                client.connection.sent();
+                client.endpoint.packet(Some(message.clone()))
+            }
+            None => Packet::unconnected(0, message.clone()),
+        };
+
+        if let Err(err) = self.socket.send_to(&encode(&packet)?, addr).await {
+            eprintln!("could not send to {addr}: {err}");
+        }
+
+        Ok(())
+    }
+}
+
+// dedicated server, no window
+pub async fn serve(opts: ServerOptions) {
+    let mut world = World::new().await;
+    world.set_physics_rate(opts.hz);
+    if let Err(err) = world.set_terrain(&mut SceneRecorder::new(), Some(opts.terrain.clone())).await {
+        eprintln!("{err}");
+        return;
+    }
+
+    let mut server = match Server::new(&opts.addr, world, opts.conditions).await {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("could not bind {}: {err}", opts.addr);
+            return;
+        }
+    };
+    server.send_rate = opts.send_rate;
+    server.max_clients = opts.max_clients;
+    server.timeout = opts.timeout;
+    server.player_bodies = opts.player_bodies;
+
+    if let Err(err) = server.run().await {
+        eprintln!("server stopped: {err}");
+    }
+}
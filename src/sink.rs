@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use chaos_framework::{vec2, vec3, Cuboid, Mesh, MeshHandle, Quat, Renderer, Sphere, Vec3, Vec4, Vertex};
+use rapier3d::{na::Point3, parry::{shape::{Ball, Capsule, Cone, Cylinder}, transformation::convex_hull}, prelude::*};
+
+use crate::{import::{AssetStore, ImportedMesh}, phys::PhysMeshHandle, physics_util::BodyShape, terrain::{Terrain, TerrainDesc}};
+
+/* anything that wants to mirror the physics world (renderer, tests, network...) */
+pub trait SceneSink {
+    // `assets` has the triangles of an imported shape, if this side ever loaded its file
+    fn spawn(&mut self, handle: PhysMeshHandle, shape: &BodyShape, assets: &AssetStore);
+    fn transform(&mut self, handle: PhysMeshHandle, position: Vec3, rotation: Quat);
+    fn despawn(&mut self, handle: PhysMeshHandle);
+    // the ground changed, `None` when there is none left
+    fn terrain(&mut self, _terrain: Option<&Terrain>) {}
+}
+
+pub struct RenderSink<'a> {
+    pub renderer: &'a mut Renderer,
+    pub meshes: &'a mut HashMap<PhysMeshHandle, MeshHandle>,
+    // where the ground's mesh lives, a sink without one never draws the ground
+    pub terrain: Option<&'a mut Option<MeshHandle>>,
+}
+
+impl<'a> RenderSink<'a> {
+    pub fn new(renderer: &'a mut Renderer, meshes: &'a mut HashMap<PhysMeshHandle, MeshHandle>) -> Self {
+        Self { renderer, meshes, terrain: None }
+    }
+
+    pub fn with_terrain(mut self, terrain: &'a mut Option<MeshHandle>) -> Self {
+        self.terrain = Some(terrain);
+        self
+    }
+}
+
+// segments around the round shapes, same as the spheres
+const SUBDIVISIONS: u32 = 16;
+
+pub fn shape_mesh(shape: &BodyShape, assets: &AssetStore) -> Mesh {
+    let mut mesh = match *shape {
+        BodyShape::Sphere { radius } => Sphere::new(SUBDIVISIONS as i32, radius, Vec4::ONE).mesh(),
+        BodyShape::Cuboid { half_extents } => Cuboid::new(half_extents * 2.0, Vec4::ONE).mesh(),
+        // parry winds these the other way round already
+        BodyShape::Capsule { half_height, radius } => {
+            let trimesh = Capsule::new_y(half_height, radius).to_trimesh(SUBDIVISIONS, SUBDIVISIONS / 2);
+            return smooth_mesh(trimesh, |p| (p - vec3(0.0, p.y.clamp(-half_height, half_height), 0.0)).normalize());
+        }
+        BodyShape::Cylinder { half_height, radius } => return flat_mesh(Cylinder::new(half_height, radius).to_trimesh(SUBDIVISIONS)),
+        BodyShape::Cone { half_height, radius } => return flat_mesh(Cone::new(half_height, radius).to_trimesh(SUBDIVISIONS)),
+        BodyShape::RoundCuboid { half_extents, border_radius } => {
+            // a small ball at every corner, the hull around them is the rounded box
+            let ball = Ball::new(border_radius).to_trimesh(SUBDIVISIONS / 2, SUBDIVISIONS / 4).0;
+            let corners: Vec<Point3<Real>> = (0..8)
+                .map(|i| vector![
+                    if i & 1 == 0 { -half_extents.x } else { half_extents.x },
+                    if i & 2 == 0 { -half_extents.y } else { half_extents.y },
+                    if i & 4 == 0 { -half_extents.z } else { half_extents.z },
+                ])
+                .flat_map(|corner| ball.iter().map(move |p| p + corner))
+                .collect();
+            return smooth_mesh(convex_hull(&corners), |p| (p - p.clamp(-half_extents, half_extents)).normalize_or(Vec3::Y));
+        }
+        // the file's normals and uvs are dropped along the way, flat shading needs neither
+        BodyShape::Imported { asset, scale, .. } => {
+            let mesh = assets.mesh(asset).unwrap_or_else(|| ImportedMesh::placeholder().into());
+            return flat_mesh((mesh.vertices.iter().map(|p| p * scale).collect(), mesh.indices.clone()));
+        }
+    };
+
+    for face in mesh.indices.chunks_mut(3) {
+        face.reverse();
+    }
+
+    mesh
+}
+
+// the same colour the old floor quad had
+pub fn terrain_mesh(terrain: &Terrain) -> Mesh {
+    let (points, triangles) = terrain.mesh();
+    let vertices: Vec<Vertex> = points.into_iter()
+        .map(|(position, normal)| Vertex::new(position, Vec4::ONE, vec2(0.0, 0.0), normal))
+        .collect();
+    let indices: Vec<u32> = triangles.into_iter().flatten().collect();
+
+    let mut mesh = Mesh::new(&vertices, &indices);
+    mesh.color = vec3(0.6, 0.6, 0.9);
+    mesh
+}
+
+fn to_vec3(p: &Point3<Real>) -> Vec3 {
+    vec3(p.x, p.y, p.z)
+}
+
+// shared vertices, `normal` gives the surface normal at a point
+fn smooth_mesh((points, triangles): (Vec<Point3<Real>>, Vec<[u32; 3]>), normal: impl Fn(Vec3) -> Vec3) -> Mesh {
+    let vertices: Vec<Vertex> = points.iter()
+        .map(|p| {
+            let position = to_vec3(p);
+            Vertex::new(position, Vec4::ONE, vec2(0.0, 0.0), normal(position))
+        })
+        .collect();
+    let indices: Vec<u32> = triangles.into_iter().flatten().collect();
+
+    Mesh::new(&vertices, &indices)
+}
+
+// every triangle gets its own vertices so edges like a cylinder's rim stay sharp
+fn flat_mesh((points, triangles): (Vec<Point3<Real>>, Vec<[u32; 3]>)) -> Mesh {
+    let mut vertices = Vec::with_capacity(triangles.len() * 3);
+    for [a, b, c] in triangles {
+        let [a, b, c] = [a, b, c].map(|i| to_vec3(&points[i as usize]));
+        let normal = (b - a).cross(c - a).normalize_or_zero();
+        vertices.extend([a, b, c].map(|position| Vertex::new(position, Vec4::ONE, vec2(0.0, 0.0), normal)));
+    }
+    let indices: Vec<u32> = (0..vertices.len() as u32).collect();
+
+    Mesh::new(&vertices, &indices)
+}
+
+impl SceneSink for RenderSink<'_> {
+    fn spawn(&mut self, handle: PhysMeshHandle, shape: &BodyShape, assets: &AssetStore) {
+        let mesh = self.renderer.add_mesh(shape_mesh(shape, assets)).unwrap();
+        self.meshes.insert(handle, mesh);
+    }
+
+    fn transform(&mut self, handle: PhysMeshHandle, position: Vec3, rotation: Quat) {
+        if let Some(mesh) = self.meshes.get(&handle) {
+            let mesh = &mut self.renderer.meshes[*mesh];
+            mesh.position = position;
+            mesh.rotation = rotation;
+        }
+    }
+
+    fn despawn(&mut self, handle: PhysMeshHandle) {
+        if let Some(mesh) = self.meshes.remove(&handle) {
+            self.renderer.destroy_mesh(mesh);
+        }
+    }
+
+    fn terrain(&mut self, terrain: Option<&Terrain>) {
+        let Some(slot) = self.terrain.as_deref_mut() else {
+            return;
+        };
+
+        if let Some(old) = slot.take() {
+            self.renderer.destroy_mesh(old);
+        }
+        *slot = terrain.map(|terrain| self.renderer.add_mesh(terrain_mesh(terrain)).unwrap());
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SceneEvent {
+    Spawn(PhysMeshHandle, BodyShape),
+    Transform(PhysMeshHandle, Vec3, Quat),
+    Despawn(PhysMeshHandle),
+    Terrain(Option<TerrainDesc>),
+}
+
+/* keeps everything in memory, no GL needed */
+#[derive(Default)]
+pub struct SceneRecorder {
+    pub events: Vec<SceneEvent>,
+    pub bodies: HashMap<PhysMeshHandle, (Vec3, Quat)>,
+}
+
+impl SceneRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+    }
+}
+
+impl SceneSink for SceneRecorder {
+    fn spawn(&mut self, handle: PhysMeshHandle, shape: &BodyShape, _assets: &AssetStore) {
+        self.events.push(SceneEvent::Spawn(handle, *shape));
+        self.bodies.insert(handle, (Vec3::ZERO, Quat::IDENTITY));
+    }
+
+    fn transform(&mut self, handle: PhysMeshHandle, position: Vec3, rotation: Quat) {
+        self.events.push(SceneEvent::Transform(handle, position, rotation));
+        self.bodies.insert(handle, (position, rotation));
+    }
+
+    fn despawn(&mut self, handle: PhysMeshHandle) {
+        self.events.push(SceneEvent::Despawn(handle));
+        self.bodies.remove(&handle);
+    }
+
+    fn terrain(&mut self, terrain: Option<&Terrain>) {
+        self.events.push(SceneEvent::Terrain(terrain.map(|terrain| terrain.desc.clone())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_framework::vec3;
+    use rapier3d::prelude::RigidBodyType;
+
+    use super::*;
+    use crate::{phys::{PhysicsError, World}, scene::SceneBody};
+
+    #[tokio::test]
+    async fn records_spawns_transforms_and_despawns() {
+        let mut world = World::new().await;
+        let mut recorder = SceneRecorder::new();
+
+        let ball = BodyShape::Sphere { radius: 0.5 };
+        let falling = world.spawn(&mut recorder, &SceneBody::new(ball, RigidBodyType::Dynamic, vec3(0.0, 5.0, 0.0))).await.unwrap();
+        let crate_shape = BodyShape::Cuboid { half_extents: Vec3::ONE };
+        let fixed = world.spawn(&mut recorder, &SceneBody::new(crate_shape, RigidBodyType::Fixed, vec3(3.0, 1.0, 0.0))).await.unwrap();
+        assert_eq!(recorder.events, [SceneEvent::Spawn(falling, ball), SceneEvent::Spawn(fixed, crate_shape)]);
+        assert_eq!(recorder.bodies.len(), 2);
+
+        recorder.clear_events();
+        for _ in 0..10 {
+            world.step().await;
+        }
+        world.sync(&mut recorder);
+
+        assert_eq!(recorder.events.len(), 2);
+        for event in &recorder.events {
+            let SceneEvent::Transform(handle, position, _) = *event else {
+                panic!("expected only transforms, got {event:?}");
+            };
+            assert_eq!(recorder.bodies[&handle].0, position);
+            if handle == falling {
+                assert!(position.y < 5.0 && position.y > 4.0, "fell to {position}");
+            } else {
+                assert_eq!(position, vec3(3.0, 1.0, 0.0));
+            }
+        }
+
+        recorder.clear_events();
+        world.destroy(&mut recorder, falling).await.unwrap();
+        assert_eq!(recorder.events, [SceneEvent::Despawn(falling)]);
+        assert!(!recorder.bodies.contains_key(&falling));
+
+        // a stale handle is refused and the sink never hears of it
+        recorder.clear_events();
+        assert!(matches!(world.destroy(&mut recorder, falling).await, Err(PhysicsError::StaleMesh(handle)) if handle == falling));
+        assert!(recorder.events.is_empty());
+
+        world.sync(&mut recorder);
+        assert!(matches!(recorder.events[..], [SceneEvent::Transform(handle, ..)] if handle == fixed));
+    }
+
+    #[tokio::test]
+    async fn records_terrain_changes() {
+        let mut world = World::new().await;
+        let mut recorder = SceneRecorder::new();
+
+        let desc = TerrainDesc::flat(20.0, 10.0);
+        world.set_terrain(&mut recorder, Some(desc.clone())).await.unwrap();
+        world.set_terrain(&mut recorder, None).await.unwrap();
+        assert_eq!(recorder.events, [SceneEvent::Terrain(Some(desc)), SceneEvent::Terrain(None)]);
+    }
+}
@@ -0,0 +1,378 @@
+use std::{collections::{HashMap, VecDeque}, fmt};
+
+use rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{articulation::{BodyGroup, GroupHandle}, joint::JointDesc, phys::{HandleAllocator, PhysMeshHandle, PhysicalWorld, SimulationConfig, World}, physics_util::{BodyShape, PhysMesh}, sink::SceneSink, terrain::{Terrain, TerrainDesc}};
+
+/* everything rapier needs to carry on exactly where it left off, the pipeline itself is just scratch space */
+#[derive(Serialize)]
+struct WorldStateRef<'a> {
+    tick: u64,
+    config: &'a SimulationConfig,
+    integration_parameters: &'a IntegrationParameters,
+    rigid_body_set: &'a RigidBodySet,
+    collider_set: &'a ColliderSet,
+    island_manager: &'a IslandManager,
+    broad_phase: &'a DefaultBroadPhase,
+    narrow_phase: &'a NarrowPhase,
+    impulse_joint_set: &'a ImpulseJointSet,
+    multibody_joint_set: &'a MultibodyJointSet,
+    ccd_solver: &'a CCDSolver,
+    query_pipeline: &'a QueryPipeline,
+    // sorted, so the same world always encodes to the same bytes
+    joints: Vec<(ImpulseJointHandle, &'a JointDesc)>,
+    multibody_joints: Vec<(MultibodyJointHandle, &'a JointDesc)>,
+    meshes: Vec<(PhysMeshHandle, RigidBodyHandle, BodyShape)>,
+    mesh_handles: &'a HandleAllocator,
+    groups: Vec<(GroupHandle, &'a BodyGroup)>,
+    next_group: u32,
+    // the heights are already in `collider_set`, this is for redrawing and handing on to clients
+    terrain: Option<(&'a TerrainDesc, ColliderHandle)>,
+}
+
+#[derive(Deserialize)]
+struct WorldState {
+    tick: u64,
+    config: SimulationConfig,
+    integration_parameters: IntegrationParameters,
+    rigid_body_set: RigidBodySet,
+    collider_set: ColliderSet,
+    island_manager: IslandManager,
+    broad_phase: DefaultBroadPhase,
+    narrow_phase: NarrowPhase,
+    impulse_joint_set: ImpulseJointSet,
+    multibody_joint_set: MultibodyJointSet,
+    ccd_solver: CCDSolver,
+    query_pipeline: QueryPipeline,
+    joints: Vec<(ImpulseJointHandle, JointDesc)>,
+    multibody_joints: Vec<(MultibodyJointHandle, JointDesc)>,
+    meshes: Vec<(PhysMeshHandle, RigidBodyHandle, BodyShape)>,
+    mesh_handles: HandleAllocator,
+    groups: Vec<(GroupHandle, BodyGroup)>,
+    next_group: u32,
+    terrain: Option<(TerrainDesc, ColliderHandle)>,
+}
+
+#[derive(Clone)]
+pub struct WorldSnapshot {
+    pub tick: u64,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Encode(bincode::Error),
+    Decode(bincode::Error),
+    // the terrain's collider wasn't in the snapshot's collider set
+    MissingTerrain(ColliderHandle),
+    Empty,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Encode(err) => write!(f, "could not encode snapshot: {err}"),
+            SnapshotError::Decode(err) => write!(f, "could not decode snapshot: {err}"),
+            SnapshotError::MissingTerrain(handle) => write!(f, "no terrain collider {:?} in the snapshot", handle.into_raw_parts()),
+            SnapshotError::Empty => write!(f, "no snapshot to restore"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl WorldSnapshot {
+    pub fn capture(world: &World, phys_world: &PhysicalWorld) -> Result<Self, SnapshotError> {
+        let mut meshes: Vec<_> = world.phys_meshes.iter()
+            .map(|(handle, phys_mesh)| (*handle, phys_mesh.body, phys_mesh.shape))
+            .collect();
+        meshes.sort_by_key(|(handle, ..)| handle.id);
+        let mut joints: Vec<_> = phys_world.joints.iter().map(|(handle, desc)| (*handle, desc)).collect();
+        joints.sort_by_key(|(handle, _)| handle.into_raw_parts());
+        let mut multibody_joints: Vec<_> = phys_world.multibody_joints.iter().map(|(handle, desc)| (*handle, desc)).collect();
+        multibody_joints.sort_by_key(|(handle, _)| handle.into_raw_parts());
+        let mut groups: Vec<_> = world.groups.iter().map(|(handle, group)| (*handle, group)).collect();
+        groups.sort_by_key(|(handle, _)| handle.0);
+
+        let state = WorldStateRef {
+            tick: phys_world.tick,
+            config: &phys_world.config,
+            integration_parameters: &phys_world.integration_parameters,
+            rigid_body_set: &phys_world.rigid_body_set,
+            collider_set: &phys_world.collider_set,
+            island_manager: &phys_world.island_manager,
+            broad_phase: &phys_world.broad_phase,
+            narrow_phase: &phys_world.narrow_phase,
+            impulse_joint_set: &phys_world.impulse_joint_set,
+            multibody_joint_set: &phys_world.multibody_joint_set,
+            ccd_solver: &phys_world.ccd_solver,
+            query_pipeline: &phys_world.query_pipeline,
+            joints,
+            multibody_joints,
+            meshes,
+            mesh_handles: &world.mesh_handles,
+            groups,
+            next_group: world.next_group,
+            terrain: world.terrain.as_ref().map(|terrain| &terrain.desc).zip(world.terrain_collider),
+        };
+
+        Ok(Self {
+            tick: phys_world.tick,
+            bytes: bincode::serialize(&state).map_err(SnapshotError::Encode)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+pub struct SnapshotRing {
+    pub snapshots: VecDeque<WorldSnapshot>,
+    pub capacity: usize,
+    // physics ticks between two automatic snapshots
+    pub interval: u64,
+    pub last_capture: Option<u64>,
+}
+
+impl SnapshotRing {
+    pub fn new(capacity: usize, interval: u64) -> Self {
+        Self { snapshots: VecDeque::with_capacity(capacity), capacity, interval, last_capture: None }
+    }
+
+    pub fn is_due(&self, tick: u64) -> bool {
+        self.last_capture.is_none_or(|last| tick >= last + self.interval)
+    }
+
+    pub fn push(&mut self, snapshot: WorldSnapshot) {
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+
+        self.last_capture = Some(snapshot.tick);
+        self.snapshots.push_back(snapshot);
+    }
+
+    // the newest snapshot from before `tick`, anything at or after it is where the world already is
+    // counts as the last capture so the restored state isn't taken again straight away
+    pub fn rewind(&mut self, tick: u64) -> Option<WorldSnapshot> {
+        while self.snapshots.back().is_some_and(|snapshot| snapshot.tick >= tick) {
+            self.snapshots.pop_back();
+        }
+
+        let snapshot = self.snapshots.pop_back()?;
+        self.last_capture = Some(snapshot.tick);
+        Some(snapshot)
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.last_capture = None;
+    }
+
+    pub fn seconds(&self, dt: f32) -> f32 {
+        match (self.snapshots.front(), self.snapshots.back()) {
+            (Some(first), Some(last)) => (last.tick - first.tick) as f32 * dt,
+            _ => 0.0,
+        }
+    }
+}
+
+impl World {
+    pub async fn snapshot(&self) -> Result<WorldSnapshot, SnapshotError> {
+        let phys_world = self.phys_world.lock().await;
+        WorldSnapshot::capture(self, &phys_world)
+    }
+
+    // puts the world back exactly as it was, meshes that came or went since are fixed up through `sink`
+    pub async fn restore(&mut self, sink: &mut dyn SceneSink, snapshot: &WorldSnapshot) -> Result<(), SnapshotError> {
+        let state: WorldState = bincode::deserialize(&snapshot.bytes).map_err(SnapshotError::Decode)?;
+
+        // only redrawn when it changed, most snapshots have the same ground as the world
+        // the heights come back with the collider set, nothing is read or generated again
+        let old_desc = self.terrain.as_ref().map(|terrain| &terrain.desc);
+        let terrain = match &state.terrain {
+            Some((desc, _)) if old_desc == Some(desc) => None,
+            Some((desc, collider)) => {
+                let terrain = state.collider_set.get(*collider).and_then(|collider| Terrain::from_collider(desc.clone(), collider));
+                Some(Some(terrain.ok_or(SnapshotError::MissingTerrain(*collider))?))
+            }
+            None if old_desc.is_none() => None,
+            None => Some(None),
+        };
+
+        let mut phys_world = self.phys_world.lock().await;
+        phys_world.tick = state.tick;
+        phys_world.config = state.config;
+        phys_world.integration_parameters = state.integration_parameters;
+        phys_world.rigid_body_set = state.rigid_body_set;
+        phys_world.collider_set = state.collider_set;
+        phys_world.island_manager = state.island_manager;
+        phys_world.broad_phase = state.broad_phase;
+        phys_world.narrow_phase = state.narrow_phase;
+        phys_world.impulse_joint_set = state.impulse_joint_set;
+        phys_world.multibody_joint_set = state.multibody_joint_set;
+        phys_world.ccd_solver = state.ccd_solver;
+        phys_world.query_pipeline = state.query_pipeline;
+        phys_world.joints = state.joints.into_iter().collect();
+        phys_world.multibody_joints = state.multibody_joints.into_iter().collect();
+        phys_world.link_multibody_joints();
+        phys_world.contacts.reset();
+        phys_world.previous_poses.clear();
+        drop(phys_world);
+
+        for (handle, phys_mesh) in &self.phys_meshes {
+            let kept = state.meshes.iter().any(|(h, _, shape)| h == handle && *shape == phys_mesh.shape);
+            if !kept {
+                sink.despawn(*handle);
+            }
+        }
+
+        let mut phys_meshes = HashMap::with_capacity(state.meshes.len());
+        for (handle, body, shape) in state.meshes {
+            let kept = self.phys_meshes.get(&handle).is_some_and(|phys_mesh| phys_mesh.shape == shape);
+            if !kept {
+                sink.spawn(handle, &shape, &self.assets);
+            }
+
+            phys_meshes.insert(handle, PhysMesh::new(body, shape));
+        }
+        self.body_meshes = phys_meshes.iter().map(|(handle, phys_mesh)| (phys_mesh.body, *handle)).collect();
+        self.phys_meshes = phys_meshes;
+        // otherwise a handle from the snapshot could be handed out a second time
+        self.mesh_handles = state.mesh_handles;
+        self.groups = state.groups.into_iter().collect();
+        self.next_group = state.next_group;
+
+        self.config = state.config;
+        if let Some(terrain) = terrain {
+            sink.terrain(terrain.as_ref());
+            self.terrain = terrain;
+        }
+        self.terrain_collider = state.terrain.map(|(_, collider)| collider);
+        self.timestep.accumulator = 0.0;
+
+        Ok(())
+    }
+
+    // called once a frame, keeps the rewind buffer topped up
+    pub fn record_history(&mut self) {
+        // never worth stalling a frame for, if the physics task is busy we catch it next time
+        let Ok(phys_world) = self.phys_world.try_lock() else {
+            return;
+        };
+
+        if self.history.is_due(phys_world.tick) {
+            match WorldSnapshot::capture(self, &phys_world) {
+                Ok(snapshot) => {
+                    drop(phys_world);
+                    self.history.push(snapshot);
+                }
+                Err(err) => eprintln!("could not record history: {err}"),
+            }
+        }
+    }
+
+    // steps back to the previous entry in the history
+    pub async fn rewind(&mut self, sink: &mut dyn SceneSink) -> Result<u64, SnapshotError> {
+        let tick = self.phys_world.lock().await.tick;
+        let snapshot = self.history.rewind(tick).ok_or(SnapshotError::Empty)?;
+        self.restore(sink, &snapshot).await?;
+
+        Ok(snapshot.tick)
+    }
+
+    pub async fn bookmark(&mut self) -> Result<(), SnapshotError> {
+        self.bookmark = Some(self.snapshot().await?);
+        Ok(())
+    }
+
+    pub async fn restore_bookmark(&mut self, sink: &mut dyn SceneSink) -> Result<u64, SnapshotError> {
+        let snapshot = self.bookmark.take().ok_or(SnapshotError::Empty)?;
+        let result = self.restore(sink, &snapshot).await;
+        let tick = snapshot.tick;
+        self.bookmark = Some(snapshot);
+        self.history.clear();
+
+        result.map(|_| tick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chaos_framework::vec3;
+
+    use super::*;
+    use crate::{scene::SceneBody, sink::SceneRecorder};
+
+    async fn tumbling_world(sink: &mut SceneRecorder) -> World {
+        let mut world = World::new().await;
+        world.set_terrain(sink, Some(TerrainDesc::flat(20.0, 20.0))).await.unwrap();
+        for i in 0..4 {
+            let body = SceneBody {
+                angvel: vec3(1.0, i as f32, 0.5),
+                ..SceneBody::new(BodyShape::Cuboid { half_extents: vec3(0.5, 0.3, 0.4) }, RigidBodyType::Dynamic, vec3(i as f32 * 0.4, 1.0 + i as f32 * 1.2, 0.0))
+            };
+            world.spawn(sink, &body).await.unwrap();
+        }
+        world
+    }
+
+    async fn poses(world: &World) -> Vec<(u128, Isometry<Real>)> {
+        let phys_world = world.phys_world.lock().await;
+        let mut poses: Vec<_> = phys_world.rigid_body_set.iter().map(|(_, body)| (body.user_data, *body.position())).collect();
+        poses.sort_by_key(|(user_data, _)| *user_data);
+        poses
+    }
+
+    #[tokio::test]
+    async fn restoring_replays_the_same_steps() {
+        let mut sink = SceneRecorder::new();
+        let mut world = tumbling_world(&mut sink).await;
+        for _ in 0..10 {
+            world.step().await;
+        }
+
+        let snapshot = world.snapshot().await.unwrap();
+        for _ in 0..60 {
+            world.step().await;
+        }
+        let first = poses(&world).await;
+
+        world.restore(&mut sink, &snapshot).await.unwrap();
+        assert_eq!(world.phys_world.lock().await.tick, snapshot.tick);
+        for _ in 0..60 {
+            world.step().await;
+        }
+
+        // bit for bit, not just close
+        assert_eq!(poses(&world).await, first);
+    }
+
+    #[tokio::test]
+    async fn rewinding_twice_goes_back_twice() {
+        let mut sink = SceneRecorder::new();
+        let mut world = tumbling_world(&mut sink).await;
+        // stop on a frame that just took a snapshot, that one is no way back
+        let mut now = 0;
+        while world.history.snapshots.len() < 4 || world.history.last_capture != Some(now) {
+            world.step().await;
+            world.record_history();
+            now = world.phys_world.lock().await.tick;
+        }
+
+        let first = world.rewind(&mut sink).await.unwrap();
+        assert!(first < now);
+        // a frame goes by between the two presses
+        world.step().await;
+        world.record_history();
+        let second = world.rewind(&mut sink).await.unwrap();
+        assert!(second < first, "{second} after {first}");
+        assert_eq!(world.phys_world.lock().await.tick, second);
+    }
+}
@@ -0,0 +1,348 @@
+use std::{fmt, path::PathBuf};
+
+use chaos_framework::{vec3, Vec3};
+use rapier3d::{na::DMatrix, parry::{query::{Ray, RayCast}, shape::HeightField}, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::headless::{flag_value, ArgError};
+
+// what we had before terrain, a 250x250 flat floor
+pub const DEFAULT_TERRAIN_SIZE: f32 = 250.0;
+// heightmap samples per message, about a kilobyte so each one fits in a packet
+pub const TERRAIN_CHUNK_SAMPLES: usize = 512;
+// a million samples, past this it's a typo or a server trying to make us allocate gigabytes
+pub const MAX_TERRAIN_RESOLUTION: u32 = 1024;
+
+/* where the heights come from, all of them in [0, 1] before scaling */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HeightSource {
+    Flat,
+    // grayscale, white is the full height, resampled to the terrain's resolution
+    // only ever read where the file is, `Terrain::generate` turns it into `Samples`
+    Image { path: PathBuf },
+    // a heightmap already resampled, `resolution` squared heights row by row in 1/65535ths of the full height
+    Samples { heights: Vec<u16> },
+    // `frequency` is hills across the whole terrain, every octave doubles it and halves the height
+    Noise { seed: u64, octaves: u32, frequency: f32 },
+}
+
+impl HeightSource {
+    // one of each, for picking from
+    pub const DEFAULTS: [HeightSource; 3] = [
+        HeightSource::Flat,
+        HeightSource::Image { path: PathBuf::new() },
+        HeightSource::Noise { seed: 1, octaves: 4, frequency: 6.0 },
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeightSource::Flat => "Flat",
+            HeightSource::Image { .. } | HeightSource::Samples { .. } => "Heightmap",
+            HeightSource::Noise { .. } => "Noise",
+        }
+    }
+
+    pub fn same_kind(&self, other: &HeightSource) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/* everything both ends need to build the same terrain, a heightmap travels as its samples and never as a path */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TerrainDesc {
+    pub source: HeightSource,
+    // samples along each side, anything outside 2..=`MAX_TERRAIN_RESOLUTION` is clamped into it, see `side`
+    pub resolution: u32,
+    // full width along x and z, y is the height of a 1.0 sample
+    pub size: Vec3,
+}
+
+impl TerrainDesc {
+    pub fn flat(width: f32, depth: f32) -> Self {
+        Self { source: HeightSource::Flat, resolution: 2, size: vec3(width, 0.0, depth) }
+    }
+
+    // `--terrain flat|noise|<image>` with `--terrain-size`, `--terrain-height`, `--terrain-resolution`,
+    // `--terrain-seed`, `--terrain-octaves` and `--terrain-frequency`, a flat floor when nothing was passed
+    pub fn from_args(args: &[String]) -> Result<Self, ArgError> {
+        let size = flag_value(args, "--terrain-size")?.unwrap_or(DEFAULT_TERRAIN_SIZE);
+        let source = match flag_value::<String>(args, "--terrain")?.as_deref() {
+            None | Some("flat") => return Ok(Self::flat(size, size)),
+            Some("noise") => HeightSource::Noise {
+                seed: flag_value(args, "--terrain-seed")?.unwrap_or(1),
+                octaves: flag_value(args, "--terrain-octaves")?.unwrap_or(4),
+                frequency: flag_value(args, "--terrain-frequency")?.unwrap_or(6.0),
+            },
+            Some(path) => HeightSource::Image { path: path.into() },
+        };
+
+        Ok(Self {
+            source,
+            resolution: flag_value(args, "--terrain-resolution")?.unwrap_or(128).clamp(2, MAX_TERRAIN_RESOLUTION),
+            size: vec3(size, flag_value(args, "--terrain-height")?.unwrap_or(8.0), size),
+        })
+    }
+
+    // the resolution actually built, whatever a desc from a file or the network says
+    pub fn side(&self) -> usize {
+        self.resolution.clamp(2, MAX_TERRAIN_RESOLUTION) as usize
+    }
+
+    fn samples(&self) -> usize {
+        self.side().pow(2)
+    }
+
+    // the desc with a heightmap's samples left out and the samples in packet sized pieces, to send in that order
+    pub fn split(&self) -> (TerrainDesc, Vec<Vec<u16>>) {
+        match &self.source {
+            HeightSource::Samples { heights } => {
+                let desc = TerrainDesc { source: HeightSource::Samples { heights: Vec::new() }, ..self.clone() };
+                (desc, heights.chunks(TERRAIN_CHUNK_SAMPLES).map(<[u16]>::to_vec).collect())
+            }
+            _ => (self.clone(), Vec::new()),
+        }
+    }
+
+    // false for a desc from `split` until all of its samples were put back
+    pub fn is_complete(&self) -> bool {
+        match &self.source {
+            HeightSource::Samples { heights } => heights.len() >= self.samples(),
+            _ => true,
+        }
+    }
+
+    // the next piece of what `split` left out, anything past the last sample is dropped
+    pub fn receive_samples(&mut self, samples: &[u16]) {
+        let expected = self.samples();
+        if let HeightSource::Samples { heights } = &mut self.source {
+            let missing = expected.saturating_sub(heights.len());
+            heights.extend_from_slice(&samples[..samples.len().min(missing)]);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TerrainError {
+    Image(PathBuf, image::ImageError),
+    // a heightmap that arrived with too few samples for its resolution
+    MissingSamples { expected: usize, got: usize },
+}
+
+impl fmt::Display for TerrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerrainError::Image(path, err) => write!(f, "could not read heightmap {}: {err}", path.display()),
+            TerrainError::MissingSamples { expected, got } => write!(f, "heightmap has {got} of its {expected} samples"),
+        }
+    }
+}
+
+impl std::error::Error for TerrainError {}
+
+/* the heights built from a `TerrainDesc`, the collider and the render mesh both come from here */
+pub struct Terrain {
+    // never an `Image`, what was read from it is kept as `Samples` so it can be handed on
+    pub desc: TerrainDesc,
+    // rows go along z, columns along x, already scaled to `desc.size`
+    pub heightfield: HeightField,
+}
+
+impl Terrain {
+    pub fn generate(mut desc: TerrainDesc) -> Result<Self, TerrainError> {
+        let n = desc.side();
+        // sample position in [0, 1] on both axes
+        let at = |i: usize, j: usize| (j as f32 / (n - 1) as f32, i as f32 / (n - 1) as f32);
+
+        let mut resolved = None;
+        let heights = match &desc.source {
+            HeightSource::Flat => DMatrix::zeros(n, n),
+            // read once here, every copy of the desc from now on carries the samples instead of the path
+            HeightSource::Image { path } => {
+                let image = image::open(path).map_err(|err| TerrainError::Image(path.clone(), err))?.to_luma16();
+                let (w, h) = (image.width() as f32 - 1.0, image.height() as f32 - 1.0);
+                // the last row and column have nothing past them to blend with
+                let pixel = |x: f32, y: f32| image.get_pixel(x.min(w) as u32, y.min(h) as u32).0[0] as f32;
+
+                let samples: Vec<u16> = (0..n * n)
+                    .map(|k| {
+                        let (u, v) = at(k / n, k % n);
+                        bilinear(u * w, v * h, pixel).round() as u16
+                    })
+                    .collect();
+                let heights = sample_heights(&samples, n)?;
+                resolved = Some(HeightSource::Samples { heights: samples });
+                heights
+            }
+            HeightSource::Samples { heights } => sample_heights(heights, n)?,
+            HeightSource::Noise { seed, octaves, frequency } => DMatrix::from_fn(n, n, |i, j| {
+                let (u, v) = at(i, j);
+                fbm(*seed, *octaves, u * frequency, v * frequency)
+            }),
+        };
+
+        if let Some(source) = resolved {
+            desc.source = source;
+        }
+
+        let scale = vector![desc.size.x, desc.size.y, desc.size.z];
+        Ok(Self { desc, heightfield: HeightField::new(heights, scale) })
+    }
+
+    // the heights a collider already has, how a snapshot brings the terrain back without building it again
+    pub fn from_collider(desc: TerrainDesc, collider: &Collider) -> Option<Self> {
+        let heightfield = collider.shape().as_heightfield()?.clone();
+        Some(Self { desc, heightfield })
+    }
+
+    pub fn collider(&self) -> ColliderBuilder {
+        ColliderBuilder::new(SharedShape::new(self.heightfield.clone()))
+    }
+
+    // centred on the origin like the collider, `None` outside of it
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        // straight down from above the highest a sample can be
+        let top = self.desc.size.y.max(0.0) + 1.0;
+        let ray = Ray::new(point![x, top, z], vector![0.0, -1.0, 0.0]);
+        self.heightfield.cast_local_ray(&ray, Real::MAX, false).map(|toi| top - toi)
+    }
+
+    // a vertex per sample with its position and normal, triangles split the same way the collider's are
+    pub fn mesh(&self) -> (Vec<(Vec3, Vec3)>, Vec<[u32; 3]>) {
+        let heights = self.heightfield.heights();
+        let (rows, cols) = heights.shape();
+        let size = self.desc.size;
+        let (dx, dz) = (size.x / (cols - 1) as f32, size.z / (rows - 1) as f32);
+        let height = |i: usize, j: usize| heights[(i.min(rows - 1), j.min(cols - 1))] * size.y;
+
+        // parry numbers vertices column by column
+        let mut vertices = Vec::with_capacity(rows * cols);
+        for j in 0..cols {
+            for i in 0..rows {
+                let position = vec3(-size.x / 2.0 + j as f32 * dx, height(i, j), -size.z / 2.0 + i as f32 * dz);
+                // central differences, one-sided on the edges
+                let slope_x = (height(i, j + 1) - height(i, j.saturating_sub(1))) / ((j + 1).min(cols - 1) - j.saturating_sub(1)) as f32 / dx;
+                let slope_z = (height(i + 1, j) - height(i.saturating_sub(1), j)) / ((i + 1).min(rows - 1) - i.saturating_sub(1)) as f32 / dz;
+                vertices.push((position, vec3(-slope_x, 1.0, -slope_z).normalize()));
+            }
+        }
+
+        let mut triangles = Vec::with_capacity((rows - 1) * (cols - 1) * 2);
+        for i in 0..rows - 1 {
+            for j in 0..cols - 1 {
+                let (a, b) = self.heightfield.triangles_vids_at(i, j);
+                triangles.extend(a.into_iter().chain(b));
+            }
+        }
+
+        (vertices, triangles)
+    }
+}
+
+fn sample_heights(samples: &[u16], n: usize) -> Result<DMatrix<f32>, TerrainError> {
+    if samples.len() < n * n {
+        return Err(TerrainError::MissingSamples { expected: n * n, got: samples.len() });
+    }
+
+    Ok(DMatrix::from_fn(n, n, |i, j| samples[i * n + j] as f32 / u16::MAX as f32))
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// blends `sample` at the four whole coordinates around (x, y)
+fn bilinear(x: f32, y: f32, sample: impl Fn(f32, f32) -> f32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let top = lerp(sample(x0, y0), sample(x0 + 1.0, y0), tx);
+    let bottom = lerp(sample(x0, y0 + 1.0), sample(x0 + 1.0, y0 + 1.0), tx);
+
+    lerp(top, bottom, ty)
+}
+
+// splitmix64 of the lattice point, in [0, 1)
+fn lattice(seed: u64, x: i64, z: i64) -> f32 {
+    let mut h = seed
+        .wrapping_add((x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+        .wrapping_add((z as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f));
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^= h >> 31;
+
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn value_noise(seed: u64, x: f32, z: f32) -> f32 {
+    let (x0, z0) = (x.floor(), z.floor());
+    // eased so the lattice doesn't show as creases
+    bilinear(x0 + smoothstep(x - x0), z0 + smoothstep(z - z0), |x, z| lattice(seed, x as i64, z as i64))
+}
+
+// octaves of value noise, kept in [0, 1]
+fn fbm(seed: u64, octaves: u32, x: f32, z: f32) -> f32 {
+    let (mut total, mut amplitude, mut frequency, mut sum) = (0.0, 1.0, 1.0, 0.0);
+    for octave in 0..octaves.max(1) {
+        total += value_noise(seed.wrapping_add(octave as u64), x * frequency, z * frequency) * amplitude;
+        sum += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+
+    total / sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heightmap(resolution: u32) -> TerrainDesc {
+        let heights = (0..resolution * resolution).map(|i| (i * 7919 % 65536) as u16).collect();
+        TerrainDesc { source: HeightSource::Samples { heights }, resolution, size: vec3(50.0, 4.0, 50.0) }
+    }
+
+    #[test]
+    fn heightmaps_survive_being_split_up() {
+        let desc = heightmap(40);
+        let (mut received, chunks) = desc.split();
+        assert_eq!(chunks.len(), (40 * 40usize).div_ceil(TERRAIN_CHUNK_SAMPLES));
+        assert!(chunks.iter().all(|chunk| chunk.len() <= TERRAIN_CHUNK_SAMPLES));
+        assert!(!received.is_complete());
+
+        for chunk in &chunks {
+            assert!(!received.is_complete());
+            received.receive_samples(chunk);
+        }
+        assert!(received.is_complete());
+        // a repeat of the last piece has nowhere to go
+        received.receive_samples(chunks.last().unwrap());
+        assert_eq!(received, desc);
+
+        // anything that isn't a heightmap goes in one piece
+        let noise = TerrainDesc { source: HeightSource::Noise { seed: 1, octaves: 2, frequency: 3.0 }, ..heightmap(8) };
+        assert_eq!(noise.split(), (noise.clone(), Vec::new()));
+    }
+
+    #[test]
+    fn resolutions_are_clamped() {
+        let args: Vec<String> = ["--terrain", "noise", "--terrain-resolution", "100000"].into_iter().map(String::from).collect();
+        assert_eq!(TerrainDesc::from_args(&args).unwrap().resolution, MAX_TERRAIN_RESOLUTION);
+
+        // what a server could send, it still only ever waits for and builds the most we allow
+        let mut huge = TerrainDesc { source: HeightSource::Samples { heights: Vec::new() }, resolution: u32::MAX, size: vec3(10.0, 1.0, 10.0) };
+        assert_eq!(huge.side(), MAX_TERRAIN_RESOLUTION as usize);
+        huge.receive_samples(&vec![0; MAX_TERRAIN_RESOLUTION.pow(2) as usize + 10]);
+        assert!(huge.is_complete());
+        let HeightSource::Samples { heights } = &huge.source else {
+            unreachable!();
+        };
+        assert_eq!(heights.len(), MAX_TERRAIN_RESOLUTION.pow(2) as usize);
+
+        let terrain = Terrain::generate(TerrainDesc { resolution: u32::MAX, ..TerrainDesc::flat(10.0, 10.0) }).unwrap();
+        assert_eq!(terrain.heightfield.heights().nrows(), MAX_TERRAIN_RESOLUTION as usize);
+        assert_eq!(Terrain::generate(TerrainDesc { resolution: 0, ..TerrainDesc::flat(10.0, 10.0) }).unwrap().heightfield.heights().nrows(), 2);
+    }
+}
@@ -1,16 +1,35 @@
+use std::collections::HashMap;
+
 use chaos_framework::*;
 use rapier3d::prelude::*;
 
-use crate::{globals::{modify_rb_overhaul_size, read_rb_overhaul_size}, phys::{PhysicalWorld, PhysicsCommand, World}, selection::{update_selection_shader_from_renderer, SELECTION_SHADER}};
+use crate::{articulation::{ChainDesc, GroupHandle, GroupKind}, import::ImportCollider, joint::{JointDesc, JointKind}, phys::{PhysMeshHandle, PhysicsCommand, PhysicsError, World}, physics_util::BodyShape, scene::SceneBody, terrain::{HeightSource, TerrainDesc, DEFAULT_TERRAIN_SIZE}, selection::{update_selection_shader_from_renderer, SELECTION_SHADER}, sink::RenderSink};
 
-pub struct AppViewport {
-    ctx: ViewportCtx,
-    selected: usize,
-}
+pub struct AppViewport;
 
 #[derive(Clone)]
 pub struct ViewportCtx {
-    pub rb_size: f32,
+    // what right click and F spawn, its position is filled in where the mouse points
+    pub spawn: SceneBody,
+    pub import_path: String,
+    pub import_collider: ImportCollider,
+    // what APPLY in the terrain section builds, not necessarily what the world has right now
+    pub terrain: TerrainDesc,
+    pub terrain_path: String,
+    pub terrain_mesh: Option<MeshHandle>,
+    // what CONNECT makes, placed between the two picked bodies and turning around `joint_axis` (world space)
+    pub joint: JointDesc,
+    pub joint_axis: Vec3,
+    pub joint_bodies: [Option<PhysMeshHandle>; 2],
+    pub selected_joint: Option<ImpulseJointHandle>,
+    // what G spawns where the mouse points, `chain` only matters for chains
+    pub articulated: GroupKind,
+    pub chain: ChainDesc,
+    pub selected_group: Option<GroupHandle>,
+    // one per joint of the selected group, what POSE drives them to
+    pub group_joints: Vec<JointDesc>,
+    pub group_pose: Vec<f32>,
+    pub group_position: Vec3,
     pub render_time: f32,
     pub phys_time: f32,
 
@@ -31,6 +50,7 @@ pub struct ViewportCtx {
     pub hierarchy: Option<RigidBodySet>,
 
     pub selection_mesh: MeshHandle,
+    pub meshes: HashMap<PhysMeshHandle, MeshHandle>,
 
     pub lmb: bool,
 }
@@ -41,7 +61,22 @@ impl ViewportCtx {
         sphere.shader = *SELECTION_SHADER;
 
         Self {
-            rb_size: 1.0,
+            spawn: SceneBody::new(BodyShape::Cuboid { half_extents: Vec3::ONE }, RigidBodyType::Dynamic, Vec3::ZERO),
+            import_path: String::new(),
+            import_collider: ImportCollider::ConvexHull,
+            terrain: TerrainDesc::flat(DEFAULT_TERRAIN_SIZE, DEFAULT_TERRAIN_SIZE),
+            terrain_path: String::new(),
+            terrain_mesh: None,
+            joint: JointDesc::new(JointKind::Revolute),
+            joint_axis: Vec3::X,
+            joint_bodies: [None; 2],
+            selected_joint: None,
+            articulated: GroupKind::Ragdoll,
+            chain: ChainDesc::default(),
+            selected_group: None,
+            group_joints: Vec::new(),
+            group_pose: Vec::new(),
+            group_position: Vec3::ZERO,
             render_time: 0.0,
             phys_time: 0.0,
 
@@ -63,31 +98,52 @@ impl ViewportCtx {
             selection_mesh: renderer
                 .add_mesh(sphere)
                 .unwrap(),
+            meshes: HashMap::new(),
 
             lmb: false,
         }
     }
 
+    pub fn sink<'a>(&'a mut self, renderer: &'a mut Renderer) -> RenderSink<'a> {
+        RenderSink::new(renderer, &mut self.meshes).with_terrain(&mut self.terrain_mesh)
+    }
+
+    pub fn clear_selection(&mut self, renderer: &mut Renderer) {
+        self.current_body_handle = None;
+        self.current_body = None;
+        renderer.meshes[self.selection_mesh].position = Vec3::ONE * -2.0;
+    }
+
+    // removes the body everywhere, and from the selection if it was the selected one
+    pub async fn destroy(&mut self, world: &mut World, renderer: &mut Renderer, handle: PhysMeshHandle) -> Result<(), PhysicsError> {
+        let phys_mesh = world.destroy(&mut self.sink(renderer), handle).await?;
+        if self.current_body_handle == Some(phys_mesh.body) {
+            self.clear_selection(renderer);
+        }
+
+        Ok(())
+    }
+
     pub async fn update(
         &mut self, 
         world: &mut World, 
         renderer: &mut Renderer,
-        el: &EventLoop,
-        current_handle: Option<RigidBodyHandle>,
+        _el: &EventLoop,
     ) {
         let phys_world = world.phys_world.lock().await;
 
-        if let Some(handle) = current_handle {
-            self.current_body_handle = Some(handle);
+        // the body went away under us, by a rewind or a load for example
+        if self.current_body_handle.is_some_and(|handle| !phys_world.rigid_body_set.contains(handle)) {
+            self.clear_selection(renderer);
         }
         
         if let Some(handle) = self.current_body_handle {
             if let Some(body) = phys_world.rigid_body_set.get(handle) {
                 self.current_body = Some(body.clone());
-                let collider = body.colliders()[0];
                 let mut total_force = Vec3::ZERO;
 
-                for pair in phys_world.narrow_phase.contact_pairs_with(collider) {
+                // a body without a collider touches nothing
+                for pair in body.colliders().first().into_iter().flat_map(|&collider| phys_world.narrow_phase.contact_pairs_with(collider)) {
                     let manifolds = &pair.manifolds;
 
                     for manifold in manifolds {
@@ -103,24 +159,21 @@ impl ViewportCtx {
                         }
                     }
 
-                    if let Some(phys_mesh_handle) = world.get_phys_mesh_from_handle(handle) {
-                        let mesh_handle = world.phys_meshes[phys_mesh_handle].mesh;
-
+                    let Some(mesh) = phys_world.mesh_of(handle) else {
+                        continue;
+                    };
+                    if let (Some(mesh_handle), Ok(phys_mesh)) = (self.meshes.get(&mesh), world.phys_mesh(mesh)) {
                         let stress = total_force.length();
 
-                        let color = vec3(stress, 0.0, 16.0 - stress) / 8.0 * read_rb_overhaul_size().cbrt();
+                        let color = vec3(stress, 0.0, 16.0 - stress) / 8.0 * phys_mesh.shape.half_size(&world.assets).cbrt();
 
-                        renderer.meshes[mesh_handle].color = color;
+                        renderer.meshes[*mesh_handle].color = color;
                     }
                 }
 
             }   
         }
 
-        if el.event_handler.rmb {
-
-        }
-        
         self.hierarchy = Some(phys_world.rigid_body_set.clone());
     }
 }
@@ -144,8 +197,6 @@ impl AppViewport {
         let frame = el.ui.frame(&mut el.window);
         frame.text("hello, world!\nTIP: hold alt to toggle mouse mode");
 
-        modify_rb_overhaul_size(ctx.rb_size);
-        
         ctx.w = (el.event_handler.width - 200.0) as i32;
         ctx.h = (el.event_handler.height - 100.0) as i32;
         
@@ -165,6 +216,59 @@ impl AppViewport {
     }
 }
 
+enum JointAction {
+    // takes the selected body as the first or second end
+    Pick(usize),
+    Connect,
+    Select(ImpulseJointHandle),
+    Update,
+    Remove,
+}
+
+enum GroupAction {
+    Select(GroupHandle),
+    Move,
+    Pose,
+    Relax,
+    Destroy,
+}
+
+// where a world point lands on the edit viewport, in imgui's coordinates, None behind the camera
+fn to_screen(renderer: &Renderer, ctx: &ViewportCtx, p: Vec3) -> Option<[f32; 2]> {
+    let clip = renderer.camera.proj * renderer.camera.view * p.extend(1.0);
+    if clip.w <= 0.0 {
+        return None;
+    }
+    let ndc = clip.truncate() / clip.w;
+
+    // the 3d view sits under the INFO window
+    Some([(ndc.x + 1.0) / 2.0 * ctx.w as f32, (1.0 - ndc.y) / 2.0 * ctx.h as f32 + ctx.h_padding as f32])
+}
+
+fn joint_color(kind: &JointKind) -> [f32; 4] {
+    match kind {
+        JointKind::Fixed => [0.9, 0.3, 0.3, 1.0],
+        JointKind::Revolute => [0.3, 0.9, 0.3, 1.0],
+        JointKind::Prismatic => [0.3, 0.6, 1.0, 1.0],
+        JointKind::Spherical => [0.9, 0.5, 1.0, 1.0],
+        JointKind::Rope { .. } => [0.9, 0.7, 0.3, 1.0],
+    }
+}
+
+async fn body_position(world: &World, handle: PhysMeshHandle) -> Result<Vec3, PhysicsError> {
+    let body = world.phys_mesh(handle)?.body;
+    let phys_world = world.phys_world.lock().await;
+    let translation = phys_world.rigid_body_set.get(body).ok_or(PhysicsError::StaleHandle(body))?.translation();
+
+    Ok(vec3(translation.x, translation.y, translation.z))
+}
+
+fn half_extents_input(frame: &Ui, half_extents: &mut Vec3) {
+    let mut extents = half_extents.to_array();
+    frame.input_float3("HALF EXT.", &mut extents).build();
+    *half_extents = Vec3::from_array(extents).max(Vec3::splat(0.05));
+}
+
 pub async fn edit_gui(
     frame: &mut Ui,
     ctx: &mut ViewportCtx, 
@@ -174,6 +278,43 @@ pub async fn edit_gui(
     frame.show_default_style_editor();
 
     let mut body_pos = Vec3::ZERO;
+    let mut apply_terrain = false;
+    let mut joint_action = None;
+    let mut group_action = None;
+
+    let (joints, joint_lines) = {
+        let phys_world = world.phys_world.lock().await;
+        let mut joints: Vec<(ImpulseJointHandle, JointKind)> = phys_world.joints.iter().map(|(handle, desc)| (*handle, desc.kind)).collect();
+        joints.sort_by_key(|(handle, _)| handle.into_raw_parts());
+        let lines: Vec<_> = joints.iter()
+            .filter_map(|(handle, kind)| Some((*handle, *kind, phys_world.joint_points(*handle)?)))
+            .collect();
+
+        (joints, lines)
+    };
+    // it broke, or went with one of its bodies
+    if ctx.selected_joint.is_some_and(|selected| !joints.iter().any(|(handle, _)| *handle == selected)) {
+        ctx.selected_joint = None;
+    }
+
+    let mut groups: Vec<(GroupHandle, GroupKind)> = world.groups.iter().map(|(handle, group)| (*handle, group.kind)).collect();
+    groups.sort_by_key(|(handle, _)| handle.0);
+    if ctx.selected_group.is_some_and(|selected| !world.groups.contains_key(&selected)) {
+        ctx.selected_group = None;
+    }
+
+    // body, anchor, anchor and body again, the middle segment stretches when the joint is pulled apart
+    let draw_list = frame.get_background_draw_list();
+    for (handle, kind, points) in &joint_lines {
+        let color = if ctx.selected_joint == Some(*handle) { [1.0, 1.0, 0.2, 1.0] } else { joint_color(kind) };
+        for (i, pair) in points.windows(2).enumerate() {
+            if let (Some(a), Some(b)) = (to_screen(renderer, ctx, pair[0]), to_screen(renderer, ctx, pair[1])) {
+                let color = if i == 1 { color } else { [0.7, 0.7, 0.7, 1.0] };
+                draw_list.add_line(a, b, color).thickness(2.0).build();
+            }
+        }
+    }
+    drop(draw_list);
 
     frame 
         .window("INFO")
@@ -187,16 +328,44 @@ pub async fn edit_gui(
             frame.text(format!("RT: {:.1}ms\nDT: {:.1}", ctx.render_time*1000.0, ctx.dt));
             
 
-            if let Ok(status) = world.status {
+            if let Some(status) = world.status {
                  let solve_time = status.solve_time*1000.0;
                  frame.text(format!("ST: {:.1}", solve_time));
+                 frame.text(format!("CMD: {} ok, {} failed", status.applied_commands, status.failed_commands));
+                 frame.text(format!("STEPS: {} ({} skipped)", status.simulated_steps, status.skipped_steps));
+                 if let Some(err) = status.last_error {
+                     frame.text(format!("ERR: {}", err));
+                 }
             } else {
                 frame.text("ST: UND.");
             }
 
             frame.next_column();
 
-            frame.slider("RB_OVERHAUL_SIZE", 0.1, 10.0, &mut ctx.rb_size);
+            frame.text(format!("BODIES: {}", world.phys_meshes.len()));
+            frame.text(format!("JOINTS: {} ({} broken)", joints.len(), world.status.map_or(0, |status| status.broken_joints)));
+            frame.text(format!("GROUPS: {}", groups.len()));
+
+            frame.next_column();
+
+            if let Some(_cb) = frame.begin_combo("PHYS HZ", format!("{}", world.timestep.hz)) {
+                for rate in [60.0, 120.0, 240.0] {
+                    let clicked = frame.selectable_config(format!("{}", rate))
+                        .selected(world.timestep.hz == rate)
+                        .build();
+                    if clicked {
+                        world.set_physics_rate(rate);
+                    }
+                }
+            }
+
+            frame.next_column();
+
+            frame.text(format!("HISTORY: {:.1}s ({} snaps)", world.history.seconds(world.timestep.dt()), world.history.snapshots.len()));
+            if let Some(bookmark) = &world.bookmark {
+                frame.text(format!("BOOKMARK: tick {} ({} KiB)", bookmark.tick, bookmark.len() / 1024));
+            }
+            frame.text("F6 bookmark, F7 restore, Z rewind");
         });
 
         
@@ -213,8 +382,140 @@ pub async fn edit_gui(
                 // });
                 frame.text(format!("{}", hierarchy.len()));
             }
+
+            frame.separator();
+            frame.text("SIMULATION");
+
+            let mut config = world.config;
+            let mut gravity = config.gravity.to_array();
+            frame.input_float3("GRAVITY", &mut gravity).build();
+            config.gravity = Vec3::from_array(gravity);
+            frame.slider("SOLVER IT.", 1, 64, &mut config.solver_iterations);
+            frame.slider("STAB. IT.", 0, 32, &mut config.stabilization_iterations);
+            frame.checkbox("CCD", &mut config.ccd);
+            frame.slider("TIME SCALE", 0.0, 4.0, &mut config.time_scale);
+
+            if config != world.config {
+                if let Err(err) = world.set_config(config) {
+                    eprintln!("could not change simulation config: {err}");
+                }
+            }
+
+            frame.separator();
+            frame.text("SPAWN");
+
+            let spawn = &mut ctx.spawn;
+            if let Some(_cb) = frame.begin_combo("SHAPE", spawn.shape.name()) {
+                for shape in BodyShape::ALL {
+                    let clicked = frame.selectable_config(shape.name())
+                        .selected(shape.same_kind(&spawn.shape))
+                        .build();
+                    if clicked && !shape.same_kind(&spawn.shape) {
+                        spawn.shape = shape;
+                    }
+                }
+            }
+            match &mut spawn.shape {
+                BodyShape::Sphere { radius } => {
+                    frame.slider("RADIUS", 0.1, 10.0, radius);
+                }
+                BodyShape::Cuboid { half_extents } => half_extents_input(frame, half_extents),
+                BodyShape::Capsule { half_height, radius } | BodyShape::Cylinder { half_height, radius } | BodyShape::Cone { half_height, radius } => {
+                    frame.slider("HALF HEIGHT", 0.05, 10.0, half_height);
+                    frame.slider("RADIUS", 0.05, 10.0, radius);
+                }
+                BodyShape::RoundCuboid { half_extents, border_radius } => {
+                    half_extents_input(frame, half_extents);
+                    frame.slider("BORDER", 0.01, 2.0, border_radius);
+                }
+                BodyShape::Imported { asset, collider, scale } => {
+                    let path = world.assets.path(*asset).map_or("?".into(), |path| path.display().to_string());
+                    frame.text(format!("{path} ({})", collider.name()));
+                    frame.slider("SCALE", 0.05, 10.0, scale);
+                }
+            }
+            frame.slider("DENSITY", 0.1, 10.0, &mut spawn.density);
+            // 0 leaves the mass to the density
+            let mut mass = spawn.mass.unwrap_or(0.0);
+            frame.slider("MASS", 0.0, 100.0, &mut mass);
+            spawn.mass = (mass > 0.0).then_some(mass);
+            frame.slider("FRICTION", 0.0, 2.0, &mut spawn.friction);
+            frame.slider("RESTITUTION", 0.0, 1.0, &mut spawn.restitution);
+            // 0 sends no contact force events
+            let mut force_threshold = spawn.contact_force_threshold.unwrap_or(0.0);
+            frame.slider("FORCE EVENTS", 0.0, 10000.0, &mut force_threshold);
+            spawn.contact_force_threshold = (force_threshold > 0.0).then_some(force_threshold);
+
+            let mut linvel = spawn.linvel.to_array();
+            frame.input_float3("LIN. VEL.", &mut linvel).build();
+            spawn.linvel = Vec3::from_array(linvel);
+            let mut angvel = spawn.angvel.to_array();
+            frame.input_float3("ANG. VEL.", &mut angvel).build();
+            spawn.angvel = Vec3::from_array(angvel);
+
+            frame.separator();
+            frame.text("IMPORT (.obj, .gltf, .glb)");
+
+            frame.input_text("FILE", &mut ctx.import_path).build();
+            if let Some(_cb) = frame.begin_combo("COLLIDER", ctx.import_collider.name()) {
+                for collider in ImportCollider::ALL {
+                    if frame.selectable_config(collider.name()).selected(collider == ctx.import_collider).build() {
+                        ctx.import_collider = collider;
+                    }
+                }
+            }
+            // right click and F place it from then on
+            if frame.button("LOAD") {
+                match world.assets.load(&ctx.import_path) {
+                    Ok(asset) => ctx.spawn.shape = BodyShape::Imported { asset, collider: ctx.import_collider, scale: 1.0 },
+                    Err(err) => eprintln!("could not import {}: {err}", ctx.import_path),
+                }
+            }
+
+            frame.separator();
+            frame.text("TERRAIN");
+
+            let terrain = &mut ctx.terrain;
+            if let Some(_cb) = frame.begin_combo("SOURCE", terrain.source.name()) {
+                for source in HeightSource::DEFAULTS {
+                    let clicked = frame.selectable_config(source.name())
+                        .selected(source.same_kind(&terrain.source))
+                        .build();
+                    if clicked && !source.same_kind(&terrain.source) {
+                        terrain.source = source;
+                    }
+                }
+            }
+            match &mut terrain.source {
+                // only ever what a terrain was built from, never picked here
+                HeightSource::Flat | HeightSource::Samples { .. } => {}
+                HeightSource::Image { path } => {
+                    frame.input_text("IMAGE", &mut ctx.terrain_path).build();
+                    *path = ctx.terrain_path.clone().into();
+                }
+                HeightSource::Noise { seed, octaves, frequency } => {
+                    frame.slider("SEED", 0, 1000, seed);
+                    frame.slider("OCTAVES", 1, 8, octaves);
+                    frame.slider("FREQUENCY", 0.5, 32.0, frequency);
+                }
+            }
+            let mut size = terrain.size.x;
+            frame.slider("SIZE", 10.0, 1000.0, &mut size);
+            terrain.size.x = size;
+            terrain.size.z = size;
+            frame.slider("HEIGHT", 0.0, 64.0, &mut terrain.size.y);
+            frame.slider("RESOLUTION", 2, 512, &mut terrain.resolution);
+            apply_terrain = frame.button("APPLY");
         });
 
+    // the world can't be awaited from inside the window
+    if apply_terrain {
+        let terrain = ctx.terrain.clone();
+        if let Err(err) = world.set_terrain(&mut ctx.sink(renderer), Some(terrain)).await {
+            eprintln!("could not change terrain: {err}");
+        }
+    }
+
     frame 
         .window("PROPERTIES")
         .collapsible(false)
@@ -223,7 +524,7 @@ pub async fn edit_gui(
         .build(|| {
             let mut pos = Vec3::ONE * -2.0;
             if let Some(body) = &mut ctx.current_body {
-                frame.text(format!("GAV. POT. ENERGY: {:.1}", body.gravitational_potential_energy(0.16, vector![0.0, -9.81, 0.0])));
+                frame.text(format!("GAV. POT. ENERGY: {:.1}", body.gravitational_potential_energy(0.16, vector![world.config.gravity.x, world.config.gravity.y, world.config.gravity.z])));
                 frame.text(format!("LIN. VELOCITY: {:.1}", body.linvel()));
 
                 let items = vec!["Dynamic"];
@@ -245,18 +546,257 @@ pub async fn edit_gui(
                 pos = vec3(body.translation().x, body.translation().y, body.translation().z);
                 
                 body_pos = vec3(pos.x, pos.y, pos.z);
-            } else {
-                
             }
             renderer.meshes[ctx.selection_mesh].position = lerp(renderer.meshes[ctx.selection_mesh].position, vec3(pos.x, pos.y, pos.z), 0.125);
-            renderer.meshes[ctx.selection_mesh].scale = Vec3::ONE * read_rb_overhaul_size();
+            let size = ctx.current_body_handle
+                .and_then(|handle| world.get_phys_mesh_from_handle(handle))
+                .and_then(|mesh| world.phys_mesh(mesh).ok())
+                .map_or(1.0, |phys_mesh| phys_mesh.shape.half_size(&world.assets));
+            renderer.meshes[ctx.selection_mesh].scale = Vec3::ONE * size;
+
+            frame.separator();
+            frame.text("JOINTS (Q selects a body)");
+
+            for (i, label) in ["BODY A", "BODY B"].into_iter().enumerate() {
+                let picked = ctx.joint_bodies[i].map_or("-".to_string(), |handle| handle.to_string());
+                if frame.button(format!("{label}: {picked}")) {
+                    joint_action = Some(JointAction::Pick(i));
+                }
+            }
+
+            let joint = &mut ctx.joint;
+            if let Some(_cb) = frame.begin_combo("KIND", joint.kind.name()) {
+                for kind in JointKind::ALL {
+                    let clicked = frame.selectable_config(kind.name())
+                        .selected(kind.same_kind(&joint.kind))
+                        .build();
+                    if clicked && !kind.same_kind(&joint.kind) {
+                        joint.kind = kind;
+                    }
+                }
+            }
+            if let JointKind::Rope { length } = &mut joint.kind {
+                frame.slider("LENGTH", 0.1, 50.0, length);
+            }
+            let mut axis = ctx.joint_axis.to_array();
+            frame.input_float3("AXIS", &mut axis).build();
+            ctx.joint_axis = Vec3::from_array(axis);
+
+            if !joint.kind.free_axes().is_empty() {
+                // radians when it turns, metres when it slides
+                let range = if joint.kind == JointKind::Prismatic { 10.0 } else { std::f32::consts::PI };
+                let mut limited = joint.limits.is_some();
+                frame.checkbox("LIMITS", &mut limited);
+                let mut limits = joint.limits.unwrap_or([-range / 2.0, range / 2.0]);
+                if limited {
+                    frame.slider("MIN", -range, range, &mut limits[0]);
+                    frame.slider("MAX", -range, range, &mut limits[1]);
+                    limits[1] = limits[1].max(limits[0]);
+                }
+                joint.limits = limited.then_some(limits);
+
+                let mut motorised = joint.motor.is_some();
+                frame.checkbox("MOTOR", &mut motorised);
+                let mut motor = joint.motor.unwrap_or_default();
+                if motorised {
+                    frame.slider("TARGET POS.", -range, range, &mut motor.target_position);
+                    frame.slider("TARGET VEL.", -20.0, 20.0, &mut motor.target_velocity);
+                    frame.slider("STIFFNESS", 0.0, 1000.0, &mut motor.stiffness);
+                    frame.slider("DAMPING", 0.0, 100.0, &mut motor.damping);
+                    frame.slider("MAX FORCE", 0.0, 10000.0, &mut motor.max_force);
+                }
+                joint.motor = motorised.then_some(motor);
+            }
+
+            // 0 never breaks
+            let mut break_force = joint.break_force.unwrap_or(0.0);
+            frame.slider("BREAK FORCE", 0.0, 10000.0, &mut break_force);
+            joint.break_force = (break_force > 0.0).then_some(break_force);
+            let mut break_torque = joint.break_torque.unwrap_or(0.0);
+            frame.slider("BREAK TORQUE", 0.0, 10000.0, &mut break_torque);
+            joint.break_torque = (break_torque > 0.0).then_some(break_torque);
+            frame.checkbox("CONTACTS", &mut joint.contacts);
+
+            if frame.button("CONNECT") {
+                joint_action = Some(JointAction::Connect);
+            }
+
+            let selected = ctx.selected_joint
+                .and_then(|selected| joints.iter().find(|(handle, _)| *handle == selected))
+                .map_or("-".to_string(), |(handle, kind)| format!("{} {}", handle.into_raw_parts().0, kind.name()));
+            if let Some(_cb) = frame.begin_combo("JOINT", selected) {
+                for (handle, kind) in &joints {
+                    let clicked = frame.selectable_config(format!("{} {}", handle.into_raw_parts().0, kind.name()))
+                        .selected(ctx.selected_joint == Some(*handle))
+                        .build();
+                    if clicked {
+                        joint_action = Some(JointAction::Select(*handle));
+                    }
+                }
+            }
+            if ctx.selected_joint.is_some() {
+                if frame.button("UPDATE") {
+                    joint_action = Some(JointAction::Update);
+                }
+                frame.same_line();
+                if frame.button("REMOVE") {
+                    joint_action = Some(JointAction::Remove);
+                }
+            }
+
+            frame.separator();
+            frame.text("ARTICULATED (G spawns at the mouse)");
+
+            if let Some(_cb) = frame.begin_combo("BUILD", ctx.articulated.name()) {
+                for kind in GroupKind::ALL {
+                    if frame.selectable_config(kind.name()).selected(kind == ctx.articulated).build() {
+                        ctx.articulated = kind;
+                    }
+                }
+            }
+            if ctx.articulated == GroupKind::Chain {
+                let chain = &mut ctx.chain;
+                frame.slider("LINKS", 1, 64, &mut chain.links);
+                frame.slider("LINK LENGTH", 0.1, 4.0, &mut chain.link_length);
+                frame.slider("LINK RADIUS", 0.02, 1.0, &mut chain.radius);
+                chain.radius = chain.radius.min(chain.link_length / 2.0);
+                frame.checkbox("ANCHORED", &mut chain.anchored);
+            }
+
+            let selected = ctx.selected_group
+                .and_then(|selected| groups.iter().find(|(handle, _)| *handle == selected))
+                .map_or("-".to_string(), |(handle, kind)| format!("{handle} {}", kind.name()));
+            if let Some(_cb) = frame.begin_combo("GROUP", selected) {
+                for (handle, kind) in &groups {
+                    let clicked = frame.selectable_config(format!("{handle} {}", kind.name()))
+                        .selected(ctx.selected_group == Some(*handle))
+                        .build();
+                    if clicked {
+                        group_action = Some(GroupAction::Select(*handle));
+                    }
+                }
+            }
+            if ctx.selected_group.is_some() {
+                let mut position = ctx.group_position.to_array();
+                frame.input_float3("ROOT POS.", &mut position).build();
+                ctx.group_position = Vec3::from_array(position);
+                if frame.button("MOVE") {
+                    group_action = Some(GroupAction::Move);
+                }
+
+                for (i, (desc, target)) in ctx.group_joints.iter().zip(&mut ctx.group_pose).enumerate() {
+                    let range = if desc.kind == JointKind::Prismatic { 10.0 } else { std::f32::consts::PI };
+                    let [min, max] = desc.limits.unwrap_or([-range, range]);
+                    frame.slider(format!("{i} {}", desc.kind.name()), min, max, target);
+                }
+                if frame.button("POSE") {
+                    group_action = Some(GroupAction::Pose);
+                }
+                frame.same_line();
+                if frame.button("RELAX") {
+                    group_action = Some(GroupAction::Relax);
+                }
+                frame.same_line();
+                if frame.button("DESTROY") {
+                    group_action = Some(GroupAction::Destroy);
+                }
+            }
         });
 
+    match joint_action {
+        Some(JointAction::Pick(i)) => {
+            ctx.joint_bodies[i] = ctx.current_body_handle.and_then(|body| world.get_phys_mesh_from_handle(body));
+        }
+        Some(JointAction::Connect) => match ctx.joint_bodies {
+            [Some(body1), Some(body2)] => {
+                // halfway between the two, where a hinge between neighbours would usually go
+                let connected = async {
+                    let anchor = (body_position(world, body1).await? + body_position(world, body2).await?) / 2.0;
+                    world.connect(body1, body2, ctx.joint, anchor, ctx.joint_axis).await
+                };
+                match connected.await {
+                    Ok(handle) => ctx.selected_joint = Some(handle),
+                    Err(err) => eprintln!("could not connect {body1} and {body2}: {err}"),
+                }
+            }
+            _ => eprintln!("pick two bodies to connect first"),
+        },
+        Some(JointAction::Select(handle)) => match world.joint(handle).await {
+            Ok((body1, body2, desc)) => {
+                ctx.selected_joint = Some(handle);
+                ctx.joint_bodies = [Some(body1), Some(body2)];
+                ctx.joint = desc;
+            }
+            Err(err) => eprintln!("could not select joint: {err}"),
+        },
+        Some(JointAction::Update) => {
+            if let Some(handle) = ctx.selected_joint {
+                if let Err(err) = world.edit_joint(handle, ctx.joint).await {
+                    eprintln!("could not update joint: {err}");
+                }
+            }
+        }
+        Some(JointAction::Remove) => {
+            if let Some(handle) = ctx.selected_joint.take() {
+                if let Err(err) = world.remove_joint(handle).await {
+                    eprintln!("could not remove joint: {err}");
+                }
+            }
+        }
+        None => {}
+    }
+
+    match group_action {
+        Some(GroupAction::Select(handle)) => {
+            let root = world.group(handle).map(|group| group.bodies[0]);
+            let selected = async { Ok::<_, PhysicsError>((world.group_joints(handle).await?, body_position(world, root?).await?)) };
+            match selected.await {
+                Ok((joints, position)) => {
+                    ctx.selected_group = Some(handle);
+                    ctx.group_pose = joints.iter().map(|desc| desc.motor.map_or(0.0, |motor| motor.target_position)).collect();
+                    ctx.group_joints = joints;
+                    ctx.group_position = position;
+                }
+                Err(err) => eprintln!("could not select group: {err}"),
+            }
+        }
+        Some(GroupAction::Move) => {
+            if let Some(handle) = ctx.selected_group {
+                if let Err(err) = world.move_group(handle, ctx.group_position).await {
+                    eprintln!("could not move group: {err}");
+                }
+            }
+        }
+        Some(GroupAction::Pose) => {
+            if let Some(handle) = ctx.selected_group {
+                if let Err(err) = world.pose_group(handle, &ctx.group_pose).await {
+                    eprintln!("could not pose group: {err}");
+                }
+            }
+        }
+        Some(GroupAction::Relax) => {
+            if let Some(handle) = ctx.selected_group {
+                if let Err(err) = world.relax_group(handle).await {
+                    eprintln!("could not relax group: {err}");
+                }
+            }
+        }
+        Some(GroupAction::Destroy) => {
+            if let Some(handle) = ctx.selected_group.take() {
+                ctx.clear_selection(renderer);
+                if let Err(err) = world.destroy_group(&mut ctx.sink(renderer), handle).await {
+                    eprintln!("could not destroy group: {err}");
+                }
+            }
+        }
+        None => {}
+    }
+
     if let Some(handle) = ctx.current_body_handle {
         if ctx.lmb {
-            world.command_sender
-                .try_send(PhysicsCommand::Impulse(renderer.camera.pos - body_pos, handle))
-                .unwrap();
+            if let Err(err) = world.send_command(PhysicsCommand::Impulse(renderer.camera.pos - body_pos, handle)) {
+                eprintln!("impulse dropped: {err}");
+            }
         }
     }
 }
\ No newline at end of file
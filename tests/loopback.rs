@@ -0,0 +1,348 @@
+use std::{
+    net::SocketAddr,
+    sync::{mpsc as std_mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chaos_framework::{vec3, Vec3};
+use physp::{
+    client::Client,
+    connection::ConnectionState,
+    netsim::NetConditions,
+    phys::{PhysMeshHandle, PhysicalWorld, World},
+    physics_util::BodyShape,
+    protocol::{decode, encode, ClientMessage, DisconnectReason, ServerMessage, ServerPacket, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION},
+    reliable::Packet,
+    scene::SceneBody,
+    server::{Server, ServerOptions},
+    sink::{SceneEvent, SceneRecorder},
+    terrain::TerrainDesc,
+};
+use rapier3d::prelude::RigidBodyType;
+use tokio::{net::UdpSocket, runtime, sync::{oneshot, Mutex}, time};
+
+const BODY: BodyShape = BodyShape::Cuboid { half_extents: Vec3::splat(0.5) };
+
+/* a server on its own thread, `Server::run` isn't Send. The test keeps a way into its physics world to compare against */
+struct TestServer {
+    addr: SocketAddr,
+    phys_world: Arc<Mutex<PhysicalWorld>>,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestServer {
+    // a flat floor so everything dropped on it comes to rest
+    fn start(conditions: Option<NetConditions>) -> Self {
+        Self::start_with(conditions, |_| {})
+    }
+
+    // `configure` gets the server before it starts listening for packets
+    fn start_with(conditions: Option<NetConditions>, configure: impl FnOnce(&mut Server) + Send + 'static) -> Self {
+        let (started, start) = std_mpsc::channel();
+        let (stop, stopped) = oneshot::channel();
+
+        let thread = thread::spawn(move || {
+            let runtime = runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async move {
+                let mut world = World::new().await;
+                world.set_terrain(&mut SceneRecorder::new(), Some(TerrainDesc::flat(40.0, 40.0))).await.unwrap();
+                let phys_world = world.phys_world.clone();
+
+                let mut server = Server::new("127.0.0.1:0", world, conditions).await.unwrap();
+                configure(&mut server);
+                started.send((server.socket.local_addr().unwrap(), phys_world)).unwrap();
+
+                tokio::select! {
+                    result = server.run() => result.unwrap(),
+                    _ = stopped => {}
+                }
+            });
+        });
+        let (addr, phys_world) = start.recv().unwrap();
+
+        Self { addr, phys_world, stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// runs the client's frame loop until `done`, false if it never got there
+async fn pump(client: &mut Client, recorder: &mut SceneRecorder, timeout: Duration, mut done: impl FnMut(&Client, &SceneRecorder) -> bool) -> bool {
+    let start = Instant::now();
+    let mut last = start;
+    while start.elapsed() < timeout {
+        time::sleep(Duration::from_millis(10)).await;
+        let now = Instant::now();
+        client.update(recorder, (now - last).as_secs_f32());
+        last = now;
+
+        if done(client, recorder) {
+            return true;
+        }
+    }
+
+    false
+}
+
+fn spawn_message(i: u32) -> ClientMessage {
+    ClientMessage::Spawn { body: SceneBody::new(BODY, RigidBodyType::Dynamic, vec3(i as f32 * 2.0, 2.0, 0.0)) }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn handshake_spawns_and_delta_snapshots() {
+    let server = TestServer::start(None);
+    let mut client = Client::connect(&server.addr.to_string(), None).await.unwrap();
+    let mut recorder = SceneRecorder::new();
+
+    assert!(pump(&mut client, &mut recorder, Duration::from_secs(5), |client, _| client.is_connected()).await);
+    let ConnectionState::Connected(connection) = &client.state else {
+        unreachable!();
+    };
+    assert_eq!(connection.version, PROTOCOL_VERSION);
+    assert_ne!(connection.session, 0);
+    assert_eq!(client.endpoint.session, connection.session);
+
+    for i in 0..3 {
+        client.send_reliable(spawn_message(i)).unwrap();
+    }
+    let replicated = pump(&mut client, &mut recorder, Duration::from_secs(10), |client, recorder| {
+        client.replica.bodies.len() == 3 && recorder.bodies.len() == 3 && client.replica.stats.deltas >= 10
+    })
+    .await;
+    assert!(replicated, "{} bodies, {:?}", client.replica.bodies.len(), client.replica.stats);
+
+    let spawned: Vec<PhysMeshHandle> = recorder.events.iter()
+        .filter_map(|event| match event {
+            SceneEvent::Spawn(handle, shape) => {
+                assert_eq!(*shape, BODY);
+                Some(*handle)
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(spawned.len(), 3);
+
+    // every body the server has is in the newest snapshot, however it was encoded
+    let newest = client.replica.snapshots.back().unwrap();
+    for handle in &spawned {
+        assert!(newest.bodies.contains_key(handle));
+    }
+    let phys_world = server.phys_world.lock().await;
+    let tagged = phys_world.rigid_body_set.iter().filter_map(|(_, body)| PhysMeshHandle::from_user_data(body.user_data)).count();
+    assert_eq!(tagged, 3);
+    drop(phys_world);
+
+    let stats = client.replica.stats;
+    assert_eq!(stats.undecodable, 0);
+    assert!(stats.deltas < stats.received, "the first snapshot has no baseline to delta against");
+}
+
+// the same drops, copies and delays every run, in both directions at both ends
+fn lossy(seed: u64) -> NetConditions {
+    NetConditions {
+        latency: Duration::from_millis(5),
+        jitter: Duration::from_millis(2),
+        loss: 0.2,
+        duplicate: 0.1,
+        reorder: 0.2,
+        seed,
+        ..NetConditions::default()
+    }
+}
+
+// the radius tells which spawn a body came from
+fn numbered_spawn(i: u32) -> ClientMessage {
+    let shape = BodyShape::Sphere { radius: 0.3 + i as f32 * 0.01 };
+    ClientMessage::Spawn { body: SceneBody::new(shape, RigidBodyType::Dynamic, vec3((i % 5) as f32 * 2.0, 1.0, (i / 5) as f32 * 2.0)) }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn lossy_link_delivers_reliable_messages_in_order() {
+    let server = TestServer::start(Some(lossy(11)));
+    let mut client = Client::connect(&server.addr.to_string(), Some(lossy(7))).await.unwrap();
+    let mut recorder = SceneRecorder::new();
+    assert!(pump(&mut client, &mut recorder, Duration::from_secs(10), |client, _| client.is_connected()).await);
+
+    // both ways reliable: the spawns reach the server in order, then the bodies come back in that order
+    // a frame apart, so they don't all go out in the one packet
+    const SPAWNS: u32 = 20;
+    for i in 0..SPAWNS {
+        client.send_reliable(numbered_spawn(i)).unwrap();
+        pump(&mut client, &mut recorder, Duration::from_millis(10), |_, _| false).await;
+    }
+    let delivered = pump(&mut client, &mut recorder, Duration::from_secs(20), |client, _| client.replica.bodies.len() == SPAWNS as usize).await;
+    assert!(delivered, "{} of {SPAWNS} bodies arrived", client.replica.bodies.len());
+    // anything late or repeated would show up here too
+    pump(&mut client, &mut recorder, Duration::from_millis(500), |_, _| false).await;
+
+    let radii: Vec<f32> = recorder.events.iter()
+        .filter_map(|event| match event {
+            SceneEvent::Spawn(_, BodyShape::Sphere { radius }) => Some(*radius),
+            SceneEvent::Spawn(_, shape) => panic!("never asked for a {shape:?}"),
+            _ => None,
+        })
+        .collect();
+    let expected: Vec<f32> = (0..SPAWNS).map(|i| 0.3 + i as f32 * 0.01).collect();
+    assert_eq!(radii, expected);
+    assert!(!recorder.events.iter().any(|event| matches!(event, SceneEvent::Despawn(_))));
+
+    // the link really was that bad, which of those packets carried the spawns is down to timing
+    let sim = client.socket.stats().unwrap();
+    assert!(sim.dropped > 0 && sim.duplicated > 0 && sim.reordered > 0, "{sim:?}");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn lossy_link_converges_on_the_server_poses() {
+    let server = TestServer::start(Some(lossy(3)));
+    let mut client = Client::connect(&server.addr.to_string(), Some(lossy(5))).await.unwrap();
+    let mut recorder = SceneRecorder::new();
+    assert!(pump(&mut client, &mut recorder, Duration::from_secs(10), |client, _| client.is_connected()).await);
+
+    for i in 0..10 {
+        client.send_reliable(numbered_spawn(i)).unwrap();
+    }
+
+    // until everything on the server is asleep and the newest snapshot we have says the same as the server
+    let start = Instant::now();
+    let mut converged = false;
+    while !converged && start.elapsed() < Duration::from_secs(30) {
+        pump(&mut client, &mut recorder, Duration::from_millis(100), |_, _| false).await;
+
+        let phys_world = server.phys_world.lock().await;
+        let Some(newest) = client.replica.snapshots.back() else {
+            continue;
+        };
+        let mut bodies = 0;
+        converged = phys_world.rigid_body_set.iter().all(|(_, body)| {
+            let Some(handle) = PhysMeshHandle::from_user_data(body.user_data) else {
+                return true;
+            };
+            bodies += 1;
+            let position = body.translation();
+            body.is_sleeping() && newest.bodies.get(&handle).is_some_and(|(replicated, _)| {
+                replicated.distance(vec3(position.x, position.y, position.z)) < 0.01
+            })
+        });
+        converged &= bodies == 10;
+    }
+
+    assert!(converged, "{:?}", client.replica.stats);
+    assert!(client.replica.stats.deltas > 0);
+    assert!(client.socket.stats().unwrap().dropped > 0);
+}
+
+fn disconnected(client: &Client) -> Option<DisconnectReason> {
+    match &client.state {
+        ConnectionState::Disconnected(reason) => Some(*reason),
+        _ => None,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn handshake_gives_up_on_a_silent_server() {
+    // bound, so the connects go somewhere, but nothing ever answers
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut client = Client::connect(&silent.local_addr().unwrap().to_string(), None).await.unwrap();
+    let mut recorder = SceneRecorder::new();
+
+    let gave_up = pump(&mut client, &mut recorder, Duration::from_secs(10), |client, _| disconnected(client).is_some()).await;
+    assert!(gave_up);
+    assert_eq!(disconnected(&client), Some(DisconnectReason::TimedOut));
+
+    // it kept asking the whole time, always with the same nonce
+    let mut buf = vec![0u8; 1500];
+    let mut nonces = Vec::new();
+    while let Ok(Ok((len, _))) = time::timeout(Duration::from_millis(50), silent.recv_from(&mut buf)).await {
+        let packet: Packet<ClientMessage> = decode(&buf[..len]).unwrap();
+        let Some(ClientMessage::Connect { nonce, .. }) = packet.message else {
+            panic!("sent {:?} before it was connected", packet.message);
+        };
+        nonces.push(nonce);
+    }
+    assert!(nonces.len() > 2, "{} connects", nonces.len());
+    assert!(nonces.iter().all(|nonce| *nonce == nonces[0]));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_full_server_turns_clients_away() {
+    let server = TestServer::start_with(None, |server| server.max_clients = 1);
+    let mut recorder = SceneRecorder::new();
+
+    let mut first = Client::connect(&server.addr.to_string(), None).await.unwrap();
+    assert!(pump(&mut first, &mut recorder, Duration::from_secs(5), |client, _| client.is_connected()).await);
+
+    let mut second = Client::connect(&server.addr.to_string(), None).await.unwrap();
+    let refused = pump(&mut second, &mut recorder, Duration::from_secs(5), |client, _| disconnected(client).is_some()).await;
+    assert!(refused);
+    assert_eq!(disconnected(&second), Some(DisconnectReason::ServerFull));
+
+    // the one that got in is still in, and its place frees up once it leaves
+    first.update(&mut recorder, 0.0);
+    assert!(first.is_connected());
+    first.disconnect();
+    let mut third = Client::connect(&server.addr.to_string(), None).await.unwrap();
+    assert!(pump(&mut third, &mut recorder, Duration::from_secs(5), |client, _| client.is_connected()).await);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn versions_the_server_doesnt_speak_are_refused() {
+    let server = TestServer::start(None);
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server.addr).await.unwrap();
+
+    let connect = ClientMessage::Connect { min_version: PROTOCOL_VERSION + 1, max_version: PROTOCOL_VERSION + 3, nonce: 1 };
+    socket.send(&encode(&Packet::unconnected(0, connect)).unwrap()).await.unwrap();
+
+    let mut buf = vec![0u8; 1500];
+    let len = time::timeout(Duration::from_secs(5), socket.recv(&mut buf)).await.unwrap().unwrap();
+    let packet: ServerPacket = decode(&buf[..len]).unwrap();
+    let reason = DisconnectReason::VersionMismatch { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION };
+    assert!(matches!(packet.message, Some(ServerMessage::Disconnected { reason: got }) if got == reason), "{:?}", packet.message);
+}
+
+#[test]
+fn server_timeouts_have_to_be_a_duration() {
+    let args = |timeout: &'static str| ["--server", "--timeout", timeout].into_iter().map(String::from);
+    assert_eq!(ServerOptions::from_args(args("2.5")).unwrap().unwrap().timeout, Duration::from_millis(2500));
+    for bad in ["-1", "0", "NaN", "inf", "1e30"] {
+        assert!(ServerOptions::from_args(args(bad)).is_err(), "{bad}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn broken_moves_and_kicks_never_reach_the_world() {
+    let server = TestServer::start(None);
+    let mut client = Client::connect(&server.addr.to_string(), None).await.unwrap();
+    let mut recorder = SceneRecorder::new();
+    assert!(pump(&mut client, &mut recorder, Duration::from_secs(5), |client, _| client.is_connected()).await);
+
+    client.send_reliable(spawn_message(0)).unwrap();
+    assert!(pump(&mut client, &mut recorder, Duration::from_secs(5), |client, _| client.replica.bodies.len() == 1).await);
+    let handle = *client.replica.bodies.keys().next().unwrap();
+
+    client.try_send(&ClientMessage::Translate { handle, position: vec3(f32::NAN, 1.0, 0.0) });
+    client.try_send(&ClientMessage::Translate { handle, position: vec3(0.0, f32::INFINITY, 0.0) });
+    client.try_send(&ClientMessage::Impulse { handle, impulse: vec3(0.0, f32::NAN, 0.0), input: 0 });
+    client.try_send(&ClientMessage::Impulse { handle, impulse: Vec3::splat(f32::MAX), input: 0 });
+    // sent after them, once it's there so are they
+    client.send_reliable(spawn_message(1)).unwrap();
+    assert!(pump(&mut client, &mut recorder, Duration::from_secs(5), |client, _| client.replica.bodies.len() == 2).await);
+    pump(&mut client, &mut recorder, Duration::from_millis(200), |_, _| false).await;
+
+    let phys_world = server.phys_world.lock().await;
+    for (_, body) in phys_world.rigid_body_set.iter() {
+        assert!(body.translation().iter().all(|value| value.is_finite()), "{:?}", body.translation());
+        assert!(body.linvel().iter().all(|value| value.is_finite() && value.abs() < 100.0), "{:?}", body.linvel());
+    }
+}